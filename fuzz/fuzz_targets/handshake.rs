@@ -0,0 +1,15 @@
+//! Feeds the input to a server as everything a client sends, from the
+//! greeting through the options to transmission requests.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nbd::client::Client;
+use nbd_fuzz::Replay;
+
+fuzz_target!(|data: &[u8]| {
+    let server = nbd_fuzz::server();
+    let mut client = Client::new(Replay::new(data), String::from("fuzz"));
+
+    // Running out of input is an error, only panics and hangs count
+    let _ = server.handle(&mut client);
+});
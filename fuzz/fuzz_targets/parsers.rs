@@ -0,0 +1,46 @@
+//! Decodes the input as a request header and as option payloads. Whatever
+//! parses has to encode back to the input, nothing may be skipped.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nbd::protocol::{parse_info, parse_meta_context, Request};
+
+fn string(s: &str) -> Vec<u8> {
+    [&(s.len() as u32).to_be_bytes(), s.as_bytes()].concat()
+}
+
+fuzz_target!(|data: &[u8]| {
+    for extended in [false, true] {
+        if let Ok(request) = Request::decode(data, extended) {
+            let mut header = Vec::new();
+            header.extend_from_slice(&request.magic.to_be_bytes());
+            header.extend_from_slice(&request.flags.to_be_bytes());
+            header.extend_from_slice(&request.command_type.to_be_bytes());
+            header.extend_from_slice(&request.handle.to_be_bytes());
+            header.extend_from_slice(&request.offset.to_be_bytes());
+            match extended {
+                true => header.extend_from_slice(&request.len.to_be_bytes()),
+                false => header.extend_from_slice(&(request.len as u32).to_be_bytes()),
+            }
+            assert_eq!(header, data[..header.len()]);
+        }
+    }
+
+    if let Some((name, requests)) = parse_info(data) {
+        let mut encoded = string(&name);
+        encoded.extend_from_slice(&(requests.len() as u16).to_be_bytes());
+        for request in requests {
+            encoded.extend_from_slice(&request.to_be_bytes());
+        }
+        assert_eq!(encoded, data);
+    }
+
+    if let Some((name, queries)) = parse_meta_context(data) {
+        let mut encoded = string(&name);
+        encoded.extend_from_slice(&(queries.len() as u32).to_be_bytes());
+        for query in queries {
+            encoded.extend_from_slice(&string(&query));
+        }
+        assert_eq!(encoded, data);
+    }
+});
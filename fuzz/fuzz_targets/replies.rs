@@ -0,0 +1,96 @@
+//! Sends the input as the data of a read, with flags and an offset taken
+//! from its first bytes, and checks the chunks reassemble to it.
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use nbd::{
+    client::Client,
+    consts::{
+        NBD_CMD_FLAG_DF, NBD_EXTENDED_REPLY_MAGIC, NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_NONE,
+        NBD_REPLY_TYPE_OFFSET_DATA, NBD_REQUEST_MAGIC, NBD_STRUCTURED_REPLY_MAGIC,
+    },
+    protocol::{structured_reply, Request},
+};
+
+const HANDLE: u64 = 0x1122334455667788;
+
+fn take<const N: usize>(data: &mut &[u8]) -> [u8; N] {
+    let (head, rest) = data.split_at(N);
+    *data = rest;
+    head.try_into().unwrap()
+}
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 11 {
+        return;
+    }
+
+    let mut rest = data;
+    let extended = take::<1>(&mut rest)[0] & 1 != 0;
+    let flags = u16::from_be_bytes(take(&mut rest));
+    let offset = u64::from_be_bytes(take(&mut rest));
+    // Offsets past the end of a 64-bit export never reach a reply
+    if offset.checked_add(rest.len() as u64).is_none() {
+        return;
+    }
+
+    let request = Request {
+        magic: NBD_REQUEST_MAGIC,
+        flags,
+        command_type: 0,
+        handle: HANDLE,
+        offset,
+        len: rest.len() as u64,
+    };
+
+    let mut client = Client::new(Cursor::new(Vec::new()), String::from("fuzz"));
+    client.set_structured_reply(true);
+    client.set_extended_headers(extended);
+    structured_reply(&mut client, &request, rest).unwrap();
+
+    let mut sent = client.stream().get_ref().as_slice();
+    let mut data = Vec::new();
+    let mut chunks = 0;
+    loop {
+        let magic = u32::from_be_bytes(take(&mut sent));
+        let chunk_flags = u16::from_be_bytes(take(&mut sent));
+        let reply_type = u16::from_be_bytes(take(&mut sent));
+        assert_eq!(u64::from_be_bytes(take(&mut sent)), HANDLE);
+        let len = match extended {
+            true => {
+                assert_eq!(magic, NBD_EXTENDED_REPLY_MAGIC);
+                assert_eq!(u64::from_be_bytes(take(&mut sent)), offset);
+                u64::from_be_bytes(take(&mut sent)) as usize
+            }
+            false => {
+                assert_eq!(magic, NBD_STRUCTURED_REPLY_MAGIC);
+                u32::from_be_bytes(take(&mut sent)) as usize
+            }
+        };
+        let (payload, after) = sent.split_at(len);
+        sent = after;
+
+        if chunk_flags & NBD_REPLY_FLAG_DONE != 0 {
+            assert_eq!(reply_type, NBD_REPLY_TYPE_NONE);
+            assert!(payload.is_empty());
+            break;
+        }
+
+        assert_eq!(reply_type, NBD_REPLY_TYPE_OFFSET_DATA);
+        let mut payload = payload;
+        let chunk_offset = u64::from_be_bytes(take(&mut payload));
+        assert_eq!(chunk_offset, offset + data.len() as u64);
+        assert!(!payload.is_empty());
+        data.extend_from_slice(payload);
+        chunks += 1;
+    }
+
+    assert!(sent.is_empty());
+    assert_eq!(data, rest);
+    // DF reads come in one piece
+    if flags & NBD_CMD_FLAG_DF != 0 && !rest.is_empty() {
+        assert_eq!(chunks, 1);
+    }
+});
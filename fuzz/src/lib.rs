@@ -0,0 +1,112 @@
+//! Helpers shared by the fuzz targets.
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use nbd::{backend::Backend, Export, Server, ServerOptions};
+
+pub const EXPORT_SIZE: usize = 64 * 1024;
+
+/// A client connection replaying the fuzzer's input, everything the server
+/// sends is dropped. Reads see EOF once the input runs out.
+pub struct Replay<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Replay<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Replay { input }
+    }
+}
+
+impl Read for Replay<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Replay<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps the export in memory, so inputs can't touch the disk.
+#[derive(Debug)]
+pub struct Memory {
+    data: RwLock<Vec<u8>>,
+}
+
+impl Memory {
+    pub fn new(size: usize) -> Self {
+        Memory {
+            data: RwLock::new(vec![0; size]),
+        }
+    }
+
+    fn range(&self, offset: u64, len: u64) -> io::Result<std::ops::Range<usize>> {
+        let size = self.data.read().unwrap().len() as u64;
+        match offset.checked_add(len) {
+            Some(end) if end <= size => Ok(offset as usize..end as usize),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Out of bounds")),
+        }
+    }
+}
+
+impl Backend for Memory {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let range = self.range(offset, buf.len() as u64)?;
+        buf.copy_from_slice(&self.data.read().unwrap()[range]);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let range = self.range(offset, buf.len() as u64)?;
+        self.data.write().unwrap()[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn trim(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.write_zeroes(offset, len, true)
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, _may_trim: bool) -> io::Result<()> {
+        let range = self.range(offset, len)?;
+        self.data.write().unwrap()[range].fill(0);
+        Ok(())
+    }
+
+    fn can_multi_conn(&self) -> bool {
+        true
+    }
+}
+
+/// A server with a single in-memory export named `fuzz`, and no timeouts
+/// since inputs never stall.
+pub fn server() -> Server {
+    let backend = Arc::new(Memory::new(EXPORT_SIZE));
+    let export = Export::new(String::from("fuzz"), String::from("Fuzzing"), backend)
+        .expect("in-memory exports always have a size");
+
+    let options = ServerOptions {
+        shutdown_grace: Duration::ZERO,
+        handshake_timeout: None,
+        ..ServerOptions::default()
+    };
+
+    Server::with_options(vec![export], options)
+}
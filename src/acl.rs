@@ -0,0 +1,355 @@
+use std::{
+    fmt, io,
+    net::IpAddr,
+    os::unix::{net::UnixStream, prelude::AsRawFd},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::pki_types::CertificateDer;
+use serde::Deserialize;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Who is on the other end of a connection, as far as we can tell.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Peer {
+    /// The address of a TCP client
+    pub ip: Option<IpAddr>,
+    /// The credentials of a UNIX socket client
+    pub credentials: Option<Credentials>,
+    /// The common name of the certificate a TLS client presented
+    pub tls_identity: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Peer {
+    pub fn from_ip(ip: IpAddr) -> Self {
+        Peer {
+            ip: Some(ip.to_canonical()),
+            ..Peer::default()
+        }
+    }
+
+    /// Reads the credentials of the process that connected to a UNIX
+    /// socket with `SO_PEERCRED`.
+    pub fn from_unix(stream: &UnixStream) -> io::Result<Self> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Peer {
+            credentials: Some(Credentials {
+                uid: cred.uid,
+                gid: cred.gid,
+            }),
+            ..Peer::default()
+        })
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(ip) = self.ip {
+            parts.push(ip.to_string());
+        }
+        if let Some(cred) = self.credentials {
+            parts.push(format!("uid={} gid={}", cred.uid, cred.gid));
+        }
+        if let Some(identity) = &self.tls_identity {
+            parts.push(format!("tls={}", identity));
+        }
+
+        match parts.is_empty() {
+            true => write!(f, "unknown"),
+            false => write!(f, "{}", parts.join(" ")),
+        }
+    }
+}
+
+/// The common name in the subject of a DER encoded certificate.
+pub fn certificate_identity(cert: &CertificateDer<'_>) -> Result<String> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref())
+        .map_err(|e| anyhow!("Invalid client certificate: {}", e))?;
+
+    let cn = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .context("Client certificate has no common name")?;
+
+    Ok(cn
+        .as_str()
+        .context("Client certificate common name is not a string")?
+        .to_owned())
+}
+
+/// An IP network, e.g. `10.0.0.0/8`. A plain address matches just itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                self.prefix,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    let shift = bits - prefix;
+    (net ^ ip).checked_shr(shift as u32).unwrap_or(0) == 0
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("Invalid address {:?}", s))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .with_context(|| format!("Invalid prefix length in {:?}", s))?,
+            None => bits,
+        };
+        if prefix > bits {
+            bail!("Prefix length in {:?} is longer than {} bits", s, bits);
+        }
+
+        // Peers are matched as IPv4 when they're IPv4-mapped, so must these
+        // networks be
+        let canonical = addr.to_canonical();
+        let prefix = match (addr, canonical) {
+            (IpAddr::V6(_), IpAddr::V4(_)) if prefix < 96 => {
+                bail!(
+                    "Prefix length of IPv4-mapped {:?} is shorter than 96 bits",
+                    s
+                )
+            }
+            (IpAddr::V6(_), IpAddr::V4(_)) => prefix - 96,
+            _ => prefix,
+        };
+
+        Ok(Cidr {
+            addr: canonical,
+            prefix,
+        })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// A single access rule, every condition it sets must hold for it to match.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub action: Action,
+    pub address: Option<Cidr>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub tls_identity: Option<String>,
+}
+
+impl Rule {
+    fn matches(&self, peer: &Peer) -> bool {
+        let address = self
+            .address
+            .is_none_or(|net| peer.ip.is_some_and(|ip| net.contains(ip)));
+        let uid = self
+            .uid
+            .is_none_or(|uid| peer.credentials.is_some_and(|c| c.uid == uid));
+        let gid = self
+            .gid
+            .is_none_or(|gid| peer.credentials.is_some_and(|c| c.gid == gid));
+        let tls_identity = self
+            .tls_identity
+            .as_ref()
+            .is_none_or(|identity| peer.tls_identity.as_ref() == Some(identity));
+
+        address && uid && gid && tls_identity
+    }
+}
+
+/// The access rules of an export.
+///
+/// The first matching rule decides. Without rules everyone is allowed, with
+/// rules anyone not matching one is denied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Acl { rules }
+    }
+
+    pub fn allows(&self, peer: &Peer) -> bool {
+        if self.rules.is_empty() {
+            return true;
+        }
+
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(peer))
+            .is_some_and(|rule| rule.action == Action::Allow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net = cidr("10.1.0.0/16");
+        assert!(net.contains(ip("10.1.0.0")));
+        assert!(net.contains(ip("10.1.255.255")));
+        assert!(!net.contains(ip("10.2.0.0")));
+        assert!(!net.contains(ip("::1")));
+
+        // A plain address is a /32 or /128
+        assert!(cidr("192.168.1.1").contains(ip("192.168.1.1")));
+        assert!(!cidr("192.168.1.1").contains(ip("192.168.1.2")));
+        assert!(cidr("fe80::1").contains(ip("fe80::1")));
+        assert!(!cidr("fe80::1").contains(ip("fe80::2")));
+
+        let net = cidr("2001:db8::/32");
+        assert!(net.contains(ip("2001:db8:ffff::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+
+        // Host bits in the network don't matter
+        assert!(cidr("10.1.2.3/8").contains(ip("10.200.0.1")));
+
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0/8", "host/8"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_cidr_everything() {
+        let net = cidr("0.0.0.0/0");
+        assert!(net.contains(ip("0.0.0.0")));
+        assert!(net.contains(ip("255.255.255.255")));
+        assert!(net.contains(ip("::ffff:1.2.3.4")));
+        assert!(!net.contains(ip("::1")));
+
+        let net = cidr("::/0");
+        assert!(net.contains(ip("::1")));
+        assert!(net.contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!net.contains(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn test_cidr_ipv4_mapped() {
+        // Dual-stack sockets see IPv4 clients as mapped IPv6 addresses
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+
+        // And mapped networks match IPv4 peers
+        let net = cidr("::ffff:10.0.0.0/104");
+        assert_eq!(net, cidr("10.0.0.0/8"));
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(cidr("::ffff:10.1.2.3").contains(ip("10.1.2.3")));
+        assert!("::ffff:10.0.0.0/64".parse::<Cidr>().is_err());
+
+        assert_eq!(
+            Peer::from_ip(ip("::ffff:10.1.2.3")).ip,
+            Some(ip("10.1.2.3"))
+        );
+    }
+
+    #[test]
+    fn test_acl() {
+        let rule = |action, address: Option<&str>, uid| Rule {
+            action,
+            address: address.map(cidr),
+            uid,
+            gid: None,
+            tls_identity: None,
+        };
+        let tcp = |s| Peer::from_ip(ip(s));
+        let unix = |uid| Peer {
+            credentials: Some(Credentials { uid, gid: uid }),
+            ..Peer::default()
+        };
+
+        assert!(Acl::default().allows(&tcp("1.2.3.4")));
+
+        // The first match decides, anyone else is denied
+        let acl = Acl::new(vec![
+            rule(Action::Deny, Some("10.0.0.1"), None),
+            rule(Action::Allow, Some("10.0.0.0/8"), None),
+            rule(Action::Allow, None, Some(1000)),
+        ]);
+        assert!(!acl.allows(&tcp("10.0.0.1")));
+        assert!(acl.allows(&tcp("10.0.0.2")));
+        assert!(!acl.allows(&tcp("192.168.0.1")));
+        assert!(acl.allows(&unix(1000)));
+        assert!(!acl.allows(&unix(0)));
+        // Address rules never match UNIX clients
+        assert!(!Acl::new(vec![rule(Action::Allow, Some("0.0.0.0/0"), None)]).allows(&unix(0)));
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::DirBuilderExt,
+        net::{UnixListener, UnixStream},
+        prelude::PermissionsExt,
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, sleep},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use crate::{config, config::ExportConfig, Server};
+
+/// A command sent to the admin socket, one JSON object per line, e.g.
+/// `{"command": "kick", "id": 3}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Request {
+    ListConnections,
+    ListExports,
+    AddExport { export: Box<ExportConfig> },
+    RemoveExport { name: String },
+    Kick { id: u64 },
+    SetReadOnly { name: String, read_only: bool },
+    Flush { name: Option<String> },
+    Resize { name: String, size: u64 },
+    CreateBitmap { name: String, bitmap: String },
+    ClearBitmap { name: String, bitmap: String },
+    Reload,
+}
+
+/// The reply to every request, also a single line of JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Serves the admin socket until `stop` is set.
+///
+/// `config` is the file exports are reloaded from, if there is one.
+pub fn serve_admin(
+    server: &Arc<Server>,
+    path: &Path,
+    config: Option<PathBuf>,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let listener = bind_private(path)
+        .with_context(|| format!("Failed to bind admin socket {}", path.display()))?;
+    listener.set_nonblocking(true)?;
+    info!("Admin socket listening on {}", path.display());
+
+    let config = Arc::new(config);
+    for conn in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+
+        match conn {
+            Ok(stream) => {
+                let server = Arc::clone(server);
+                let config = Arc::clone(&config);
+                thread::spawn(move || {
+                    if let Err(e) = handle_admin(&server, config.as_deref(), stream) {
+                        debug!("Admin connection failed: {}", e);
+                    }
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                sleep(Duration::from_millis(100));
+            }
+            Err(e) => warn!("error: {}", e),
+        }
+    }
+
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Binds a socket only its owner can connect to at `path`.
+///
+/// Anyone who can connect can take the server apart, so the socket is bound
+/// in a directory of its own and only linked to `path` once locked down.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let mut private = name.to_owned();
+    private.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(private);
+    DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let socket = dir.join("socket");
+    let result = UnixListener::bind(&socket).and_then(|listener| {
+        fs::set_permissions(&socket, Permissions::from_mode(0o600))?;
+        // Unlike a rename, fails when something is already there
+        fs::hard_link(&socket, path)?;
+        Ok(listener)
+    });
+
+    let _ = fs::remove_file(&socket);
+    let _ = fs::remove_dir(&dir);
+    result
+}
+
+fn handle_admin(server: &Server, config: Option<&Path>, stream: UnixStream) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                info!(?request, "Admin request");
+                match execute(server, config, request) {
+                    Ok(result) => Response {
+                        ok: true,
+                        result: Some(result),
+                        error: None,
+                    },
+                    Err(e) => Response {
+                        ok: false,
+                        result: None,
+                        error: Some(format!("{:#}", e)),
+                    },
+                }
+            }
+            Err(e) => Response {
+                ok: false,
+                result: None,
+                error: Some(format!("Invalid request: {}", e)),
+            },
+        };
+
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+fn execute(server: &Server, config: Option<&Path>, request: Request) -> Result<Value> {
+    let result = match request {
+        Request::ListConnections => {
+            let connections: Vec<_> = server
+                .connections()
+                .list()
+                .iter()
+                .map(|c| {
+                    let stats = c.stats();
+                    json!({
+                        "id": c.id(),
+                        "addr": c.addr(),
+                        "export": c.export(),
+                        "requests": stats.requests.load(Ordering::Relaxed),
+                        "bytes_read": stats.bytes_read.load(Ordering::Relaxed),
+                        "bytes_written": stats.bytes_written.load(Ordering::Relaxed),
+                        "errors": stats.errors.load(Ordering::Relaxed),
+                    })
+                })
+                .collect();
+
+            json!(connections)
+        }
+        Request::ListExports => {
+            let connections = server.connections().list();
+            let exports: Vec<_> = server
+                .exports()
+                .iter()
+                .map(|e| {
+                    let clients = connections
+                        .iter()
+                        .filter(|c| c.export().as_deref() == Some(e.name()))
+                        .count();
+                    json!({
+                        "name": e.name(),
+                        "description": e.description(),
+                        "size": e.size(),
+                        "read_only": e.read_only(),
+                        "connections": clients,
+                        "bitmaps": e.bitmaps().iter().map(|b| b.name()).collect::<Vec<_>>(),
+                    })
+                })
+                .collect();
+
+            json!(exports)
+        }
+        Request::AddExport { export } => {
+            let mut names = Vec::new();
+            for export in export.open()? {
+                names.push(export.name().to_owned());
+                server.add_export(export)?;
+            }
+            json!({ "names": names })
+        }
+        Request::RemoveExport { name } => {
+            server.remove_export(&name)?;
+            json!({ "name": name })
+        }
+        Request::Kick { id } => {
+            let conn = server
+                .connections()
+                .get(id)
+                .ok_or_else(|| anyhow!("No such connection {}", id))?;
+            if !conn.can_close() {
+                bail!("Connection {} is served over stdio and can't be closed", id);
+            }
+            info!(client = conn.addr(), "Disconnecting client on request");
+            conn.close();
+            json!({ "id": id })
+        }
+        Request::SetReadOnly { name, read_only } => {
+            server.set_read_only(&name, read_only)?;
+            json!({ "name": name, "read_only": read_only })
+        }
+        Request::Flush { name } => {
+            let exports: Vec<_> = server
+                .exports()
+                .into_iter()
+                .filter(|e| name.as_deref().is_none_or(|name| e.name() == name))
+                .collect();
+            if exports.is_empty() {
+                bail!("No such export {:?}", name.unwrap_or_default());
+            }
+
+            for export in &exports {
+                export
+                    .backend()
+                    .flush()
+                    .with_context(|| format!("Failed to flush {:?}", export.name()))?;
+            }
+            json!({ "flushed": exports.iter().map(|e| e.name()).collect::<Vec<_>>() })
+        }
+        Request::Resize { name, size } => {
+            server.resize_export(&name, size)?;
+            json!({ "name": name, "size": size })
+        }
+        Request::CreateBitmap { name, bitmap } => {
+            server.create_bitmap(&name, &bitmap)?;
+            json!({ "name": name, "bitmap": bitmap })
+        }
+        Request::ClearBitmap { name, bitmap } => {
+            server.clear_bitmap(&name, &bitmap)?;
+            json!({ "name": name, "bitmap": bitmap })
+        }
+        Request::Reload => match config {
+            Some(path) => {
+                config::reload(server, path)?;
+                json!({ "exports": server.exports().iter().map(|e| e.name()).collect::<Vec<_>>() })
+            }
+            None => bail!("The server wasn't started with a config file"),
+        },
+    };
+
+    Ok(result)
+}
+
+/// Sends a single request to the admin socket at `path` and returns its
+/// result, used by `nbd ctl`.
+pub fn request(path: &Path, request: &Value) -> Result<Value> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Failed to connect to {}", path.display()))?;
+    serde_json::to_writer(&mut stream, request)?;
+    stream.write_all(b"\n")?;
+    stream.flush()?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response: Response = serde_json::from_str(&line).context("Invalid response")?;
+
+    match response {
+        Response {
+            ok: true, result, ..
+        } => Ok(result.unwrap_or(Value::Null)),
+        Response { error, .. } => bail!(error.unwrap_or_else(|| String::from("Request failed"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::{connections::Closer, test_util::TempDir, Export};
+
+    #[test]
+    fn test_bind_private() {
+        let dir = TempDir::new();
+        let path = dir.0.join("admin.sock");
+
+        let _listener = bind_private(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        UnixStream::connect(&path).unwrap();
+
+        // Nothing is left behind, and nothing is replaced
+        let entries: Vec<_> = fs::read_dir(&dir.0).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        let e = bind_private(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 1);
+        UnixStream::connect(&path).unwrap();
+    }
+
+    #[test]
+    fn test_kick() {
+        let dir = TempDir::new();
+        let file = dir.0.join("disk.img");
+        fs::write(&file, vec![0; 4096]).unwrap();
+        let export = Export::init_export(
+            file.to_string_lossy().into_owned(),
+            String::from("disk"),
+            String::new(),
+        )
+        .unwrap();
+        let server = Server::new(export);
+
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let socket = server
+            .connections()
+            .register("unix", Some(Closer::Unix(ours)));
+        let stdio = server.connections().register("stdio", None);
+
+        let kick = |id| execute(&server, None, Request::Kick { id });
+        kick(socket.connection().id()).unwrap();
+        assert_eq!(theirs.read(&mut [0; 1]).unwrap(), 0);
+
+        // Claiming a client was disconnected when it wasn't would be a lie
+        assert!(kick(stdio.connection().id()).is_err());
+        assert!(kick(1000).is_err());
+    }
+}
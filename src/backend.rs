@@ -0,0 +1,1201 @@
+use std::{
+    alloc::{self, Layout},
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io,
+    ops::{Deref, DerefMut},
+    os::unix::{
+        fs::{FileTypeExt, OpenOptionsExt},
+        prelude::{AsRawFd, FileExt},
+    },
+    path::Path,
+    ptr::NonNull,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    consts::{MAX_BLOCK_SIZE, NBD_STATE_HOLE, NBD_STATE_ZERO},
+    xts::Xts,
+    BlockSize,
+};
+
+// From linux/fs.h, the libc crate only has the sector size ones. The request
+// type is c_ulong on glibc but c_int on musl, where BLKGETSIZE64 wraps.
+const BLKGETSIZE64: libc::Ioctl = 0x80081272u32 as libc::Ioctl;
+const BLKDISCARD: libc::Ioctl = 0x1277u32 as libc::Ioctl;
+const BLKZEROOUT: libc::Ioctl = 0x127fu32 as libc::Ioctl;
+// Keeps the reply to a status query on a badly fragmented file bounded, the
+// client asks again for the rest
+pub(crate) const MAX_EXTENTS: usize = 64 * 1024;
+// Memory alignment that satisfies O_DIRECT on any device, and the block
+// size used for regular files opened with it
+pub const DIRECT_ALIGNMENT: u32 = 4096;
+
+/// A run of bytes with the same allocation state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub len: u64,
+    /// `NBD_STATE_HOLE` and `NBD_STATE_ZERO` bits
+    pub flags: u32,
+}
+
+/// Storage behind an export.
+///
+/// Backends are shared by every connection to the export, so all I/O goes
+/// through `&self` with explicit offsets.
+pub trait Backend: Debug + Send + Sync {
+    fn size(&self) -> io::Result<u64>;
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Makes every completed write durable.
+    fn flush(&self) -> io::Result<()>;
+
+    /// Discards a range, reads from it may return anything afterwards.
+    fn trim(&self, offset: u64, len: u64) -> io::Result<()>;
+
+    /// Zeroes a range, punching a hole when `may_trim` allows it.
+    fn write_zeroes(&self, offset: u64, len: u64, may_trim: bool) -> io::Result<()>;
+
+    /// Hints that a range will be read soon.
+    fn cache(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Describes how a range is allocated. The extents may stop short of
+    /// the end of the range but always cover some of it.
+    fn extents(&self, _offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        Ok(vec![Extent { len, flags: 0 }])
+    }
+
+    /// Whether the backend was opened without write access.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Grows or shrinks the storage.
+    fn resize(&self, _size: u64) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// The block sizes the storage works best with, when it has any.
+    fn block_size(&self) -> Option<BlockSize> {
+        None
+    }
+
+    /// Whether a write completed on one connection is visible to reads on
+    /// every other, and a flush on any connection makes all of them
+    /// durable, which is what `NBD_FLAG_CAN_MULTI_CONN` promises.
+    fn can_multi_conn(&self) -> bool {
+        false
+    }
+}
+
+/// A regular file or a block device, e.g. a partition or an LVM volume.
+#[derive(Debug)]
+pub struct FileBackend {
+    file: File,
+    read_only: bool,
+    device: Option<Device>,
+}
+
+/// What the kernel tells us about a block device.
+#[derive(Debug, Clone, Copy)]
+struct Device {
+    size: u64,
+    logical_sector: u32,
+    physical_sector: u32,
+}
+
+impl FileBackend {
+    pub fn open(path: &Path, read_only: bool) -> Result<Self> {
+        FileBackend::open_with(path, read_only, 0)
+    }
+
+    /// Opens with `O_DIRECT`, bypassing the page cache. I/O then has to be
+    /// aligned, which [`Aligned`] takes care of.
+    pub fn open_direct(path: &Path, read_only: bool) -> Result<Self> {
+        FileBackend::open_with(path, read_only, libc::O_DIRECT)
+    }
+
+    fn open_with(path: &Path, read_only: bool, flags: libc::c_int) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .custom_flags(flags)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+
+        let device = match file.metadata()?.file_type().is_block_device() {
+            true => Some(
+                Device::probe(&file)
+                    .with_context(|| format!("Failed to query block device {}", path.display()))?,
+            ),
+            false => None,
+        };
+
+        Ok(FileBackend {
+            file,
+            read_only,
+            device,
+        })
+    }
+
+    /// Runs a `BLKDISCARD` or `BLKZEROOUT` ioctl on a range.
+    fn ioctl_range(&self, request: libc::Ioctl, offset: u64, len: u64) -> io::Result<()> {
+        let range: [u64; 2] = [offset, len];
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), request, range.as_ptr()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn discard(&self, device: &Device, offset: u64, len: u64) -> io::Result<()> {
+        // Discarding is only a hint, the unaligned edges are left alone
+        let sector = device.logical_sector as u64;
+        let start = offset.div_ceil(sector) * sector;
+        let end = (offset + len) / sector * sector;
+        if start >= end {
+            return Ok(());
+        }
+
+        self.ioctl_range(BLKDISCARD, start, end - start)
+    }
+
+    fn zero_out(&self, device: &Device, offset: u64, len: u64) -> io::Result<()> {
+        // BLKZEROOUT wants whole sectors, the edges are written by hand
+        let sector = device.logical_sector as u64;
+        let start = offset.div_ceil(sector) * sector;
+        let end = (offset + len) / sector * sector;
+        if start >= end {
+            return write_zeroes_slow(self, offset, len);
+        }
+
+        write_zeroes_slow(self, offset, start - offset)?;
+        match self.ioctl_range(BLKZEROOUT, start, end - start) {
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                write_zeroes_slow(self, start, end - start)?
+            }
+            result => result?,
+        }
+        write_zeroes_slow(self, end, offset + len - end)
+    }
+
+    fn seek(&self, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+        let ret = unsafe { libc::lseek(self.file.as_raw_fd(), offset as libc::off_t, whence) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ret as u64)
+    }
+
+    fn fallocate(&self, mode: libc::c_int, offset: u64, len: u64) -> io::Result<()> {
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                mode,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl Device {
+    fn probe(file: &File) -> io::Result<Device> {
+        let fd = file.as_raw_fd();
+        let mut size: u64 = 0;
+        let mut logical_sector: libc::c_int = 0;
+        let mut physical_sector: libc::c_uint = 0;
+
+        for ret in unsafe {
+            [
+                libc::ioctl(fd, BLKGETSIZE64, &mut size),
+                libc::ioctl(fd, libc::BLKSSZGET, &mut logical_sector),
+                libc::ioctl(fd, libc::BLKPBSZGET, &mut physical_sector),
+            ]
+        } {
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(Device {
+            size,
+            logical_sector: logical_sector as u32,
+            physical_sector: physical_sector.max(logical_sector as u32),
+        })
+    }
+}
+
+impl Backend for FileBackend {
+    fn size(&self) -> io::Result<u64> {
+        // The metadata of a block device says it's empty
+        match &self.device {
+            Some(device) => Ok(device.size),
+            None => Ok(self.file.metadata()?.len()),
+        }
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn trim(&self, offset: u64, len: u64) -> io::Result<()> {
+        if let Some(device) = &self.device {
+            return self.discard(device, offset, len);
+        }
+
+        self.fallocate(
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+        )
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, may_trim: bool) -> io::Result<()> {
+        // A discarded range of a device doesn't have to read back as zeroes
+        if let Some(device) = &self.device {
+            return self.zero_out(device, offset, len);
+        }
+
+        let result = if may_trim {
+            self.trim(offset, len)
+        } else {
+            self.fallocate(
+                libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+                offset,
+                len,
+            )
+        };
+
+        match result {
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                write_zeroes_slow(self, offset, len)
+            }
+            result => result,
+        }
+    }
+
+    fn cache(&self, offset: u64, len: u64) -> io::Result<()> {
+        let ret = unsafe {
+            libc::posix_fadvise(
+                self.file.as_raw_fd(),
+                offset as libc::off_t,
+                len as libc::off_t,
+                libc::POSIX_FADV_WILLNEED,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+
+        Ok(())
+    }
+
+    fn extents(&self, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        // The kernel doesn't track holes of block devices
+        if self.device.is_some() {
+            return Ok(vec![Extent { len, flags: 0 }]);
+        }
+
+        let end = offset + len;
+        let mut extents = Vec::new();
+        let mut pos = offset;
+        while pos < end && extents.len() < MAX_EXTENTS {
+            let data = match self.seek(pos, libc::SEEK_DATA) {
+                Ok(data) => data.min(end),
+                // Only a hole up to the end of the file
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => end,
+                // The filesystem can't tell, everything is data
+                Err(_) if extents.is_empty() => return Ok(vec![Extent { len, flags: 0 }]),
+                Err(e) => return Err(e),
+            };
+            if data > pos {
+                extents.push(Extent {
+                    len: data - pos,
+                    flags: NBD_STATE_HOLE | NBD_STATE_ZERO,
+                });
+                pos = data;
+                continue;
+            }
+
+            let hole = self.seek(pos, libc::SEEK_HOLE)?.min(end);
+            extents.push(Extent {
+                len: hole - pos,
+                flags: 0,
+            });
+            pos = hole;
+        }
+
+        Ok(extents)
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        // Block devices are as large as they are
+        if self.device.is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+
+        self.file.set_len(size)
+    }
+
+    fn block_size(&self) -> Option<BlockSize> {
+        self.device.map(|device| BlockSize {
+            minimum: device.logical_sector,
+            preferred: device.physical_sector,
+            maximum: MAX_BLOCK_SIZE as u32,
+        })
+    }
+
+    // Every connection shares the page cache of the same file, and
+    // fdatasync covers all of its dirty pages
+    fn can_multi_conn(&self) -> bool {
+        true
+    }
+}
+
+/// Zeroes a range by writing zeroes, for backends that can't do better.
+pub fn write_zeroes_slow<B: Backend + ?Sized>(
+    backend: &B,
+    offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    const CHUNK: u64 = 1024 * 1024;
+    // Aligned in case the file was opened with O_DIRECT
+    let zeroes = AlignedBuf::new(std::cmp::min(len, CHUNK) as usize);
+
+    let mut done = 0;
+    while done < len {
+        let n = std::cmp::min(len - done, CHUNK) as usize;
+        backend.write_at(&zeroes[..n], offset + done)?;
+        done += n as u64;
+    }
+
+    Ok(())
+}
+
+/// A zeroed buffer aligned to `DIRECT_ALIGNMENT`.
+struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        // Zero-sized allocations aren't allowed
+        let layout = Layout::from_size_align(len.max(1), DIRECT_ALIGNMENT as usize).unwrap();
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+
+        AlignedBuf { ptr, len, layout }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// Serves any offset and length from a backend that only takes whole,
+/// aligned blocks, like a file opened with `O_DIRECT`.
+///
+/// Data goes through aligned buffers, and writes that only cover part of a
+/// block read the rest of it first.
+#[derive(Debug)]
+pub struct Aligned {
+    inner: Arc<dyn Backend>,
+    block: u64,
+    /// Two partial writes to the same block must not interleave
+    rmw: Mutex<()>,
+}
+
+impl Aligned {
+    pub fn new(inner: Arc<dyn Backend>, block: u32) -> Result<Self> {
+        if !block.is_power_of_two() || block > DIRECT_ALIGNMENT {
+            bail!(
+                "Block size {} must be a power of two up to {}",
+                block,
+                DIRECT_ALIGNMENT
+            );
+        }
+
+        let size = inner.size()?;
+        if !size.is_multiple_of(block as u64) {
+            bail!(
+                "Size {} is not a multiple of the {} byte block size",
+                size,
+                block
+            );
+        }
+
+        Ok(Aligned {
+            inner,
+            block: block as u64,
+            rmw: Mutex::new(()),
+        })
+    }
+
+    /// The whole blocks covering a range.
+    fn covering(&self, offset: u64, len: u64) -> io::Result<(u64, u64)> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.inner.size()? => Ok((
+                offset / self.block * self.block,
+                end.div_ceil(self.block) * self.block,
+            )),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+}
+
+impl Backend for Aligned {
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let (start, end) = self.covering(offset, buf.len() as u64)?;
+        let mut bounce = AlignedBuf::new((end - start) as usize);
+        self.inner.read_at(&mut bounce, start)?;
+
+        let at = (offset - start) as usize;
+        buf.copy_from_slice(&bounce[at..at + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let (start, end) = self.covering(offset, buf.len() as u64)?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let mut bounce = AlignedBuf::new((end - start) as usize);
+        let at = (offset - start) as usize;
+
+        let partial = at != 0 || end - start != buf.len() as u64;
+        let _rmw = partial.then(|| self.rmw.lock().unwrap());
+        if at != 0 {
+            let block = self.block as usize;
+            self.inner.read_at(&mut bounce[..block], start)?;
+        }
+        if (at + buf.len()) as u64 != end - start {
+            let block = self.block as usize;
+            let last = bounce.len() - block;
+            self.inner.read_at(&mut bounce[last..], end - self.block)?;
+        }
+
+        bounce[at..at + buf.len()].copy_from_slice(buf);
+        self.inner.write_at(&bounce, start)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn trim(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.covering(offset, len)?;
+
+        // Partial blocks are left alone, trimming is only a hint
+        let start = offset.div_ceil(self.block) * self.block;
+        let end = (offset + len) / self.block * self.block;
+        match end > start {
+            true => self.inner.trim(start, end - start),
+            false => Ok(()),
+        }
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, may_trim: bool) -> io::Result<()> {
+        self.covering(offset, len)?;
+
+        let start = offset.div_ceil(self.block) * self.block;
+        let end = (offset + len) / self.block * self.block;
+        if end <= start {
+            return self.write_at(&vec![0; len as usize], offset);
+        }
+
+        self.write_at(&vec![0; (start - offset) as usize], offset)?;
+        self.inner.write_zeroes(start, end - start, may_trim)?;
+        self.write_at(&vec![0; (offset + len - end) as usize], end)
+    }
+
+    fn cache(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.inner.cache(offset, len)
+    }
+
+    fn extents(&self, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        self.inner.extents(offset, len)
+    }
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        if !size.is_multiple_of(self.block) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        self.inner.resize(size)
+    }
+
+    fn block_size(&self) -> Option<BlockSize> {
+        let block = self.block as u32;
+        let inner = self.inner.block_size().unwrap_or_default();
+
+        // Requests down to a byte are aligned here, only a minimum the inner
+        // backend needs beyond the block still has to reach the client
+        Some(BlockSize {
+            minimum: if inner.minimum > block {
+                inner.minimum
+            } else {
+                BlockSize::default().minimum
+            },
+            preferred: inner.preferred.max(block),
+            maximum: inner.maximum,
+        })
+    }
+
+    fn can_multi_conn(&self) -> bool {
+        self.inner.can_multi_conn()
+    }
+}
+
+/// A window into another backend, e.g. one partition of a disk image.
+#[derive(Debug)]
+pub struct Slice {
+    inner: Arc<dyn Backend>,
+    offset: u64,
+    len: u64,
+}
+
+impl Slice {
+    /// The `len` bytes of `inner` starting at `offset`, up to its end when
+    /// `len` isn't given.
+    pub fn new(inner: Arc<dyn Backend>, offset: u64, len: Option<u64>) -> Result<Self> {
+        let size = inner.size()?;
+        if offset > size {
+            bail!(
+                "Offset {} is past the end of the backend ({} bytes)",
+                offset,
+                size
+            );
+        }
+
+        let len = len.unwrap_or(size - offset);
+        if offset.checked_add(len).is_none_or(|end| end > size) {
+            bail!(
+                "Range of {} bytes at offset {} is past the end of the backend ({} bytes)",
+                len,
+                offset,
+                size
+            );
+        }
+
+        Ok(Slice { inner, offset, len })
+    }
+
+    /// Translates a range to the inner backend, refusing anything that
+    /// would reach outside the window.
+    fn translate(&self, offset: u64, len: u64) -> io::Result<u64> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(self.offset + offset),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+}
+
+impl Backend for Slice {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let offset = self.translate(offset, buf.len() as u64)?;
+        self.inner.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let offset = self.translate(offset, buf.len() as u64)?;
+        self.inner.write_at(buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn trim(&self, offset: u64, len: u64) -> io::Result<()> {
+        let offset = self.translate(offset, len)?;
+        self.inner.trim(offset, len)
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, may_trim: bool) -> io::Result<()> {
+        let offset = self.translate(offset, len)?;
+        self.inner.write_zeroes(offset, len, may_trim)
+    }
+
+    fn cache(&self, offset: u64, len: u64) -> io::Result<()> {
+        let offset = self.translate(offset, len)?;
+        self.inner.cache(offset, len)
+    }
+
+    fn extents(&self, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let offset = self.translate(offset, len)?;
+        self.inner.extents(offset, len)
+    }
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+
+    fn block_size(&self) -> Option<BlockSize> {
+        self.inner.block_size()
+    }
+
+    fn can_multi_conn(&self) -> bool {
+        self.inner.can_multi_conn()
+    }
+}
+
+/// Several backends one after another, e.g. a disk image split into
+/// `disk.000`, `disk.001`, ...
+#[derive(Debug)]
+pub struct Concat {
+    parts: Vec<Arc<dyn Backend>>,
+    /// Where each part starts in the combined backend
+    starts: Vec<u64>,
+    size: u64,
+}
+
+impl Concat {
+    pub fn new(parts: Vec<Arc<dyn Backend>>) -> Result<Self> {
+        if parts.is_empty() {
+            bail!("Nothing to concatenate");
+        }
+
+        let mut starts = Vec::with_capacity(parts.len());
+        let mut size: u64 = 0;
+        for part in &parts {
+            starts.push(size);
+            size = size
+                .checked_add(part.size()?)
+                .context("Concatenated size overflows")?;
+        }
+
+        Ok(Concat {
+            parts,
+            starts,
+            size,
+        })
+    }
+
+    /// Calls `f` for every part a range touches with the part, the offset
+    /// in it, where that piece starts within the range and its length.
+    fn each(
+        &self,
+        offset: u64,
+        len: u64,
+        mut f: impl FnMut(&dyn Backend, u64, usize, u64) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let end = match offset.checked_add(len) {
+            Some(end) if end <= self.size => end,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+
+        let first = self.starts.partition_point(|start| *start <= offset) - 1;
+        let mut pos = offset;
+        for i in first..self.parts.len() {
+            if pos >= end {
+                break;
+            }
+
+            let start = self.starts[i];
+            let part_end = self.starts.get(i + 1).copied().unwrap_or(self.size);
+            let n = std::cmp::min(end, part_end).saturating_sub(pos);
+            if n > 0 {
+                f(
+                    self.parts[i].as_ref(),
+                    pos - start,
+                    (pos - offset) as usize,
+                    n,
+                )?;
+                pos += n;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Backend for Concat {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.each(offset, buf.len() as u64, |part, at, pos, n| {
+            part.read_at(&mut buf[pos..pos + n as usize], at)
+        })
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.each(offset, buf.len() as u64, |part, at, pos, n| {
+            part.write_at(&buf[pos..pos + n as usize], at)
+        })
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.parts.iter().try_for_each(|part| part.flush())
+    }
+
+    fn trim(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.each(offset, len, |part, at, _, n| part.trim(at, n))
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, may_trim: bool) -> io::Result<()> {
+        self.each(offset, len, |part, at, _, n| {
+            part.write_zeroes(at, n, may_trim)
+        })
+    }
+
+    fn cache(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.each(offset, len, |part, at, _, n| part.cache(at, n))
+    }
+
+    fn extents(&self, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+        // A part that stopped short ends the answer, there can't be a gap
+        let mut short = false;
+        self.each(offset, len, |part, at, _, n| {
+            if short {
+                return Ok(());
+            }
+
+            let found = part.extents(at, n)?;
+            short = found.iter().map(|e| e.len).sum::<u64>() < n;
+            extents.extend(found);
+            Ok(())
+        })?;
+
+        Ok(extents)
+    }
+
+    fn read_only(&self) -> bool {
+        self.parts.iter().any(|part| part.read_only())
+    }
+
+    fn can_multi_conn(&self) -> bool {
+        self.parts.iter().all(|part| part.can_multi_conn())
+    }
+}
+
+/// Keeps the data of another backend encrypted with AES-XTS, clients see the
+/// plaintext.
+///
+/// Only whole sectors can be read or written, each encrypted with its
+/// number as the tweak; wrap it in `Aligned` to serve other requests.
+#[derive(Debug)]
+pub struct Encrypted {
+    inner: Arc<dyn Backend>,
+    cipher: Xts,
+    sector: u64,
+}
+
+impl Encrypted {
+    pub fn new(inner: Arc<dyn Backend>, cipher: Xts, sector: u32) -> Result<Self> {
+        if !sector.is_power_of_two() || !(512..=4096).contains(&sector) {
+            bail!(
+                "Sector size {} must be a power of two from 512 to 4096",
+                sector
+            );
+        }
+
+        let size = inner.size()?;
+        if !size.is_multiple_of(sector as u64) {
+            bail!(
+                "Size {} is not a multiple of the {} byte sector size",
+                size,
+                sector
+            );
+        }
+
+        Ok(Encrypted {
+            inner,
+            cipher,
+            sector: sector as u64,
+        })
+    }
+
+    fn check(&self, offset: u64, len: u64) -> io::Result<()> {
+        match offset.is_multiple_of(self.sector) && len.is_multiple_of(self.sector) {
+            true => Ok(()),
+            false => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    /// Writes encrypted zeroes, a hole in the inner backend would read back
+    /// as garbage.
+    fn zero(&self, offset: u64, len: u64) -> io::Result<()> {
+        const CHUNK: u64 = 1024 * 1024;
+        let mut buf = AlignedBuf::new(std::cmp::min(len, CHUNK) as usize);
+
+        let mut done = 0;
+        while done < len {
+            let n = std::cmp::min(len - done, CHUNK) as usize;
+            buf[..n].fill(0);
+            self.encrypt(&mut buf[..n], offset + done);
+            self.inner.write_at(&buf[..n], offset + done)?;
+            done += n as u64;
+        }
+
+        Ok(())
+    }
+
+    fn encrypt(&self, buf: &mut [u8], offset: u64) {
+        let first = offset / self.sector;
+        for (i, sector) in buf.chunks_exact_mut(self.sector as usize).enumerate() {
+            self.cipher.encrypt(sector, first + i as u64);
+        }
+    }
+
+    fn decrypt(&self, buf: &mut [u8], offset: u64) {
+        let first = offset / self.sector;
+        for (i, sector) in buf.chunks_exact_mut(self.sector as usize).enumerate() {
+            self.cipher.decrypt(sector, first + i as u64);
+        }
+    }
+}
+
+impl Backend for Encrypted {
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.check(offset, buf.len() as u64)?;
+        self.inner.read_at(buf, offset)?;
+        self.decrypt(buf, offset);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.check(offset, buf.len() as u64)?;
+        let mut encrypted = AlignedBuf::new(buf.len());
+        encrypted.copy_from_slice(buf);
+        self.encrypt(&mut encrypted, offset);
+        self.inner.write_at(&encrypted, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn trim(&self, offset: u64, len: u64) -> io::Result<()> {
+        // Discarding would show which sectors are in use, and trimming is
+        // only a hint
+        self.check(offset, len)
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, _may_trim: bool) -> io::Result<()> {
+        self.check(offset, len)?;
+        match offset.checked_add(len) {
+            Some(end) if end <= self.inner.size()? => self.zero(offset, len),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    fn cache(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.inner.cache(offset, len)
+    }
+
+    // Holes and zeroes of the inner backend don't read back as zeroes, so
+    // everything is reported as data
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+
+    fn resize(&self, size: u64) -> io::Result<()> {
+        if !size.is_multiple_of(self.sector) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let old = self.inner.size()?;
+        self.inner.resize(size)?;
+        match size > old {
+            true => self.zero(old, size - old),
+            false => Ok(()),
+        }
+    }
+
+    fn block_size(&self) -> Option<BlockSize> {
+        let sector = self.sector as u32;
+        let inner = self.inner.block_size().unwrap_or_default();
+
+        Some(BlockSize {
+            minimum: inner.minimum.max(sector),
+            preferred: inner.preferred.max(sector),
+            maximum: inner.maximum,
+        })
+    }
+
+    fn can_multi_conn(&self) -> bool {
+        self.inner.can_multi_conn()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Storage in memory, recording the ranges it was asked to write.
+    #[derive(Debug, Default)]
+    struct Memory {
+        data: Mutex<Vec<u8>>,
+        writes: Mutex<Vec<(u64, u64)>>,
+    }
+
+    impl Memory {
+        fn new(data: Vec<u8>) -> Arc<Memory> {
+            Arc::new(Memory {
+                data: Mutex::new(data),
+                ..Memory::default()
+            })
+        }
+
+        fn writes(&self) -> Vec<(u64, u64)> {
+            std::mem::take(&mut self.writes.lock().unwrap())
+        }
+
+        fn range(&self, offset: u64, len: u64) -> io::Result<std::ops::Range<usize>> {
+            match offset.checked_add(len) {
+                Some(end) if end <= self.data.lock().unwrap().len() as u64 => {
+                    Ok(offset as usize..end as usize)
+                }
+                _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            }
+        }
+    }
+
+    impl Backend for Memory {
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.data.lock().unwrap().len() as u64)
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            let range = self.range(offset, buf.len() as u64)?;
+            buf.copy_from_slice(&self.data.lock().unwrap()[range]);
+            Ok(())
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+            let range = self.range(offset, buf.len() as u64)?;
+            self.data.lock().unwrap()[range].copy_from_slice(buf);
+            self.writes.lock().unwrap().push((offset, buf.len() as u64));
+            Ok(())
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn trim(&self, offset: u64, len: u64) -> io::Result<()> {
+            self.write_zeroes(offset, len, true)
+        }
+
+        fn write_zeroes(&self, offset: u64, len: u64, _may_trim: bool) -> io::Result<()> {
+            let range = self.range(offset, len)?;
+            self.data.lock().unwrap()[range].fill(0);
+            self.writes.lock().unwrap().push((offset, len));
+            Ok(())
+        }
+    }
+
+    fn pattern(offset: u64, len: u64) -> Vec<u8> {
+        (offset..offset + len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Parts of 100, 0, 50 and 200 bytes holding the pattern between them.
+    fn concat() -> (Concat, Vec<Arc<Memory>>) {
+        let mut start = 0;
+        let parts: Vec<_> = [100, 0, 50, 200]
+            .into_iter()
+            .map(|len| {
+                let part = Memory::new(pattern(start, len));
+                start += len;
+                part
+            })
+            .collect();
+        let concat = Concat::new(
+            parts
+                .iter()
+                .map(|part| Arc::clone(part) as Arc<dyn Backend>)
+                .collect(),
+        )
+        .unwrap();
+
+        (concat, parts)
+    }
+
+    #[test]
+    fn test_aligned() {
+        let inner = Memory::new(pattern(0, 4096));
+        let aligned = Aligned::new(Arc::clone(&inner) as Arc<dyn Backend>, 512).unwrap();
+
+        let mut buf = vec![0; 1000];
+        aligned.read_at(&mut buf, 300).unwrap();
+        assert_eq!(buf, pattern(300, 1000));
+
+        // Partial blocks are read, modified and written whole
+        aligned.write_at(&[1; 100], 700).unwrap();
+        assert_eq!(inner.writes(), [(512, 512)]);
+        aligned.write_at(&[2; 1000], 1300).unwrap();
+        assert_eq!(inner.writes(), [(1024, 1536)]);
+        aligned.write_at(&[3; 512], 3584).unwrap();
+        assert_eq!(inner.writes(), [(3584, 512)]);
+        aligned.write_at(&[], 100).unwrap();
+        assert_eq!(inner.writes(), []);
+
+        let mut expected = pattern(0, 4096);
+        expected[700..800].fill(1);
+        expected[1300..2300].fill(2);
+        expected[3584..].fill(3);
+        let mut buf = vec![0; 4096];
+        aligned.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, expected);
+
+        assert!(aligned.read_at(&mut [0; 2], 4095).is_err());
+        assert!(aligned.write_at(&[0; 2], 4095).is_err());
+    }
+
+    #[test]
+    fn test_aligned_zeroes() {
+        let inner = Memory::new(pattern(0, 4096));
+        let aligned = Aligned::new(Arc::clone(&inner) as Arc<dyn Backend>, 512).unwrap();
+
+        // The whole blocks in the middle are zeroed by the inner backend
+        aligned.write_zeroes(100, 1900, true).unwrap();
+        assert_eq!(inner.writes(), [(0, 512), (512, 1024), (1536, 512)]);
+        aligned.write_zeroes(2100, 100, true).unwrap();
+        assert_eq!(inner.writes(), [(2048, 512)]);
+
+        let mut expected = pattern(0, 4096);
+        expected[100..2000].fill(0);
+        expected[2100..2200].fill(0);
+        let mut buf = vec![0; 4096];
+        aligned.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, expected);
+
+        // Trimming leaves partial blocks alone
+        aligned.trim(100, 600).unwrap();
+        assert_eq!(inner.writes(), []);
+        aligned.trim(100, 1000).unwrap();
+        assert_eq!(inner.writes(), [(512, 512)]);
+    }
+
+    #[test]
+    fn test_aligned_sizes() {
+        let inner = || Memory::new(vec![0; 4096]) as Arc<dyn Backend>;
+        assert!(Aligned::new(inner(), 4096).is_ok());
+        assert!(Aligned::new(inner(), 1000).is_err());
+        assert!(Aligned::new(inner(), 8192).is_err());
+        assert!(Aligned::new(Memory::new(vec![0; 1000]), 512).is_err());
+    }
+
+    #[test]
+    fn test_concat_each() {
+        let (concat, _) = concat();
+        assert_eq!(concat.size().unwrap(), 350);
+
+        let pieces = |offset, len| {
+            let mut pieces = Vec::new();
+            concat
+                .each(offset, len, |part, at, pos, n| {
+                    pieces.push((part.size().unwrap(), at, pos, n));
+                    Ok(())
+                })
+                .map(|_| pieces)
+        };
+
+        // Up to, from and across the boundaries, never touching the empty part
+        assert_eq!(pieces(0, 100).unwrap(), [(100, 0, 0, 100)]);
+        assert_eq!(pieces(100, 50).unwrap(), [(50, 0, 0, 50)]);
+        assert_eq!(pieces(99, 2).unwrap(), [(100, 99, 0, 1), (50, 0, 1, 1)]);
+        assert_eq!(
+            pieces(50, 300).unwrap(),
+            [(100, 50, 0, 50), (50, 0, 50, 50), (200, 0, 100, 200)]
+        );
+        assert_eq!(pieces(349, 1).unwrap(), [(200, 199, 0, 1)]);
+
+        // Empty ranges touch nothing, even at the end
+        assert_eq!(pieces(100, 0).unwrap(), []);
+        assert_eq!(pieces(350, 0).unwrap(), []);
+
+        assert!(pieces(350, 1).is_err());
+        assert!(pieces(300, 51).is_err());
+        assert!(pieces(u64::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn test_concat_io() {
+        let (concat, parts) = concat();
+
+        let mut buf = vec![0; 350];
+        concat.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, pattern(0, 350));
+
+        concat.write_at(&[1; 100], 90).unwrap();
+        concat.write_zeroes(140, 20, false).unwrap();
+        assert_eq!(parts[0].writes(), [(90, 10)]);
+        assert_eq!(parts[1].writes(), []);
+        assert_eq!(parts[2].writes(), [(0, 50), (40, 10)]);
+        assert_eq!(parts[3].writes(), [(0, 40), (0, 10)]);
+
+        let mut expected = pattern(0, 350);
+        expected[90..190].fill(1);
+        expected[140..160].fill(0);
+        concat.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, expected);
+
+        assert!(Concat::new(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,510 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, Weak},
+};
+
+use anyhow::{bail, Context, Result};
+use tracing::{info, warn};
+
+use crate::{
+    backend::{Extent, MAX_EXTENTS},
+    consts::NBD_STATE_DIRTY,
+};
+
+pub const DEFAULT_GRANULARITY: u64 = 64 * 1024;
+
+const MAGIC: &[u8; 8] = b"NBDDIRTY";
+const VERSION: u32 = 1;
+// Set while blocks may have been written without the bitmap being saved
+const FLAG_IN_USE: u32 = 1;
+// magic, version, flags, granularity and the size of the export
+const HEADER_SIZE: usize = 8 + 4 + 4 + 8 + 8;
+
+// Reloads build new exports while the clients of the old ones keep writing,
+// both have to mark the same bitmap
+static OPEN: Mutex<Vec<(PathBuf, Weak<DirtyBitmap>)>> = Mutex::new(Vec::new());
+
+/// Tracks which blocks of an export were written, for incremental backups.
+///
+/// The bitmap lives in memory and is saved to a file. The file is marked in
+/// use before the first write after a save, a bitmap found in use wasn't
+/// saved on shutdown and is loaded as all dirty.
+#[derive(Debug)]
+pub struct DirtyBitmap {
+    name: String,
+    path: PathBuf,
+    granularity: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    size: u64,
+    bits: Vec<u64>,
+    in_use: bool,
+}
+
+impl State {
+    fn set(&mut self, first: u64, last: u64) {
+        for bit in first..=last {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    fn get(&self, bit: u64) -> bool {
+        self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+}
+
+/// Bitmap names become part of file and context names.
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > 255 {
+        bail!("Bitmap names must be 1 to 255 bytes long");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        || name.starts_with('.')
+    {
+        bail!(
+            "Invalid bitmap name {:?}, use letters, digits, '-', '_' and '.'",
+            name
+        );
+    }
+
+    Ok(())
+}
+
+/// Where the bitmap `name` of `export` is saved in `dir`.
+///
+/// The export name is percent-encoded, leaving no '.' to confuse it with the
+/// bitmap name, and the empty name is "%", which no encoding gives. Every
+/// export and bitmap pair so gets a file of its own.
+pub fn path(dir: &Path, export: &str, name: &str) -> PathBuf {
+    let export: String = match export {
+        "" => String::from("%"),
+        _ => export
+            .bytes()
+            .map(
+                |b| match b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_') {
+                    true => char::from(b).to_string(),
+                    false => format!("%{:02X}", b),
+                },
+            )
+            .collect(),
+    };
+
+    dir.join(format!("{}.{}.bitmap", export, name))
+}
+
+fn bits(size: u64, granularity: u64) -> u64 {
+    size.div_ceil(granularity)
+}
+
+impl DirtyBitmap {
+    /// Opens the bitmap saved at `path`, or creates a clean one, for an
+    /// export of `size` bytes.
+    ///
+    /// A bitmap that is already open is shared.
+    pub fn open(name: &str, path: &Path, size: u64, granularity: u64) -> Result<Arc<DirtyBitmap>> {
+        let mut open = OPEN.lock().unwrap();
+        open.retain(|(_, bitmap)| bitmap.strong_count() > 0);
+        if let Some(bitmap) = open
+            .iter()
+            .find(|(p, _)| p == path)
+            .and_then(|(_, bitmap)| bitmap.upgrade())
+        {
+            return Ok(bitmap);
+        }
+
+        let bitmap = match File::open(path) {
+            Ok(file) => DirtyBitmap::load(name, path, file, size)
+                .with_context(|| format!("Failed to load bitmap {}", path.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if !granularity.is_power_of_two() || granularity < 512 {
+                    bail!("Bitmap granularity must be a power of two of at least 512");
+                }
+
+                info!(bitmap = name, path = %path.display(), "Creating dirty bitmap");
+                DirtyBitmap {
+                    name: name.to_owned(),
+                    path: path.to_owned(),
+                    granularity,
+                    state: Mutex::new(State {
+                        size,
+                        bits: vec![0; bits(size, granularity).div_ceil(64) as usize],
+                        in_use: false,
+                    }),
+                }
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to open {}", path.display()));
+            }
+        };
+
+        bitmap
+            .save(&bitmap.state(), false)
+            .with_context(|| format!("Failed to save bitmap {}", path.display()))?;
+
+        let bitmap = Arc::new(bitmap);
+        open.push((path.to_owned(), Arc::downgrade(&bitmap)));
+        Ok(bitmap)
+    }
+
+    fn load(name: &str, path: &Path, mut file: File, size: u64) -> Result<DirtyBitmap> {
+        let mut header = [0; HEADER_SIZE];
+        file.read_exact(&mut header)?;
+        if &header[0..8] != MAGIC {
+            bail!("Not a dirty bitmap");
+        }
+
+        let be32 = |b: &[u8]| u32::from_be_bytes(b.try_into().unwrap());
+        let be64 = |b: &[u8]| u64::from_be_bytes(b.try_into().unwrap());
+        if be32(&header[8..12]) != VERSION {
+            bail!("Unsupported version {}", be32(&header[8..12]));
+        }
+        let flags = be32(&header[12..16]);
+        let granularity = be64(&header[16..24]);
+        let saved_size = be64(&header[24..32]);
+        if !granularity.is_power_of_two() || granularity < 512 {
+            bail!("Invalid granularity {}", granularity);
+        }
+
+        let words = bits(saved_size, granularity).div_ceil(64);
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        if data.len() as u64 != words * 8 {
+            bail!("Bitmap data doesn't match its header");
+        }
+
+        let mut state = State {
+            size: saved_size,
+            bits: data
+                .chunks_exact(8)
+                .map(|w| u64::from_le_bytes(w.try_into().unwrap()))
+                .collect(),
+            in_use: false,
+        };
+
+        if flags & FLAG_IN_USE != 0 {
+            warn!(
+                bitmap = name,
+                "Dirty bitmap wasn't saved on shutdown, marking everything dirty"
+            );
+            state.bits.fill(u64::MAX);
+        }
+
+        let bitmap = DirtyBitmap {
+            name: name.to_owned(),
+            path: path.to_owned(),
+            granularity,
+            state: Mutex::new(state),
+        };
+        if saved_size != size {
+            warn!(
+                bitmap = name,
+                from = saved_size,
+                to = size,
+                "Export changed size while the bitmap was saved"
+            );
+            bitmap.resize_state(&mut bitmap.state(), size);
+        }
+
+        Ok(bitmap)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Marks a range as written, saving the bitmap as in use first when
+    /// needed.
+    pub fn mark(&self, offset: u64, len: u64) -> io::Result<()> {
+        let mut state = self.state();
+        let count = bits(state.size, self.granularity);
+        if len == 0 || offset >= state.size {
+            return Ok(());
+        }
+
+        if !state.in_use {
+            self.save(&state, true)?;
+            state.in_use = true;
+        }
+
+        let last = (offset.saturating_add(len - 1) / self.granularity).min(count - 1);
+        state.set(offset / self.granularity, last);
+        Ok(())
+    }
+
+    /// Describes a range as dirty and clean extents.
+    pub fn extents(&self, offset: u64, len: u64) -> Vec<Extent> {
+        let state = self.state();
+        let end = offset.saturating_add(len).min(state.size);
+
+        let mut extents: Vec<Extent> = Vec::new();
+        let mut pos = offset;
+        while pos < end && extents.len() < MAX_EXTENTS {
+            let bit = pos / self.granularity;
+            let flags = match state.get(bit) {
+                true => NBD_STATE_DIRTY,
+                false => 0,
+            };
+            let next = ((bit + 1) * self.granularity).min(end);
+
+            match extents.last_mut() {
+                Some(last) if last.flags == flags => last.len += next - pos,
+                _ => extents.push(Extent {
+                    len: next - pos,
+                    flags,
+                }),
+            }
+            pos = next;
+        }
+
+        extents
+    }
+
+    /// Forgets every write, usually once a backup was taken.
+    pub fn clear(&self) -> io::Result<()> {
+        let mut state = self.state();
+        state.bits.fill(0);
+        self.save(&state, state.in_use)?;
+
+        info!(bitmap = %self.name, "Cleared dirty bitmap");
+        Ok(())
+    }
+
+    /// Follows the export to a new size, a grown area counts as written.
+    pub fn resize(&self, size: u64) {
+        self.resize_state(&mut self.state(), size);
+    }
+
+    fn resize_state(&self, state: &mut State, size: u64) {
+        let old = bits(state.size, self.granularity);
+        let new = bits(size, self.granularity);
+
+        state.bits.resize(new.div_ceil(64) as usize, 0);
+        if new > old {
+            state.set(old, new - 1);
+        } else if !new.is_multiple_of(64) {
+            // Bits past the end would come back if it grows again
+            state.bits[(new / 64) as usize] &= (1 << (new % 64)) - 1;
+        }
+        state.size = size;
+    }
+
+    /// Saves the bitmap as not in use, it is marked in use again by the
+    /// next write.
+    pub fn close(&self) -> io::Result<()> {
+        let mut state = self.state();
+        if state.in_use {
+            self.save(&state, false)?;
+            state.in_use = false;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the file, syncing it before anything relies on it.
+    fn save(&self, state: &State, in_use: bool) -> io::Result<()> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + state.bits.len() * 8);
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&VERSION.to_be_bytes());
+        data.extend_from_slice(&(if in_use { FLAG_IN_USE } else { 0 }).to_be_bytes());
+        data.extend_from_slice(&self.granularity.to_be_bytes());
+        data.extend_from_slice(&state.size.to_be_bytes());
+        for word in &state.bits {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+
+        let tmp = self.path.with_extension("bitmap.tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for DirtyBitmap {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            warn!(bitmap = %self.name, "Failed to save dirty bitmap: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const SIZE: u64 = 1024 * 1024;
+    const GRANULARITY: u64 = 64 * 1024;
+
+    fn extent(len: u64, dirty: bool) -> Extent {
+        let flags = match dirty {
+            true => NBD_STATE_DIRTY,
+            false => 0,
+        };
+        Extent { len, flags }
+    }
+
+    fn in_use(path: &Path) -> bool {
+        let header = fs::read(path).unwrap();
+        u32::from_be_bytes(header[12..16].try_into().unwrap()) & FLAG_IN_USE != 0
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir = TempDir::new();
+        let path = dir.0.join("disk.backup.bitmap");
+
+        let bitmap = DirtyBitmap::open("backup", &path, SIZE, GRANULARITY).unwrap();
+        assert!(!in_use(&path));
+        bitmap.mark(100, 1).unwrap();
+        bitmap.mark(3 * GRANULARITY - 1, 2).unwrap();
+        // On disk before the write happens
+        assert!(in_use(&path));
+        drop(bitmap);
+        assert!(!in_use(&path));
+
+        // The granularity is the saved one
+        let bitmap = DirtyBitmap::open("backup", &path, SIZE, 512).unwrap();
+        let expected = [
+            extent(GRANULARITY, true),
+            extent(GRANULARITY, false),
+            extent(2 * GRANULARITY, true),
+            extent(SIZE - 4 * GRANULARITY, false),
+        ];
+        assert_eq!(bitmap.extents(0, SIZE), expected);
+
+        bitmap.clear().unwrap();
+        drop(bitmap);
+        let bitmap = DirtyBitmap::open("backup", &path, SIZE, GRANULARITY).unwrap();
+        assert_eq!(bitmap.extents(0, SIZE), [extent(SIZE, false)]);
+    }
+
+    #[test]
+    fn test_shared_until_closed() {
+        let dir = TempDir::new();
+        let path = dir.0.join("disk.backup.bitmap");
+
+        // Exports built by a reload mark the bitmap of the ones they replace
+        let old = DirtyBitmap::open("backup", &path, SIZE, GRANULARITY).unwrap();
+        let new = DirtyBitmap::open("backup", &path, SIZE, GRANULARITY).unwrap();
+        assert!(Arc::ptr_eq(&old, &new));
+        old.mark(0, 1).unwrap();
+        drop(old);
+        assert_eq!(new.extents(0, GRANULARITY), [extent(GRANULARITY, true)]);
+        assert!(in_use(&path));
+
+        drop(new);
+        let reopened = DirtyBitmap::open("backup", &path, SIZE, GRANULARITY).unwrap();
+        assert_eq!(
+            reopened.extents(0, GRANULARITY),
+            [extent(GRANULARITY, true)]
+        );
+    }
+
+    #[test]
+    fn test_unclean_shutdown() {
+        let dir = TempDir::new();
+        let path = dir.0.join("disk.backup.bitmap");
+        let crashed = dir.0.join("crashed.backup.bitmap");
+
+        let bitmap = DirtyBitmap::open("backup", &path, SIZE, GRANULARITY).unwrap();
+        bitmap.mark(0, 1).unwrap();
+        // What a crash would have left behind
+        fs::copy(&path, &crashed).unwrap();
+        drop(bitmap);
+
+        let bitmap = DirtyBitmap::open("backup", &crashed, SIZE, GRANULARITY).unwrap();
+        assert_eq!(bitmap.extents(0, SIZE), [extent(SIZE, true)]);
+    }
+
+    #[test]
+    fn test_resized_while_saved() {
+        let dir = TempDir::new();
+        let path = dir.0.join("disk.backup.bitmap");
+        drop(DirtyBitmap::open("backup", &path, SIZE, GRANULARITY).unwrap());
+
+        // Whatever was written to the grown area is unknown
+        let bitmap = DirtyBitmap::open("backup", &path, 2 * SIZE, GRANULARITY).unwrap();
+        assert_eq!(
+            bitmap.extents(0, 2 * SIZE),
+            [extent(SIZE, false), extent(SIZE, true)]
+        );
+
+        bitmap.resize(SIZE / 2);
+        bitmap.resize(SIZE);
+        assert_eq!(
+            bitmap.extents(0, SIZE),
+            [extent(SIZE / 2, false), extent(SIZE / 2, true)]
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let dir = TempDir::new();
+        let path = dir.0.join("disk.backup.bitmap");
+        assert!(DirtyBitmap::open("backup", &path, SIZE, 1000).is_err());
+        assert!(DirtyBitmap::open("backup", &path, SIZE, 256).is_err());
+
+        fs::write(&path, b"garbage").unwrap();
+        assert!(DirtyBitmap::open("backup", &path, SIZE, GRANULARITY).is_err());
+
+        // Cut short
+        drop(DirtyBitmap::open("backup", &dir.0.join("other"), SIZE, GRANULARITY).unwrap());
+        let data = fs::read(dir.0.join("other")).unwrap();
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(DirtyBitmap::open("backup", &path, SIZE, GRANULARITY).is_err());
+    }
+
+    #[test]
+    fn test_path() {
+        let dir = Path::new("/var/lib/nbd");
+        assert_eq!(
+            path(dir, "disk", "backup"),
+            Path::new("/var/lib/nbd/disk.backup.bitmap")
+        );
+        assert_eq!(
+            path(dir, "a/b", "backup"),
+            Path::new("/var/lib/nbd/a%2Fb.backup.bitmap")
+        );
+
+        // Names that used to share a file
+        let pairs = [
+            ("a/b", "x"),
+            ("a_b", "x"),
+            ("a%2Fb", "x"),
+            ("", "x"),
+            ("default", "x"),
+            ("%", "x"),
+            ("a.b", "c"),
+            ("a", "b.c"),
+            ("..", "x"),
+            ("é", "x"),
+        ];
+        let paths: HashSet<_> = pairs.iter().map(|(e, n)| path(dir, e, n)).collect();
+        assert_eq!(paths.len(), pairs.len());
+
+        // Whatever the export is called the file stays in the directory
+        for (export, name) in pairs {
+            assert_eq!(path(dir, export, name).parent(), Some(dir));
+        }
+    }
+}
@@ -1,10 +1,35 @@
-use std::io::{Read, Write};
+use std::{
+    io::{self, Read, Write},
+    sync::Arc,
+};
+
+use crate::{
+    acl::Peer,
+    connections::{Closer, Connection, Lease},
+    meta::Context,
+    Export,
+};
 
 #[derive(Debug, Default)]
 pub struct Client<T: Read + Write> {
     stream: T,
     structured_reply: bool,
+    fixed_newstyle: bool,
+    no_zeroes: bool,
+    extended_headers: bool,
+    block_size_constraints: bool,
+    /// The export the metadata contexts were selected for, and the contexts
+    /// with their ids
+    meta_contexts: Option<(String, Vec<(u32, Context)>)>,
     addr: String,
+    closer: Option<Closer>,
+    /// The registered connection, whose deadline reads and writes keep to
+    conn: Option<Arc<Connection>>,
+    tls: bool,
+    export: Option<Arc<Export>>,
+    lease: Option<Lease>,
+    peer: Peer,
+    oldstyle: Option<String>,
 }
 
 impl<T: Read + Write> Client<T> {
@@ -12,7 +37,19 @@ impl<T: Read + Write> Client<T> {
         Client {
             stream,
             structured_reply: false,
+            fixed_newstyle: false,
+            no_zeroes: false,
+            extended_headers: false,
+            block_size_constraints: false,
+            meta_contexts: None,
             addr,
+            closer: None,
+            conn: None,
+            tls: false,
+            export: None,
+            lease: None,
+            peer: Peer::default(),
+            oldstyle: None,
         }
     }
 
@@ -20,8 +57,12 @@ impl<T: Read + Write> Client<T> {
         &self.addr
     }
 
-    pub fn stream(&mut self) -> &mut T {
-        &mut self.stream
+    /// The client's stream, keeping to the connection's deadline.
+    pub fn stream(&mut self) -> Stream<'_, T> {
+        Stream {
+            inner: &mut self.stream,
+            conn: self.conn.as_deref(),
+        }
     }
 
     pub fn set_structured_reply(&mut self, value: bool) {
@@ -31,26 +72,167 @@ impl<T: Read + Write> Client<T> {
     pub fn structured_reply(&self) -> bool {
         self.structured_reply
     }
+
+    /// Whether the client set `NBD_FLAG_C_FIXED_NEWSTYLE`, older clients
+    /// can't be sent errors for options they don't know.
+    pub fn fixed_newstyle(&self) -> bool {
+        self.fixed_newstyle
+    }
+
+    pub fn set_fixed_newstyle(&mut self, value: bool) {
+        self.fixed_newstyle = value;
+    }
+
+    /// Whether the client set `NBD_FLAG_C_NO_ZEROES`, dropping the padding
+    /// after the `NBD_OPT_EXPORT_NAME` reply.
+    pub fn no_zeroes(&self) -> bool {
+        self.no_zeroes
+    }
+
+    pub fn set_no_zeroes(&mut self, value: bool) {
+        self.no_zeroes = value;
+    }
+
+    /// Whether requests and replies use the 64-bit headers of
+    /// `NBD_OPT_EXTENDED_HEADERS`.
+    pub fn extended_headers(&self) -> bool {
+        self.extended_headers
+    }
+
+    pub fn set_extended_headers(&mut self, value: bool) {
+        self.extended_headers = value;
+    }
+
+    /// Whether the client asked for `NBD_INFO_BLOCK_SIZE` when selecting the
+    /// export, requests breaking the constraints are then refused.
+    pub fn block_size_constraints(&self) -> bool {
+        self.block_size_constraints
+    }
+
+    pub fn set_block_size_constraints(&mut self, value: bool) {
+        self.block_size_constraints = value;
+    }
+
+    /// The contexts selected with `NBD_OPT_SET_META_CONTEXT`, none unless
+    /// they were selected for `export`.
+    pub fn meta_contexts(&self, export: &str) -> &[(u32, Context)] {
+        match &self.meta_contexts {
+            Some((name, contexts)) if name == export => contexts,
+            _ => &[],
+        }
+    }
+
+    pub fn set_meta_contexts(&mut self, export: String, contexts: Vec<(u32, Context)>) {
+        self.meta_contexts = Some((export, contexts));
+    }
+
+    /// Sets a handle the server can use to close the connection on shutdown.
+    pub fn set_closer(&mut self, closer: Closer) {
+        self.closer = Some(closer);
+    }
+
+    pub fn take_closer(&mut self) -> Option<Closer> {
+        self.closer.take()
+    }
+
+    pub fn set_connection(&mut self, conn: Arc<Connection>) {
+        self.conn = Some(conn);
+    }
+
+    /// Whether the session was upgraded with `NBD_OPT_STARTTLS`.
+    pub fn tls(&self) -> bool {
+        self.tls
+    }
+
+    pub fn set_tls(&mut self, value: bool) {
+        self.tls = value;
+    }
+
+    /// The export served with oldstyle negotiation, for clients of
+    /// listeners that predate options.
+    pub fn oldstyle(&self) -> Option<&str> {
+        self.oldstyle.as_deref()
+    }
+
+    pub fn set_oldstyle(&mut self, export: Option<String>) {
+        self.oldstyle = export;
+    }
+
+    /// Who the client is, checked against the access rules of exports.
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    pub fn set_peer(&mut self, peer: Peer) {
+        self.peer = peer;
+    }
+
+    /// The export selected during the handshake.
+    pub fn export(&self) -> Option<&Arc<Export>> {
+        self.export.as_ref()
+    }
+
+    pub fn set_export(&mut self, export: Arc<Export>) {
+        self.export = Some(export);
+    }
+
+    /// Holds the client's place on its export for as long as it is connected.
+    pub fn set_lease(&mut self, lease: Lease) {
+        self.lease = Some(lease);
+    }
 }
 
 impl<T: Read + Write> Write for Client<T> {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.stream.write(buf)
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream().write(buf)
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.stream.flush()
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream().flush()
     }
 }
 
 impl<T: Read + Write> Read for Client<T> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.stream.read(buf)
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream().read(buf)
+    }
+}
+
+/// A client's stream, arming the connection's deadline before each read
+/// and write.
+pub struct Stream<'a, T> {
+    inner: &'a mut T,
+    conn: Option<&'a Connection>,
+}
+
+impl<T> Stream<'_, T> {
+    pub fn get_ref(&self) -> &T {
+        self.inner
+    }
+
+    fn arm(&self) -> io::Result<()> {
+        match self.conn {
+            Some(conn) => conn.arm(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: Write> Write for Stream<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.arm()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.arm()?;
+        self.inner.flush()
     }
 }
 
-impl<T: Read + Write> Drop for Client<T> {
-    fn drop(&mut self) {
-        println!("Client {} disconnected", self.addr);
+impl<T: Read> Read for Stream<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.arm()?;
+        self.inner.read(buf)
     }
 }
@@ -0,0 +1,930 @@
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    acl::{Acl, Rule},
+    backend::{Aligned, Backend, Concat, Encrypted, FileBackend, Slice, DIRECT_ALIGNMENT},
+    bitmap::{self, DEFAULT_GRANULARITY},
+    connections::Writers,
+    consts::{
+        DEFAULT_HANDSHAKE_TIMEOUT_SECS, DEFAULT_SECTOR_SIZE, DEFAULT_SEND_TIMEOUT_SECS,
+        DEFAULT_SHUTDOWN_GRACE_SECS, MAX_BLOCK_SIZE, NBD_DEFAULT_PORT,
+    },
+    luks, partition,
+    qos::{Limits, Throttle},
+    tcp::Keepalive,
+    tls,
+    xts::Xts,
+    BlockSize, Export, Server, ServerOptions,
+};
+
+/// The server configuration, usually loaded with `nbd --config server.toml`.
+///
+/// ```toml
+/// [server]
+/// shutdown_grace = 10
+/// handshake_timeout = 30
+/// metrics = "127.0.0.1:9100"
+///
+/// [[listeners]]
+/// type = "tcp"
+/// address = "0.0.0.0:10809"
+///
+/// [tls]
+/// certificate = "/etc/nbd/server.pem"
+/// key = "/etc/nbd/server.key"
+///
+/// [[exports]]
+/// name = "disk"
+/// path = "/var/lib/nbd/disk.img"
+/// trim = true
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub exports: Vec<ExportConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Seconds connected clients get to disconnect on shutdown
+    #[serde(default = "default_shutdown_grace")]
+    pub shutdown_grace: u64,
+    /// Serve Prometheus metrics over HTTP on this address
+    pub metrics: Option<SocketAddr>,
+    /// Accept admin commands on this UNIX socket
+    pub admin_socket: Option<PathBuf>,
+    /// Seconds clients get to select an export, 0 waits forever
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+    /// Seconds a client may go without sending a request
+    pub idle_timeout: Option<u64>,
+    /// Seconds a client may leave replies unread, 0 waits forever
+    #[serde(default = "default_send_timeout")]
+    pub send_timeout: u64,
+    /// Probe idle TCP connections for dead peers
+    pub keepalive: Option<Keepalive>,
+    /// Seconds sent data may go unacknowledged before a TCP peer is dropped
+    pub tcp_user_timeout: Option<u64>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE_SECS,
+            metrics: None,
+            admin_socket: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT_SECS,
+            idle_timeout: None,
+            send_timeout: DEFAULT_SEND_TIMEOUT_SECS,
+            keepalive: None,
+            tcp_user_timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Listener {
+    Tcp {
+        address: SocketAddr,
+        /// Serve this export to oldstyle clients instead of negotiating
+        oldstyle: Option<String>,
+    },
+    Unix {
+        path: PathBuf,
+        oldstyle: Option<String>,
+    },
+}
+
+impl Listener {
+    /// The export served with oldstyle negotiation, if any.
+    pub fn oldstyle(&self) -> Option<&str> {
+        match self {
+            Listener::Tcp { oldstyle, .. } | Listener::Unix { oldstyle, .. } => oldstyle.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+    /// Require client certificates signed by this CA
+    pub ca: Option<PathBuf>,
+    /// Refuse clients that don't upgrade to TLS
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// A file or block device given by `path`
+    #[default]
+    File,
+    /// The files given by `paths`, one after another
+    Concat,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportConfig {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub backend: BackendKind,
+    /// The file of a `file` export
+    pub path: Option<PathBuf>,
+    /// The pieces of a `concat` export, in order
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+    /// Export only the part of the file starting at this byte
+    pub offset: Option<u64>,
+    /// Export only this many bytes, up to the end of the file by default
+    pub length: Option<u64>,
+    /// Export a single partition of the file's MBR or GPT
+    pub partition: Option<u32>,
+    /// Export every partition of the file as `<name>-part<N>`
+    #[serde(default)]
+    pub partitions: bool,
+    /// Bypass the page cache with `O_DIRECT`
+    #[serde(default)]
+    pub direct: bool,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub trim: bool,
+    #[serde(default)]
+    pub flush: bool,
+    #[serde(default)]
+    pub fua: bool,
+    #[serde(default)]
+    pub cache: bool,
+    #[serde(default)]
+    pub write_zeroes: bool,
+    #[serde(default = "default_true")]
+    pub multi_conn: bool,
+    #[serde(default)]
+    pub rotational: bool,
+    /// Let clients grow the export with `NBD_CMD_RESIZE`
+    #[serde(default)]
+    pub resize: bool,
+    /// Let clients shrink it too
+    #[serde(default)]
+    pub shrink: bool,
+    #[serde(default)]
+    pub block_size: Option<BlockSize>,
+    /// Checked in order, the first matching rule decides
+    #[serde(default)]
+    pub access: Vec<Rule>,
+    /// Clients beyond this many are refused
+    pub max_connections: Option<usize>,
+    #[serde(default)]
+    pub writers: Writers,
+    /// I/O limits shared by every client of the export
+    pub limits: Option<Limits>,
+    /// I/O limits applied to each connection on its own
+    pub connection_limits: Option<Limits>,
+    /// Dirty bitmaps tracking writes, offered as `qemu:dirty-bitmap:<name>`
+    #[serde(default)]
+    pub bitmaps: Vec<String>,
+    /// Where bitmaps are saved, next to the (first) file by default
+    pub bitmap_dir: Option<PathBuf>,
+    /// Bytes covered by each bit of new bitmaps
+    pub bitmap_granularity: Option<u64>,
+    /// Keep the data encrypted, clients see the plaintext
+    pub encryption: Option<Encryption>,
+    /// Serve the decrypted payload of a LUKS volume
+    pub luks: Option<Luks>,
+}
+
+/// AES-XTS encryption of an export's data.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Encryption {
+    /// The raw key, 32 bytes for AES-128-XTS or 64 for AES-256-XTS
+    pub keyfile: PathBuf,
+    /// Bytes encrypted together, 512 by default
+    pub sector_size: Option<u32>,
+}
+
+impl Encryption {
+    /// Wraps `backend` so its data is encrypted with the key.
+    fn open(&self, backend: Arc<dyn Backend>) -> Result<Arc<dyn Backend>> {
+        let key = read_keyfile(&self.keyfile)?;
+        let cipher = Xts::new(&key).context("Invalid keyfile")?;
+
+        let sector = self.sector_size.unwrap_or(DEFAULT_SECTOR_SIZE);
+        let encrypted = Encrypted::new(backend, cipher, sector)?;
+        Ok(Arc::new(Aligned::new(Arc::new(encrypted), sector)?))
+    }
+}
+
+/// The secret unlocking a LUKS volume, one of `passphrase` and `keyfile`.
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Luks {
+    pub passphrase: Option<String>,
+    /// Used whole as the passphrase, like `cryptsetup --key-file`
+    pub keyfile: Option<PathBuf>,
+}
+
+impl fmt::Debug for Luks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Luks")
+            .field("passphrase", &self.passphrase.as_ref().map(|_| "..."))
+            .field("keyfile", &self.keyfile)
+            .finish()
+    }
+}
+
+impl Luks {
+    /// Unlocks the volume in `backend`, giving its payload.
+    fn open(&self, backend: Arc<dyn Backend>) -> Result<Arc<dyn Backend>> {
+        let passphrase = match (&self.passphrase, &self.keyfile) {
+            (Some(passphrase), None) => passphrase.as_bytes().to_vec(),
+            (None, Some(keyfile)) => read_keyfile(keyfile)?,
+            _ => bail!("Exactly one of passphrase and keyfile is needed"),
+        };
+
+        luks::open(backend, &passphrase)
+    }
+}
+
+/// Reads a secret, warning when other users could too.
+fn read_keyfile(path: &Path) -> Result<Vec<u8>> {
+    let metadata =
+        fs::metadata(path).with_context(|| format!("Can't read keyfile {}", path.display()))?;
+    if metadata.permissions().mode() & 0o077 != 0 {
+        warn!("Keyfile {} can be read by other users", path.display());
+    }
+
+    fs::read(path).with_context(|| format!("Can't read keyfile {}", path.display()))
+}
+
+fn default_shutdown_grace() -> u64 {
+    DEFAULT_SHUTDOWN_GRACE_SECS
+}
+
+fn default_handshake_timeout() -> u64 {
+    DEFAULT_HANDSHAKE_TIMEOUT_SECS
+}
+
+fn default_send_timeout() -> u64 {
+    DEFAULT_SEND_TIMEOUT_SECS
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Config {
+    /// Reads and validates a configuration file.
+    pub fn load(path: &Path) -> Result<Config> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+
+        Config::parse(&contents).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn parse(contents: &str) -> Result<Config> {
+        let config: Config = toml::from_str(contents)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.exports.is_empty() {
+            bail!("No exports configured");
+        }
+
+        let mut names = HashSet::new();
+        for export in &self.exports {
+            if !names.insert(export.name.as_str()) {
+                bail!("Export {:?} is defined more than once", export.name);
+            }
+
+            export
+                .validate()
+                .with_context(|| format!("Export {:?}", export.name))?;
+        }
+
+        if self.server.idle_timeout == Some(0) {
+            bail!("idle_timeout must be at least 1 second");
+        }
+        if self.server.tcp_user_timeout == Some(0) {
+            bail!("tcp_user_timeout must be at least 1 second");
+        }
+        if let Some(keepalive) = self.server.keepalive {
+            if keepalive.idle == 0 || keepalive.interval == 0 || keepalive.count == 0 {
+                bail!("keepalive settings must be at least 1");
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for listener in &self.listeners {
+            if !seen.insert(listener) {
+                bail!("Listener {:?} is defined more than once", listener);
+            }
+            if listener.oldstyle().is_some() && self.tls.as_ref().is_some_and(|t| t.required) {
+                bail!("Oldstyle listeners can't be used when TLS is required");
+            }
+        }
+
+        if let Some(tls) = &self.tls {
+            for path in [Some(&tls.certificate), Some(&tls.key), tls.ca.as_ref()]
+                .into_iter()
+                .flatten()
+            {
+                if !path.exists() {
+                    bail!("TLS file {} does not exist", path.display());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The configured listeners, TCP on the default port when there are none.
+    pub fn listeners(&self) -> Vec<Listener> {
+        if self.listeners.is_empty() {
+            let address = SocketAddr::from(([0, 0, 0, 0], NBD_DEFAULT_PORT as u16));
+            return vec![Listener::Tcp {
+                address,
+                oldstyle: None,
+            }];
+        }
+
+        self.listeners.clone()
+    }
+
+    pub fn server_options(&self) -> Result<ServerOptions> {
+        let mut options = ServerOptions {
+            shutdown_grace: Duration::from_secs(self.server.shutdown_grace),
+            handshake_timeout: match self.server.handshake_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            idle_timeout: self.server.idle_timeout.map(Duration::from_secs),
+            send_timeout: match self.server.send_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            keepalive: self.server.keepalive,
+            user_timeout: self.server.tcp_user_timeout.map(Duration::from_secs),
+            ..ServerOptions::default()
+        };
+
+        if let Some(config) = &self.tls {
+            let tls = tls::server_config(&config.certificate, &config.key, config.ca.as_deref())?;
+            options.tls = Some(Arc::new(tls));
+            options.tls_required = config.required;
+        }
+
+        Ok(options)
+    }
+
+    /// Opens the backend of every export.
+    pub fn exports(&self) -> Result<Vec<Export>> {
+        self.exports_reusing(&[])
+    }
+
+    /// Like `exports`, but the exports of sections that didn't change since
+    /// `current` was built keep their backend and shared limits.
+    pub fn exports_reusing(&self, current: &[Arc<Export>]) -> Result<Vec<Export>> {
+        let mut exports = Vec::new();
+        for config in &self.exports {
+            let built = config
+                .build(current)
+                .with_context(|| format!("Export {:?}", config.name))?;
+            exports.extend(built);
+        }
+
+        // Partition exports are only named once the tables are read
+        let mut names = HashSet::new();
+        for export in &exports {
+            if !names.insert(export.name()) {
+                bail!("Export {:?} is defined more than once", export.name());
+            }
+        }
+
+        Ok(exports)
+    }
+}
+
+/// Reloads the exports of a running server from a configuration file.
+///
+/// Listeners, TLS and server settings only change on restart. When the file
+/// doesn't load the running exports are left alone. Sections that didn't
+/// change keep their open backends, so LUKS volumes aren't unlocked again
+/// and limits aren't reset.
+pub fn reload(server: &Server, path: &Path) -> Result<()> {
+    let config = Config::load(path)?;
+    server.set_exports(config.exports_reusing(&server.exports())?);
+
+    Ok(())
+}
+
+/// The config section an export was built from and the backend it opened,
+/// shared by the exports of the section.
+#[derive(Debug)]
+pub(crate) struct Source {
+    config: ExportConfig,
+    backend: Arc<dyn Backend>,
+}
+
+impl ExportConfig {
+    /// Validates the settings and opens the export's backend, giving one
+    /// export per partition with `partitions`.
+    pub fn open(&self) -> Result<Vec<Export>> {
+        self.validate()
+            .and_then(|_| self.build(&[]))
+            .with_context(|| format!("Export {:?}", self.name))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.name.len() > 4096 {
+            bail!("Name is longer than 4096 bytes");
+        }
+
+        match (self.backend, &self.path, self.paths.is_empty()) {
+            (BackendKind::File, Some(_), true) => {}
+            (BackendKind::File, _, _) => bail!("A file export needs a path and no paths"),
+            (BackendKind::Concat, None, false) => {}
+            (BackendKind::Concat, _, _) => bail!("A concat export needs paths and no path"),
+        }
+        for path in self.path.iter().chain(&self.paths) {
+            if !path.exists() {
+                bail!("Path {} does not exist", path.display());
+            }
+        }
+
+        if self.direct && self.backend != BackendKind::File {
+            bail!("direct is only supported by file exports");
+        }
+
+        if self.read_only && (self.trim || self.write_zeroes) {
+            bail!("trim and write_zeroes can't be enabled on a read-only export");
+        }
+
+        if let Some(block_size) = &self.block_size {
+            validate_block_size(block_size)?;
+        }
+
+        if self.shrink && !self.resize {
+            bail!("shrink needs resize");
+        }
+        if self.resize
+            && (self.read_only
+                || self.backend != BackendKind::File
+                || self.offset.is_some()
+                || self.length.is_some()
+                || self.partition.is_some()
+                || self.partitions
+                || self.luks.is_some())
+        {
+            bail!("Only writable exports of a whole file can be resized");
+        }
+
+        if self.partitions && self.partition.is_some() {
+            bail!("partition and partitions can't both be set");
+        }
+        if (self.partition.is_some() || self.partitions)
+            && (self.offset.is_some() || self.length.is_some())
+        {
+            bail!("offset and length can't be combined with partition or partitions");
+        }
+        if self.partition == Some(0) {
+            bail!("Partitions are numbered from 1");
+        }
+
+        if self.max_connections == Some(0) {
+            bail!("max_connections must be at least 1");
+        }
+
+        if let Some(limits) = &self.limits {
+            limits.validate().context("Invalid limits")?;
+        }
+        if let Some(limits) = &self.connection_limits {
+            limits.validate().context("Invalid connection_limits")?;
+        }
+
+        for name in &self.bitmaps {
+            bitmap::validate_name(name)?;
+        }
+
+        if let Some(encryption) = &self.encryption {
+            if !encryption.keyfile.exists() {
+                bail!("Keyfile {} does not exist", encryption.keyfile.display());
+            }
+        }
+        if let Some(luks) = &self.luks {
+            if self.encryption.is_some() {
+                bail!("encryption and luks can't both be set");
+            }
+            match (&luks.passphrase, &luks.keyfile) {
+                (Some(_), None) => {}
+                (None, Some(keyfile)) if !keyfile.exists() => {
+                    bail!("Keyfile {} does not exist", keyfile.display())
+                }
+                (None, Some(_)) => {}
+                _ => bail!("luks needs exactly one of passphrase and keyfile"),
+            }
+        }
+        if let Some(granularity) = self.bitmap_granularity {
+            if !granularity.is_power_of_two() || granularity < 512 {
+                bail!("bitmap_granularity must be a power of two of at least 512");
+            }
+        }
+
+        for (i, rule) in self.access.iter().enumerate() {
+            if rule.address.is_some() && (rule.uid.is_some() || rule.gid.is_some()) {
+                bail!(
+                    "Access rule {} matches both an address and UNIX credentials, no client has both",
+                    i + 1
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds the exports of the section, taking the backend of `current`
+    /// exports built from the same settings instead of opening it again.
+    fn build(&self, current: &[Arc<Export>]) -> Result<Vec<Export>> {
+        let previous = current
+            .iter()
+            .filter_map(|export| export.source.as_ref())
+            .find(|source| source.config == *self);
+        let source = match previous {
+            Some(source) => Arc::clone(source),
+            None => Arc::new(Source {
+                config: self.clone(),
+                backend: self.open_backend()?,
+            }),
+        };
+
+        let mut exports = self.slice(Arc::clone(&source.backend))?;
+        for export in &mut exports {
+            let previous = current.iter().find(|e| {
+                e.name == export.name && e.source.as_ref().is_some_and(|s| Arc::ptr_eq(s, &source))
+            });
+            // Clients of the old export and of this one share the limits,
+            // and see each other's resizes
+            if let Some(previous) = previous {
+                export.throttle = previous.throttle.clone();
+                export.size = Arc::clone(&previous.size);
+                export.resizing = Arc::clone(&previous.resizing);
+            }
+            export.source = Some(Arc::clone(&source));
+        }
+
+        Ok(exports)
+    }
+
+    /// Opens the file(s) of the section, decrypted when set up so.
+    fn open_backend(&self) -> Result<Arc<dyn Backend>> {
+        let backend: Arc<dyn Backend> = match self.backend {
+            BackendKind::File if self.direct => {
+                let path = self.path.as_deref().context("No path")?;
+                let file = FileBackend::open_direct(path, self.read_only)?;
+                // Devices take their logical sector size, files a page
+                let block = file.block_size().map_or(DIRECT_ALIGNMENT, |b| b.minimum);
+                Arc::new(Aligned::new(Arc::new(file), block).context("Can't use O_DIRECT")?)
+            }
+            BackendKind::File => {
+                let path = self.path.as_deref().context("No path")?;
+                Arc::new(FileBackend::open(path, self.read_only)?)
+            }
+            BackendKind::Concat => {
+                let parts = self
+                    .paths
+                    .iter()
+                    .map(|path| {
+                        FileBackend::open(path, self.read_only)
+                            .map(|part| Arc::new(part) as Arc<dyn Backend>)
+                    })
+                    .collect::<Result<_>>()?;
+                Arc::new(Concat::new(parts)?)
+            }
+        };
+        let backend = match &self.encryption {
+            Some(encryption) => encryption
+                .open(backend)
+                .context("Can't set up encryption")?,
+            None => backend,
+        };
+        match &self.luks {
+            Some(luks) => luks.open(backend).context("Can't open the LUKS volume"),
+            None => Ok(backend),
+        }
+    }
+
+    /// The exports of `backend`, a slice or partitions of it when set up so.
+    fn slice(&self, backend: Arc<dyn Backend>) -> Result<Vec<Export>> {
+        if self.offset.is_some() || self.length.is_some() {
+            let slice = Slice::new(backend, self.offset.unwrap_or(0), self.length)?;
+            return Ok(vec![self.export(
+                self.name.clone(),
+                self.description.clone(),
+                Arc::new(slice),
+            )?]);
+        }
+
+        if self.partition.is_none() && !self.partitions {
+            return Ok(vec![self.export(
+                self.name.clone(),
+                self.description.clone(),
+                backend,
+            )?]);
+        }
+
+        let partitions =
+            partition::detect(backend.as_ref()).context("Failed to read the partition table")?;
+        if let Some(number) = self.partition {
+            let partition = match partitions.iter().find(|p| p.number == number) {
+                Some(partition) => partition,
+                None => bail!("No partition {}", number),
+            };
+            let slice = Slice::new(backend, partition.offset, Some(partition.len))?;
+            return Ok(vec![self.export(
+                self.name.clone(),
+                self.description.clone(),
+                Arc::new(slice),
+            )?]);
+        }
+
+        partitions
+            .into_iter()
+            .map(|partition| {
+                let slice = Slice::new(Arc::clone(&backend), partition.offset, Some(partition.len))
+                    .with_context(|| format!("Partition {}", partition.number))?;
+                let description = match partition.name {
+                    Some(name) => name,
+                    None => self.description.clone(),
+                };
+                self.export(
+                    format!("{}-part{}", self.name, partition.number),
+                    description,
+                    Arc::new(slice),
+                )
+            })
+            .collect()
+    }
+
+    /// An export of `backend` with the configured settings.
+    fn export(
+        &self,
+        name: String,
+        description: String,
+        backend: Arc<dyn Backend>,
+    ) -> Result<Export> {
+        let mut export = Export::new(name, description, backend)?;
+        export.read_only = self.read_only;
+        export.trim = self.trim;
+        export.flush = self.flush;
+        export.fua = self.fua;
+        export.cache = self.cache;
+        export.write_zeroes = self.write_zeroes;
+        export.multiconn = self.multi_conn;
+        export.rotational = self.rotational;
+        export.can_resize = self.resize;
+        export.can_shrink = self.shrink;
+        if let Some(block_size) = self.block_size {
+            export.block_size = block_size;
+        }
+        export.acl = Acl::new(self.access.clone());
+        export.max_connections = self.max_connections;
+        export.writers = self.writers;
+        export.throttle = self.limits.as_ref().and_then(Throttle::new).map(Arc::new);
+        export.connection_limits = self.connection_limits.unwrap_or_default();
+        export.bitmap_dir = self.bitmap_dir.clone().or_else(|| {
+            self.path
+                .iter()
+                .chain(&self.paths)
+                .next()
+                .and_then(|path| path.parent())
+                .map(Path::to_path_buf)
+        });
+        export.bitmap_granularity = self.bitmap_granularity.unwrap_or(DEFAULT_GRANULARITY);
+        for name in &self.bitmaps {
+            export
+                .add_bitmap(name)
+                .with_context(|| format!("Bitmap {:?}", name))?;
+        }
+
+        Ok(export)
+    }
+}
+
+/// Checks the constraints the NBD protocol puts on block sizes.
+fn validate_block_size(block_size: &BlockSize) -> Result<()> {
+    let BlockSize {
+        minimum,
+        preferred,
+        maximum,
+    } = *block_size;
+
+    if !minimum.is_power_of_two() || minimum > 64 * 1024 {
+        bail!(
+            "Minimum block size {} must be a power of two no larger than 64KiB",
+            minimum
+        );
+    }
+
+    if !preferred.is_power_of_two() || preferred < minimum {
+        bail!(
+            "Preferred block size {} must be a power of two no smaller than the minimum",
+            preferred
+        );
+    }
+
+    if maximum < preferred || maximum % minimum != 0 || maximum as u64 > MAX_BLOCK_SIZE {
+        bail!(
+            "Maximum block size {} must be a multiple of the minimum, no smaller than the preferred size and at most {}",
+            maximum,
+            MAX_BLOCK_SIZE
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// A directory holding `disk.img`.
+    fn disk() -> TempDir {
+        let dir = TempDir::new();
+        fs::write(dir.0.join("disk.img"), vec![0; 64 * 1024]).unwrap();
+
+        dir
+    }
+
+    /// Parses `config` with `{dir}` standing for `dir`.
+    fn parse(dir: &TempDir, config: &str) -> Result<Config> {
+        Config::parse(&config.replace("{dir}", dir.0.to_str().unwrap()))
+    }
+
+    const DISK: &str = "[[exports]]\nname = \"disk\"\npath = \"{dir}/disk.img\"\n";
+
+    #[test]
+    fn test_parse() {
+        let dir = disk();
+        let config = parse(
+            &dir,
+            &format!(
+                "[server]\nidle_timeout = 60\n\n[[listeners]]\ntype = \"unix\"\npath = \"/run/nbd.sock\"\n\n{}trim = true\nblock_size = {{ minimum = 4096 }}\n\n[[exports.access]]\naction = \"allow\"\naddress = \"10.0.0.0/8\"\n",
+                DISK
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(config.server.idle_timeout, Some(60));
+        assert_eq!(config.server.shutdown_grace, DEFAULT_SHUTDOWN_GRACE_SECS);
+        assert_eq!(config.listeners().len(), 1);
+        let export = &config.exports[0];
+        assert!(export.trim && export.multi_conn && !export.read_only);
+        assert_eq!(export.block_size.unwrap().minimum, 4096);
+        assert_eq!(export.block_size.unwrap().preferred, 4096);
+        assert_eq!(export.access.len(), 1);
+
+        // Without listeners the default port is used
+        let config = parse(&dir, DISK).unwrap();
+        assert_eq!(
+            config.listeners(),
+            [Listener::Tcp {
+                address: SocketAddr::from(([0, 0, 0, 0], NBD_DEFAULT_PORT as u16)),
+                oldstyle: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let dir = disk();
+        let export = |extra: &str| parse(&dir, &format!("{}{}\n", DISK, extra));
+        assert!(export("").is_ok());
+
+        for invalid in [
+            "unknown = true",
+            "backend = \"concat\"",
+            "paths = [\"{dir}/disk.img\"]",
+            "path = \"{dir}/missing.img\"",
+            "read_only = true\ntrim = true",
+            "read_only = true\nwrite_zeroes = true",
+            "block_size = { minimum = 1000 }",
+            "block_size = { minimum = 4096, preferred = 512 }",
+            "block_size = { maximum = 4294967295 }",
+            "shrink = true",
+            "resize = true\nread_only = true",
+            "resize = true\noffset = 512",
+            "partition = 1\npartitions = true",
+            "partition = 1\noffset = 512",
+            "partition = 0",
+            "max_connections = 0",
+            "bitmaps = [\"a/b\"]",
+            "bitmap_granularity = 1000",
+            "[[exports.access]]\naction = \"allow\"\naddress = \"10.0.0.0/33\"",
+            "[[exports.access]]\naction = \"allow\"\naddress = \"::1\"\nuid = 0",
+            "[exports.luks]",
+            "[exports.luks]\npassphrase = \"a\"\nkeyfile = \"{dir}/disk.img\"",
+        ] {
+            // `path` may only be given once, so replace it rather than add another
+            let config = match invalid.strip_prefix("path = ") {
+                Some(path) => parse(&dir, &DISK.replace("\"{dir}/disk.img\"", path)),
+                None => export(invalid),
+            };
+            assert!(config.is_err(), "{:?} was accepted", invalid);
+        }
+
+        assert!(parse(&dir, "").is_err());
+        assert!(parse(&dir, &format!("{}{}", DISK, DISK)).is_err());
+        assert!(parse(&dir, &format!("[server]\nidle_timeout = 0\n{}", DISK)).is_err());
+        let listener = "[[listeners]]\ntype = \"tcp\"\naddress = \"127.0.0.1:10809\"\n";
+        assert!(parse(&dir, &format!("{}{}{}", listener, listener, DISK)).is_err());
+    }
+
+    const LIMITED: &str = r#"
+[[exports]]
+name = "disk"
+path = "{dir}/disk.img"
+
+[exports.limits]
+iops = 100
+
+[[exports]]
+name = "other"
+path = "{dir}/disk.img"
+"#;
+
+    #[test]
+    fn test_reload_keeps_sizes() {
+        let dir = disk();
+        let config = "[[exports]]\nname = \"disk\"\npath = \"{dir}/disk.img\"\nresize = true\n";
+        let current = Arc::new(parse(&dir, config).unwrap().exports().unwrap().remove(0));
+        current.resize(128 * 1024).unwrap();
+
+        let reloaded = parse(&dir, config)
+            .unwrap()
+            .exports_reusing(&[Arc::clone(&current)])
+            .unwrap()
+            .remove(0);
+        assert_eq!(reloaded.size(), 128 * 1024);
+
+        // Clients of the old export see resizes made through the new one
+        reloaded.resize(256 * 1024).unwrap();
+        assert_eq!(current.size(), 256 * 1024);
+    }
+
+    #[test]
+    fn test_reload_reuses_unchanged_exports() {
+        let dir = disk();
+        let current: Vec<_> = parse(&dir, LIMITED)
+            .unwrap()
+            .exports()
+            .unwrap()
+            .into_iter()
+            .map(Arc::new)
+            .collect();
+
+        let changed = LIMITED.replace("name = \"other\"", "name = \"other\"\nread_only = true");
+        let reloaded = parse(&dir, &changed)
+            .unwrap()
+            .exports_reusing(&current)
+            .unwrap();
+
+        // Same backend and token bucket for the unchanged section
+        assert!(Arc::ptr_eq(&current[0].backend, &reloaded[0].backend));
+        let (old, new) = (&current[0].throttle, &reloaded[0].throttle);
+        assert!(Arc::ptr_eq(old.as_ref().unwrap(), new.as_ref().unwrap()));
+
+        // A fresh one for the other
+        assert!(!Arc::ptr_eq(&current[1].backend, &reloaded[1].backend));
+        assert!(reloaded[1].read_only());
+    }
+}
@@ -0,0 +1,516 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Shutdown, TcpStream},
+    os::unix::net::UnixStream,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::metrics::ConnectionStats;
+
+/// A second handle to a client's socket, used to close it from another
+/// thread while the connection thread is blocked reading from it.
+#[derive(Debug)]
+pub enum Closer {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Closer {
+    pub fn close(&self) -> io::Result<()> {
+        match self {
+            Closer::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Closer::Unix(stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+
+    /// Makes reads on the socket fail after `timeout`, `None` blocks forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Closer::Tcp(stream) => stream.set_read_timeout(timeout),
+            Closer::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// Makes writes on the socket fail after `timeout`, `None` blocks forever.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Closer::Tcp(stream) => stream.set_write_timeout(timeout),
+            Closer::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+/// Whether an I/O error is a read timing out.
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[derive(Debug)]
+pub struct Connection {
+    id: u64,
+    addr: String,
+    closer: Option<Closer>,
+    /// When the handshake has to be done by
+    deadline: Mutex<Option<Instant>>,
+    in_flight: AtomicBool,
+    export: Mutex<Option<String>>,
+    stats: ConnectionStats,
+}
+
+impl Connection {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// The export the client selected, once the handshake is done.
+    pub fn export(&self) -> Option<String> {
+        self.export.lock().unwrap().clone()
+    }
+
+    pub fn set_export(&self, name: &str) {
+        *self.export.lock().unwrap() = Some(name.to_owned());
+    }
+
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// Whether a request is currently being processed.
+    pub fn in_flight(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Marks a request as in flight until the returned guard is dropped.
+    pub fn begin_request(&self) -> InFlight<'_> {
+        self.in_flight.store(true, Ordering::SeqCst);
+        InFlight(self)
+    }
+
+    /// Limits how long a read from the client may block, clients without a
+    /// socket (stdio) aren't limited.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.closer {
+            Some(closer) => closer.set_read_timeout(timeout),
+            None => Ok(()),
+        }
+    }
+
+    /// Limits how long a write to the client may block.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.closer {
+            Some(closer) => closer.set_write_timeout(timeout),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes reads from and writes to the client fail once `deadline` has
+    /// passed, `None` lifts it without touching the socket's timeouts.
+    pub fn set_deadline(&self, deadline: Option<Instant>) -> io::Result<()> {
+        *self.deadline.lock().unwrap() = deadline;
+        self.arm()
+    }
+
+    /// Sets the socket's timeouts to the time left until the deadline, or
+    /// fails if there is none left. Socket timeouts restart with every read,
+    /// so this comes before each of them or a client sending a byte at a
+    /// time could take as long as it likes.
+    pub fn arm(&self) -> io::Result<()> {
+        let deadline = match *self.deadline.lock().unwrap() {
+            Some(deadline) => deadline,
+            None => return Ok(()),
+        };
+
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.set_read_timeout(Some(left))?;
+        self.set_write_timeout(Some(left))
+    }
+
+    /// Whether the connection has a socket [`close`](Self::close) can shut
+    /// down, clients on a stdio pipe don't.
+    pub fn can_close(&self) -> bool {
+        self.closer.is_some()
+    }
+
+    /// Closes the socket, which makes the connection thread see EOF.
+    pub fn close(&self) {
+        if let Some(closer) = &self.closer {
+            if let Err(e) = closer.close() {
+                warn!(client = %self.addr, "Failed to close connection: {}", e);
+            }
+        }
+    }
+}
+
+pub struct InFlight<'a>(&'a Connection);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.store(false, Ordering::SeqCst);
+    }
+}
+
+/// The set of live client connections of a server.
+#[derive(Debug, Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    live: Mutex<HashMap<u64, Arc<Connection>>>,
+    changed: Condvar,
+}
+
+impl Connections {
+    pub fn register(&self, addr: &str, closer: Option<Closer>) -> Registration<'_> {
+        let conn = Arc::new(Connection {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            addr: addr.to_owned(),
+            closer,
+            deadline: Mutex::new(None),
+            in_flight: AtomicBool::new(false),
+            export: Mutex::new(None),
+            stats: ConnectionStats::default(),
+        });
+
+        self.live.lock().unwrap().insert(conn.id, Arc::clone(&conn));
+
+        Registration {
+            connections: self,
+            conn,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.live.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn list(&self) -> Vec<Arc<Connection>> {
+        let mut list: Vec<_> = self.live.lock().unwrap().values().cloned().collect();
+        list.sort_by_key(|c| c.id);
+        list
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Connection>> {
+        self.live.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Waits up to `timeout` for every connection to go away, returns
+    /// whether they did.
+    pub fn wait_empty(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut live = self.live.lock().unwrap();
+
+        while !live.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+
+            live = self.changed.wait_timeout(live, deadline - now).unwrap().0;
+        }
+
+        true
+    }
+}
+
+/// Keeps a connection registered until dropped.
+pub struct Registration<'a> {
+    connections: &'a Connections,
+    conn: Arc<Connection>,
+}
+
+impl Registration<'_> {
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.conn
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        info!(client = %self.conn.addr, "Client disconnected");
+        self.connections.live.lock().unwrap().remove(&self.conn.id);
+        self.connections.changed.notify_all();
+    }
+}
+
+/// Who may write to an export when several clients use it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Writers {
+    /// Every client may write, they have to coordinate among themselves
+    #[default]
+    Shared,
+    /// The first client to connect may write, later ones are refused while
+    /// it is connected
+    Single,
+    /// The first client to connect may write, later ones are read-only
+    Downgrade,
+}
+
+/// Why a session was refused by [`Sessions::admit`].
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Refusal {
+    #[error("Export has reached its limit of {0} connections")]
+    TooManyConnections(usize),
+    #[error("Export already has a writer")]
+    WriterConnected,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    sessions: usize,
+    writers: usize,
+}
+
+impl Usage {
+    /// Whether another session would be admitted as a writer.
+    fn check(
+        &self,
+        max_connections: Option<usize>,
+        writers: Writers,
+        writable: bool,
+    ) -> Result<bool, Refusal> {
+        if let Some(max) = max_connections {
+            if self.sessions >= max {
+                return Err(Refusal::TooManyConnections(max));
+            }
+        }
+
+        match writers {
+            Writers::Shared => Ok(writable),
+            _ if !writable => Ok(false),
+            _ if self.writers == 0 => Ok(true),
+            Writers::Single => Err(Refusal::WriterConnected),
+            Writers::Downgrade => Ok(false),
+        }
+    }
+}
+
+/// Sessions per export, by name so limits still hold for clients that
+/// connected before a reload replaced the export.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    usage: Arc<Mutex<HashMap<String, Usage>>>,
+}
+
+impl Sessions {
+    /// Admits a session to the export called `name`, `writable` being
+    /// whether the export accepts writes at all.
+    pub fn admit(
+        &self,
+        name: &str,
+        max_connections: Option<usize>,
+        writers: Writers,
+        writable: bool,
+    ) -> Result<Lease, Refusal> {
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(name.to_owned()).or_default();
+        let writer = entry.check(max_connections, writers, writable)?;
+
+        entry.sessions += 1;
+        if writer {
+            entry.writers += 1;
+        }
+
+        Ok(Lease {
+            usage: Arc::clone(&self.usage),
+            name: name.to_owned(),
+            writer,
+        })
+    }
+
+    /// Whether a session would be admitted as a writer, without taking a
+    /// place on the export.
+    pub fn peek(
+        &self,
+        name: &str,
+        max_connections: Option<usize>,
+        writers: Writers,
+        writable: bool,
+    ) -> Result<bool, Refusal> {
+        let usage = self.usage.lock().unwrap();
+        match usage.get(name) {
+            Some(entry) => entry.check(max_connections, writers, writable),
+            None => Usage::default().check(max_connections, writers, writable),
+        }
+    }
+}
+
+/// A session's place on an export, given back when dropped.
+#[derive(Debug)]
+pub struct Lease {
+    usage: Arc<Mutex<HashMap<String, Usage>>>,
+    name: String,
+    writer: bool,
+}
+
+impl Lease {
+    /// Whether the session may only read.
+    pub fn read_only(&self) -> bool {
+        !self.writer
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(entry) = usage.get_mut(&self.name) {
+            entry.sessions -= 1;
+            if self.writer {
+                entry.writers -= 1;
+            }
+            if entry.sessions == 0 {
+                usage.remove(&self.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_deadline() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let connections = Connections::default();
+        let registration =
+            connections.register("unix", Some(Closer::Unix(theirs.try_clone().unwrap())));
+        let conn = registration.connection();
+
+        conn.set_deadline(Some(Instant::now() + Duration::from_millis(200)))
+            .unwrap();
+        for timeout in [theirs.read_timeout(), theirs.write_timeout()] {
+            assert!(timeout.unwrap().unwrap() <= Duration::from_millis(200));
+        }
+
+        // Every arming counts down to the same deadline
+        thread::sleep(Duration::from_millis(100));
+        conn.arm().unwrap();
+        assert!(theirs.read_timeout().unwrap().unwrap() <= Duration::from_millis(100));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(conn.arm().unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        // Lifting it leaves the timeouts to be set, and stops failing
+        conn.set_deadline(None).unwrap();
+        conn.set_read_timeout(None).unwrap();
+        assert!(conn.arm().is_ok());
+        assert_eq!(theirs.read_timeout().unwrap(), None);
+        drop(ours);
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let sessions = Sessions::default();
+        let first = sessions
+            .admit("disk", Some(2), Writers::Shared, true)
+            .unwrap();
+        let _second = sessions
+            .admit("disk", Some(2), Writers::Shared, true)
+            .unwrap();
+        assert_eq!(
+            sessions
+                .admit("disk", Some(2), Writers::Shared, true)
+                .unwrap_err(),
+            Refusal::TooManyConnections(2)
+        );
+        assert!(sessions
+            .peek("disk", Some(2), Writers::Shared, true)
+            .is_err());
+        // Limits are per export
+        assert!(sessions
+            .admit("other", Some(2), Writers::Shared, true)
+            .is_ok());
+
+        drop(first);
+        assert!(sessions
+            .admit("disk", Some(2), Writers::Shared, true)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_writers() {
+        let sessions = Sessions::default();
+        let writer = sessions.admit("disk", None, Writers::Single, true).unwrap();
+        assert!(!writer.read_only());
+        assert_eq!(
+            sessions
+                .admit("disk", None, Writers::Single, true)
+                .unwrap_err(),
+            Refusal::WriterConnected
+        );
+        assert_eq!(
+            sessions.peek("disk", None, Writers::Single, true),
+            Err(Refusal::WriterConnected)
+        );
+        // Nobody writes to a read-only export, so nobody is refused
+        assert!(sessions
+            .admit("disk", None, Writers::Single, false)
+            .unwrap()
+            .read_only());
+        drop(writer);
+        assert_eq!(sessions.peek("disk", None, Writers::Single, true), Ok(true));
+
+        let sessions = Sessions::default();
+        let writer = sessions
+            .admit("disk", None, Writers::Downgrade, true)
+            .unwrap();
+        let reader = sessions
+            .admit("disk", None, Writers::Downgrade, true)
+            .unwrap();
+        assert!(!writer.read_only() && reader.read_only());
+        drop(writer);
+        assert_eq!(
+            sessions.peek("disk", None, Writers::Downgrade, true),
+            Ok(true)
+        );
+        drop(reader);
+
+        let sessions = Sessions::default();
+        let _first = sessions.admit("disk", None, Writers::Shared, true).unwrap();
+        assert!(!sessions
+            .admit("disk", None, Writers::Shared, true)
+            .unwrap()
+            .read_only());
+    }
+
+    #[test]
+    fn test_peek_takes_no_place() {
+        let sessions = Sessions::default();
+        for _ in 0..3 {
+            assert_eq!(
+                sessions.peek("disk", Some(1), Writers::Single, true),
+                Ok(true)
+            );
+        }
+
+        let _writer = sessions
+            .admit("disk", Some(1), Writers::Single, true)
+            .unwrap();
+        assert!(sessions
+            .peek("disk", Some(1), Writers::Single, true)
+            .is_err());
+    }
+}
@@ -7,11 +7,14 @@ pub const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
 
 pub const NBD_INIT_MAGIC: u64 = 0x4e42444d41474943;
 pub const NBD_OPTS_MAGIC: u64 = 0x49484156454F5054;
+pub const NBD_CLISERV_MAGIC: u64 = 0x00420281861253;
 pub const NBD_REP_MAGIC: u64 = 0x3e889045565a9;
 pub const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
 pub const NBD_REQUEST_MAGIC: u32 = 0x25609513;
+pub const NBD_EXTENDED_REQUEST_MAGIC: u32 = 0x21e41c71;
 
 pub const NBD_REQUEST_SIZE: u32 = 28;
+pub const NBD_EXTENDED_REQUEST_SIZE: u32 = 32;
 pub const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
 pub const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
 pub const NBD_CMD_FLAG_DF: u16 = 1 << 2;
@@ -25,6 +28,16 @@ pub const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
 pub const MIN_BLOCK_SIZE: u64 = 1;
 pub const PREFERRED_BLOCK_SIZE: u64 = 4096;
 pub const MAX_BLOCK_SIZE: u64 = 32 * 1024 * 1024;
+// Far more than any option needs, export names are at most 4KiB
+pub const MAX_OPTION_LENGTH: u32 = 64 * 1024;
+pub const MAX_OPTIONS: usize = 256;
+pub const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 10;
+pub const DEFAULT_HANDSHAKE_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_SEND_TIMEOUT_SECS: u64 = 60;
+pub const DEFAULT_KEEPALIVE_INTERVAL_SECS: u32 = 10;
+pub const DEFAULT_KEEPALIVE_COUNT: u32 = 6;
+pub const DEFAULT_ADMIN_SOCKET: &str = "/tmp/nbd-admin.sock";
+pub const DEFAULT_SECTOR_SIZE: u32 = 512;
 
 // Flags https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md#transmission-flags
 pub const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
@@ -42,6 +55,7 @@ pub const NBD_FLAG_SEND_FAST_ZERO: u16 = 1 << 11;
 
 // Structured reply
 pub const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e33ef;
+pub const NBD_EXTENDED_REPLY_MAGIC: u32 = 0x6e8a278c;
 pub const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
 
 // Chunk types
@@ -50,8 +64,27 @@ pub const NBD_REPLY_TYPE_NONE: u16 = 0;
 pub const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
 pub const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
 pub const NBD_REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+pub const NBD_REPLY_TYPE_BLOCK_STATUS_EXT: u16 = 6;
+pub const NBD_REPLY_TYPE_ERROR: u16 = (1 << 15) + 1;
+
+// base:allocation states
+pub const NBD_STATE_HOLE: u32 = 1 << 0;
+pub const NBD_STATE_ZERO: u32 = 1 << 1;
+// qemu:dirty-bitmap state
+pub const NBD_STATE_DIRTY: u32 = 1 << 0;
+
+// Error values https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md#error-values
+pub const NBD_EPERM: u32 = 1;
+pub const NBD_EIO: u32 = 5;
+pub const NBD_ENOMEM: u32 = 12;
+pub const NBD_EINVAL: u32 = 22;
+pub const NBD_ENOSPC: u32 = 28;
+pub const NBD_EOVERFLOW: u32 = 75;
+pub const NBD_ENOTSUP: u32 = 95;
+pub const NBD_ESHUTDOWN: u32 = 108;
 
 #[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum NbdCmd {
     Read,
     Write,
@@ -61,6 +94,29 @@ pub enum NbdCmd {
     Cache,
     WriteZeroes,
     BlockStatus,
+    /// The experimental resize extension, the new size is in the offset
+    Resize,
+}
+
+impl TryFrom<u16> for NbdCmd {
+    type Error = u16;
+
+    fn try_from(command: u16) -> Result<Self, Self::Error> {
+        let command = match command {
+            0 => NbdCmd::Read,
+            1 => NbdCmd::Write,
+            2 => NbdCmd::Disc,
+            3 => NbdCmd::Flush,
+            4 => NbdCmd::Trim,
+            5 => NbdCmd::Cache,
+            6 => NbdCmd::WriteZeroes,
+            7 => NbdCmd::BlockStatus,
+            8 => NbdCmd::Resize,
+            unknown => return Err(unknown),
+        };
+
+        Ok(command)
+    }
 }
 
 #[repr(u32)]
@@ -76,6 +132,30 @@ pub enum NbdOpt {
     StructuredReply = 8,
     ListMetaContext = 9,
     SetMetaContext = 10,
+    ExtendedHeaders = 11,
+}
+
+impl TryFrom<u32> for NbdOpt {
+    type Error = u32;
+
+    fn try_from(option: u32) -> Result<Self, Self::Error> {
+        let option = match option {
+            0 => NbdOpt::Export,
+            1 => NbdOpt::ExportName,
+            2 => NbdOpt::Abort,
+            3 => NbdOpt::List,
+            5 => NbdOpt::StartTls,
+            6 => NbdOpt::Info,
+            7 => NbdOpt::Go,
+            8 => NbdOpt::StructuredReply,
+            9 => NbdOpt::ListMetaContext,
+            10 => NbdOpt::SetMetaContext,
+            11 => NbdOpt::ExtendedHeaders,
+            unknown => return Err(unknown),
+        };
+
+        Ok(option)
+    }
 }
 
 #[repr(u32)]
@@ -88,6 +168,11 @@ pub enum NbdReply {
 
     // Errors
     NbdRepErrUnsup = 1 | NBD_REP_FLAG_ERROR,
+    NbdRepErrPolicy = 2 | NBD_REP_FLAG_ERROR,
+    NbdRepErrInvalid = 3 | NBD_REP_FLAG_ERROR,
+    NbdRepErrTlsReqd = 5 | NBD_REP_FLAG_ERROR,
+    NbdRepErrUnknown = 6 | NBD_REP_FLAG_ERROR,
+    NbdRepErrShutdown = 7 | NBD_REP_FLAG_ERROR,
 }
 
 #[repr(u16)]
@@ -1,30 +1,63 @@
-use anyhow::Result;
+use acl::Acl;
+use anyhow::{bail, Context, Result};
+use backend::{Backend, FileBackend};
+use bitmap::DirtyBitmap;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use client::Client;
+use config::Source;
+use connections::{is_timeout, Connection, Connections, Lease, Refusal, Sessions, Writers};
 use consts::{
-    NbdReply, NBD_FLAG_C_FIXED_NEWSTYLE, NBD_FLAG_C_NO_ZEROES, NBD_FLAG_FIXED_NEWSTYLE,
-    NBD_FLAG_HAS_FLAGS, NBD_FLAG_NO_ZEROES,
+    NbdReply, NBD_CLISERV_MAGIC, NBD_FLAG_C_FIXED_NEWSTYLE, NBD_FLAG_C_NO_ZEROES,
+    NBD_FLAG_FIXED_NEWSTYLE, NBD_FLAG_HAS_FLAGS, NBD_FLAG_NO_ZEROES,
 };
+use meta::Context as MetaContext;
+use metrics::{ExportMetrics, Metrics};
+use qos::{Limits, Throttle};
+use serde::Deserialize;
+use tcp::Keepalive;
 
 use std::fmt::Debug;
-use std::fs::{self, OpenOptions};
-use std::intrinsics::transmute;
-use std::io::{Read, Write};
-use std::os::unix::prelude::MetadataExt;
-use std::path::Path;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
+use rustls::StreamOwned;
 use thiserror::Error;
+use tracing::{debug, debug_span, field, info, info_span, warn, Span};
 
 use crate::consts::{
-    NbdCmd, NbdInfoOpt, NbdOpt, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE, NBD_INIT_MAGIC, NBD_OPTS_MAGIC,
-    NBD_REQUEST_MAGIC, NBD_REQUEST_SIZE, PREFERRED_BLOCK_SIZE,
+    NbdCmd, NbdInfoOpt, NbdOpt, DEFAULT_HANDSHAKE_TIMEOUT_SECS, DEFAULT_SEND_TIMEOUT_SECS,
+    DEFAULT_SHUTDOWN_GRACE_SECS, MAX_BLOCK_SIZE, MAX_OPTIONS, MAX_OPTION_LENGTH, MIN_BLOCK_SIZE,
+    NBD_CMD_FLAG_FUA, NBD_CMD_FLAG_NO_HOLE, NBD_CMD_FLAG_REQ_ONE, NBD_EINVAL, NBD_EIO, NBD_ENOMEM,
+    NBD_ENOSPC, NBD_ENOTSUP, NBD_EOVERFLOW, NBD_EPERM, NBD_ESHUTDOWN, NBD_EXTENDED_REQUEST_MAGIC,
+    NBD_EXTENDED_REQUEST_SIZE, NBD_INIT_MAGIC, NBD_OPTS_MAGIC, NBD_REQUEST_MAGIC, NBD_REQUEST_SIZE,
+    PREFERRED_BLOCK_SIZE,
 };
 
+pub mod acl;
+pub mod admin;
+pub mod backend;
+pub mod bitmap;
 pub mod client;
+pub mod config;
+pub mod connections;
 pub mod consts;
-mod protocol;
+pub mod luks;
+pub mod meta;
+pub mod metrics;
+pub mod partition;
+pub mod protocol;
+pub mod qos;
+pub mod stdio;
+pub mod systemd;
 pub mod tcp;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod tls;
 pub mod unix;
+pub mod xts;
 
 #[derive(Debug, Error)]
 pub enum NbdError {
@@ -37,137 +70,754 @@ pub enum NbdError {
 pub enum InteractionResult {
     Abort,
     Continue,
+    StartTls,
+}
+
+/// The outcome of a single transmission request.
+enum Outcome {
+    Done,
+    Data(Vec<u8>),
+    /// Extents for each metadata context id
+    Extents(Vec<(u32, Vec<backend::Extent>)>),
+    Error(u32),
+}
+
+/// Block size constraints advertised with `NBD_INFO_BLOCK_SIZE`, and
+/// enforced for clients that asked for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockSize {
+    pub minimum: u32,
+    pub preferred: u32,
+    pub maximum: u32,
+}
+
+impl Default for BlockSize {
+    fn default() -> Self {
+        BlockSize {
+            minimum: MIN_BLOCK_SIZE as u32,
+            preferred: PREFERRED_BLOCK_SIZE as u32,
+            maximum: MAX_BLOCK_SIZE as u32,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Export {
-    path: String,
     name: String,
     description: String,
-    size: u64,
+    backend: Arc<dyn Backend>,
+    /// Shared with every copy of the export, and with its reloads while its
+    /// config is unchanged, so a resize is seen by all of its connections
+    size: Arc<AtomicU64>,
+    /// Held for writing by a resize and for reading by requests changing
+    /// data, so a shrink waits for writes that were checked against the
+    /// old size
+    resizing: Arc<RwLock<()>>,
     read_only: bool,
     can_resize: bool,
+    can_shrink: bool,
     fast_zero: bool,
     trim: bool,
     flush: bool,
+    fua: bool,
+    cache: bool,
+    write_zeroes: bool,
     rotational: bool,
     df: bool,
     multiconn: bool,
+    block_size: BlockSize,
+    acl: Acl,
+    max_connections: Option<usize>,
+    writers: Writers,
+    /// Shared by every connection to the export
+    throttle: Option<Arc<Throttle>>,
+    /// Applied to each connection on its own
+    connection_limits: Limits,
+    /// Shared with every copy of the export, like the size
+    bitmaps: Arc<RwLock<Vec<Arc<DirtyBitmap>>>>,
+    /// Where bitmaps are saved, none can be created without it
+    bitmap_dir: Option<PathBuf>,
+    bitmap_granularity: u64,
+    /// The config section the export was built from, if any
+    source: Option<Arc<Source>>,
 }
 
 impl Export {
     pub fn init_export(path: String, name: String, description: String) -> Result<Export> {
-        let md = fs::metadata(Path::new(&path))?;
+        let backend = FileBackend::open(Path::new(&path), false)?;
+
+        let mut export = Export::new(name, description, Arc::new(backend))?;
+        // Bitmaps are kept next to the file
+        export.bitmap_dir = Path::new(&path).parent().map(Path::to_path_buf);
+        Ok(export)
+    }
 
+    /// An export with the default settings.
+    pub fn new(name: String, description: String, backend: Arc<dyn Backend>) -> Result<Export> {
         let export = Export {
-            path,
             name,
             description,
-            size: md.size(),
-            read_only: false, // TODO make configurable
+            size: Arc::new(AtomicU64::new(backend.size()?)),
+            resizing: Arc::default(),
+            read_only: false,
             can_resize: false,
+            can_shrink: false,
             fast_zero: false,
             trim: false,
             flush: false,
+            fua: false,
+            cache: false,
+            write_zeroes: false,
             rotational: false,
             df: true,
             multiconn: true,
+            block_size: backend.block_size().unwrap_or_default(),
+            acl: Acl::default(),
+            backend,
+            max_connections: None,
+            writers: Writers::default(),
+            throttle: None,
+            connection_limits: Limits::default(),
+            bitmaps: Arc::default(),
+            bitmap_dir: None,
+            bitmap_granularity: bitmap::DEFAULT_GRANULARITY,
+            source: None,
         };
 
         Ok(export)
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Whether the export can shrink as well as grow.
+    pub fn can_shrink(&self) -> bool {
+        self.can_shrink
+    }
+
+    /// Grows or shrinks the export for every connection using it.
+    ///
+    /// Fails with `EINVAL` when shrinking isn't allowed or the size isn't a
+    /// multiple of the minimum block size.
+    pub fn resize(&self, size: u64) -> io::Result<()> {
+        let _resizing = self.resizing.write().unwrap();
+        let current = self.size();
+        if (size < current && !self.can_shrink)
+            || !size.is_multiple_of(self.block_size.minimum as u64)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        self.backend.resize(size)?;
+        self.size.store(size, Ordering::SeqCst);
+        for bitmap in self.bitmaps() {
+            bitmap.resize(size);
+        }
+        info!(export = %self.name, from = current, to = size, "Resized export");
+        Ok(())
+    }
+
+    /// Whether `len` bytes at `offset` are within the export.
+    fn contains(&self, offset: u64, len: u64) -> bool {
+        offset
+            .checked_add(len)
+            .is_some_and(|end| end <= self.size())
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// The export a client admitted with a read-only lease gets, a read-only
+    /// copy of it when it was downgraded.
+    fn admitted(self: Arc<Self>, read_only: bool) -> Arc<Export> {
+        if !read_only || self.read_only {
+            return self;
+        }
+
+        let mut downgraded = Export::clone(&self);
+        downgraded.read_only = true;
+        Arc::new(downgraded)
+    }
+
+    pub fn backend(&self) -> &Arc<dyn Backend> {
+        &self.backend
+    }
+
+    /// The dirty bitmaps tracking writes to the export.
+    pub fn bitmaps(&self) -> Vec<Arc<DirtyBitmap>> {
+        self.bitmaps.read().unwrap().clone()
+    }
+
+    pub fn bitmap(&self, name: &str) -> Option<Arc<DirtyBitmap>> {
+        self.bitmaps().into_iter().find(|b| b.name() == name)
+    }
+
+    /// Starts tracking writes in a new bitmap, or in the one saved under
+    /// that name.
+    pub fn add_bitmap(&self, name: &str) -> Result<Arc<DirtyBitmap>> {
+        bitmap::validate_name(name)?;
+        let dir = match &self.bitmap_dir {
+            Some(dir) => dir,
+            None => bail!("Export {:?} has no bitmap directory", self.name),
+        };
+
+        let mut bitmaps = self.bitmaps.write().unwrap();
+        if bitmaps.iter().any(|b| b.name() == name) {
+            bail!("Bitmap {:?} already exists", name);
+        }
+
+        let path = bitmap::path(dir, &self.name, name);
+        let bitmap = DirtyBitmap::open(name, &path, self.size(), self.bitmap_granularity)?;
+        bitmaps.push(Arc::clone(&bitmap));
+        Ok(bitmap)
+    }
+
+    /// Records a write in every bitmap, before it happens.
+    fn mark_dirty(&self, offset: u64, len: u64) -> io::Result<()> {
+        for bitmap in self.bitmaps.read().unwrap().iter() {
+            bitmap.mark(offset, len)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// How long clients get to disconnect on shutdown before their sockets
+    /// are closed
+    pub shutdown_grace: Duration,
+    /// Enables `NBD_OPT_STARTTLS`
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    /// Refuse to serve clients that don't upgrade to TLS
+    pub tls_required: bool,
+    /// How long clients get to select an export after connecting
+    pub handshake_timeout: Option<Duration>,
+    /// Disconnect clients that send no request for this long
+    pub idle_timeout: Option<Duration>,
+    /// Disconnect clients that don't take replies sent to them for this long
+    pub send_timeout: Option<Duration>,
+    /// Probe idle TCP connections for dead peers
+    pub keepalive: Option<Keepalive>,
+    /// Give up on TCP peers that leave data unacknowledged for this long
+    pub user_timeout: Option<Duration>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            shutdown_grace: Duration::from_secs(DEFAULT_SHUTDOWN_GRACE_SECS),
+            tls: None,
+            tls_required: false,
+            handshake_timeout: Some(Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS)),
+            idle_timeout: None,
+            send_timeout: Some(Duration::from_secs(DEFAULT_SEND_TIMEOUT_SECS)),
+            keepalive: None,
+            user_timeout: None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Server {
-    export: Export,
+    exports: RwLock<Vec<Arc<Export>>>,
+    options: ServerOptions,
+    shutting_down: AtomicBool,
+    connections: Connections,
+    sessions: Sessions,
+    metrics: Metrics,
 }
 
 impl Server {
     pub fn new(export: Export) -> Self {
-        Server { export }
+        Server::with_options(vec![export], ServerOptions::default())
+    }
+
+    pub fn with_options(exports: Vec<Export>, options: ServerOptions) -> Self {
+        Server {
+            exports: RwLock::new(exports.into_iter().map(Arc::new).collect()),
+            options,
+            shutting_down: AtomicBool::new(false),
+            connections: Connections::default(),
+            sessions: Sessions::default(),
+            metrics: Metrics::default(),
+        }
+    }
+
+    pub fn options(&self) -> &ServerOptions {
+        &self.options
+    }
+
+    pub fn connections(&self) -> &Connections {
+        &self.connections
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn exports(&self) -> Vec<Arc<Export>> {
+        self.exports.read().unwrap().clone()
+    }
+
+    /// Replaces the export registry.
+    ///
+    /// Only new connections see the change, clients already using an export
+    /// keep it until they disconnect, even if it was removed or changed.
+    pub fn set_exports(&self, exports: Vec<Export>) {
+        let exports: Vec<_> = exports.into_iter().map(Arc::new).collect();
+        let mut current = self.exports.write().unwrap();
+
+        for export in exports.iter() {
+            if !current.iter().any(|e| e.name == export.name) {
+                info!(export = %export.name, "Adding export");
+            }
+        }
+        for export in current.iter() {
+            if !exports.iter().any(|e| e.name == export.name) {
+                info!(
+                    export = %export.name,
+                    "Removing export, connected clients keep using it"
+                );
+            }
+        }
+
+        *current = exports;
+    }
+
+    /// Adds an export for new connections.
+    pub fn add_export(&self, export: Export) -> Result<()> {
+        let mut exports = self.exports.write().unwrap();
+        if exports.iter().any(|e| e.name == export.name) {
+            bail!("Export {:?} already exists", export.name);
+        }
+
+        info!(export = %export.name, "Adding export");
+        exports.push(Arc::new(export));
+        Ok(())
+    }
+
+    /// Stops offering an export, connected clients keep using it.
+    pub fn remove_export(&self, name: &str) -> Result<()> {
+        let mut exports = self.exports.write().unwrap();
+        let before = exports.len();
+        exports.retain(|e| e.name != name);
+        if exports.len() == before {
+            bail!("No such export {:?}", name);
+        }
+
+        info!(export = %name, "Removing export, connected clients keep using it");
+        Ok(())
+    }
+
+    /// Makes an export read-only or writable for new connections.
+    pub fn set_read_only(&self, name: &str, read_only: bool) -> Result<()> {
+        let mut exports = self.exports.write().unwrap();
+        let export = match exports.iter_mut().find(|e| e.name == name) {
+            Some(export) => export,
+            None => bail!("No such export {:?}", name),
+        };
+
+        if !read_only && export.backend.read_only() {
+            bail!("Export {:?} was opened read-only", name);
+        }
+
+        info!(export = %name, read_only, "Changing export access");
+        let mut changed = Export::clone(export);
+        changed.read_only = read_only;
+        *export = Arc::new(changed);
+        Ok(())
+    }
+
+    /// Resizes an export on behalf of the administrator, connected clients
+    /// see the new size right away.
+    pub fn resize_export(&self, name: &str, size: u64) -> Result<()> {
+        let export = match self.exports().into_iter().find(|e| e.name == name) {
+            Some(export) => export,
+            None => bail!("No such export {:?}", name),
+        };
+
+        if size < export.size() && !export.can_shrink {
+            bail!("Export {:?} can't shrink", name);
+        }
+        if !size.is_multiple_of(export.block_size.minimum as u64) {
+            bail!(
+                "Size must be a multiple of the minimum block size {}",
+                export.block_size.minimum
+            );
+        }
+
+        export
+            .resize(size)
+            .with_context(|| format!("Failed to resize {:?}", name))
+    }
+
+    /// Creates a dirty bitmap on an export. Like other changes made at
+    /// runtime it is forgotten on reload, unless the config lists it.
+    pub fn create_bitmap(&self, name: &str, bitmap: &str) -> Result<()> {
+        let export = match self.exports().into_iter().find(|e| e.name == name) {
+            Some(export) => export,
+            None => bail!("No such export {:?}", name),
+        };
+
+        export.add_bitmap(bitmap)?;
+        info!(export = %name, bitmap, "Created dirty bitmap");
+        Ok(())
+    }
+
+    /// Clears a dirty bitmap, usually once a backup was taken.
+    pub fn clear_bitmap(&self, name: &str, bitmap: &str) -> Result<()> {
+        let export = match self.exports().into_iter().find(|e| e.name == name) {
+            Some(export) => export,
+            None => bail!("No such export {:?}", name),
+        };
+
+        match export.bitmap(bitmap) {
+            Some(found) => found
+                .clear()
+                .with_context(|| format!("Failed to clear bitmap {:?}", bitmap)),
+            None => bail!("Export {:?} has no bitmap {:?}", name, bitmap),
+        }
+    }
+
+    /// Looks up an export by name, the empty name selects the first export
+    /// unless one is explicitly named "".
+    fn find_export(&self, name: &str) -> Option<Arc<Export>> {
+        let exports = self.exports.read().unwrap();
+
+        exports
+            .iter()
+            .find(|e| e.name == name)
+            .or_else(|| match name {
+                "" => exports.first(),
+                _ => None,
+            })
+            .cloned()
+    }
+
+    /// Admits a client to an export, enforcing its connection limit and
+    /// writer policy.
+    fn admit(&self, export: Arc<Export>) -> Result<(Arc<Export>, Lease), Refusal> {
+        let lease = self.sessions.admit(
+            &export.name,
+            export.max_connections,
+            export.writers,
+            !export.read_only,
+        )?;
+
+        if lease.read_only() && !export.read_only {
+            info!(export = %export.name, "Another client is writing, connecting read-only");
+        }
+        Ok((export.admitted(lease.read_only()), lease))
+    }
+
+    /// The export as [`Server::admit`] would give it, without taking a place
+    /// on it.
+    fn peek(&self, export: Arc<Export>) -> Result<Arc<Export>, Refusal> {
+        let writer = self.sessions.peek(
+            &export.name,
+            export.max_connections,
+            export.writers,
+            !export.read_only,
+        )?;
+
+        Ok(export.admitted(!writer))
+    }
+
+    /// Stops serving clients.
+    ///
+    /// Requests already being processed are completed, new ones fail with
+    /// `NBD_ESHUTDOWN`. Clients still connected once the grace period is
+    /// over have their sockets closed. Safe to call from several listeners.
+    pub fn shutdown(&self) {
+        if !self.shutting_down.swap(true, Ordering::SeqCst) {
+            info!(
+                grace = ?self.options.shutdown_grace,
+                clients = self.connections.len(),
+                "Shutting down, waiting for clients to disconnect"
+            );
+        }
+
+        if !self.connections.wait_empty(self.options.shutdown_grace) {
+            self.close_connections();
+        }
+
+        // Saved as not in use, so they are trusted on the next start
+        for export in self.exports() {
+            for bitmap in export.bitmaps() {
+                if let Err(e) = bitmap.close() {
+                    warn!(export = %export.name, bitmap = bitmap.name(), "Failed to save dirty bitmap: {}", e);
+                }
+            }
+        }
+    }
+
+    fn close_connections(&self) {
+        let remaining = self.connections.list();
+        warn!(
+            clients = remaining.len(),
+            "Closing connections still open after the grace period"
+        );
+        for conn in remaining {
+            if conn.in_flight() {
+                warn!(client = conn.addr(), "Interrupting a request in flight");
+            } else {
+                info!(client = conn.addr(), "Closing idle connection");
+            }
+            conn.close();
+        }
     }
 
     pub fn handle<T: Read + Write>(&self, c: &mut Client<T>) -> Result<()> {
         let addr = c.addr().to_owned();
-        println!("Handling client {}", addr);
+        let span = info_span!("connection", client = %addr, export = field::Empty);
+        let _enter = span.enter();
+        info!("Handling client");
+        let registration = self.connections.register(&addr, c.take_closer());
+        self.metrics.connection_accepted();
+        let conn = registration.connection();
+        c.set_connection(Arc::clone(conn));
+        let deadline = self.options.handshake_timeout.map(|t| Instant::now() + t);
+        // Every read and write of the handshake keeps to it from now on
+        conn.set_deadline(deadline)?;
 
-        match self.handshake(c, &self.export)? {
+        match self.negotiate(c)? {
             InteractionResult::Abort => {
-                println!("Aborting connection");
-                return Ok(());
+                debug!("Aborting connection");
+                Ok(())
             }
-            InteractionResult::Continue => {
-                println!("Continuing connection");
+            InteractionResult::Continue => self.transmit(c, conn),
+            InteractionResult::StartTls => self.handle_tls(c, conn),
+        }
+    }
+
+    /// Continues the handshake over TLS after `NBD_OPT_STARTTLS`, whatever
+    /// was negotiated in plain text is forgotten.
+    fn handle_tls<T: Read + Write>(&self, c: &mut Client<T>, conn: &Connection) -> Result<()> {
+        let config = match &self.options.tls {
+            Some(config) => Arc::clone(config),
+            None => bail!("STARTTLS accepted without a TLS configuration"),
+        };
+
+        let mut session = rustls::ServerConnection::new(config)?;
+        while session.is_handshaking() {
+            if let Err(e) = session.complete_io(&mut c.stream()) {
+                if is_timeout(&e) {
+                    self.metrics.handshake_failed();
+                    warn!("TLS handshake timed out, disconnecting");
+                    return Ok(());
+                }
+                return Err(e.into());
             }
         }
 
-        println!("Starting transmission");
-        match self.transmission(c, &self.export)? {
+        let mut peer = c.peer().clone();
+        if let Some(cert) = session.peer_certificates().and_then(|certs| certs.first()) {
+            peer.tls_identity = Some(acl::certificate_identity(cert)?);
+        }
+        debug!(%peer, "Upgraded connection to TLS");
+
+        let addr = c.addr().to_owned();
+        let no_zeroes = c.no_zeroes();
+        let mut tls = Client::new(StreamOwned::new(session, c.stream()), addr);
+        tls.set_tls(true);
+        tls.set_peer(peer);
+        // STARTTLS is only accepted with fixed newstyle
+        tls.set_fixed_newstyle(true);
+        tls.set_no_zeroes(no_zeroes);
+
+        match self.negotiate(&mut tls)? {
             InteractionResult::Abort => {
-                println!("Aborting connection");
-                return Ok(());
+                debug!("Aborting connection");
+                Ok(())
             }
-            InteractionResult::Continue => {
-                println!("Continuing connection");
+            InteractionResult::Continue => self.transmit(&mut tls, conn),
+            InteractionResult::StartTls => bail!("STARTTLS accepted twice"),
+        }
+    }
+
+    fn negotiate<T: Read + Write>(&self, c: &mut Client<T>) -> Result<InteractionResult> {
+        let result = self.handshake(c);
+        if result.is_err() {
+            self.metrics.handshake_failed();
+        }
+
+        match result {
+            Err(e) if e.downcast_ref::<io::Error>().is_some_and(is_timeout) => {
+                warn!("Client didn't finish the handshake in time, disconnecting");
+                Ok(InteractionResult::Abort)
+            }
+            result => result,
+        }
+    }
+
+    fn transmit<T: Read + Write>(&self, c: &mut Client<T>, conn: &Connection) -> Result<()> {
+        debug!("Starting transmission");
+        match self.transmission(c, conn)? {
+            InteractionResult::Abort => {
+                debug!("Aborting connection");
+            }
+            _ => {
+                debug!("Continuing connection");
             }
         }
 
         Ok(())
     }
 
-    fn handshake<T: Read + Write>(
-        &self,
-        c: &mut Client<T>,
-        export: &Export,
-    ) -> Result<InteractionResult> {
-        // 64 bits
-        c.stream().write_all(&NBD_INIT_MAGIC.to_be_bytes())?;
+    fn handshake<T: Read + Write>(&self, c: &mut Client<T>) -> Result<InteractionResult> {
+        if let Some(name) = c.oldstyle().map(str::to_owned) {
+            return self.oldstyle_handshake(c, &name);
+        }
 
-        // 64 bits
-        c.stream().write_all(&NBD_OPTS_MAGIC.to_be_bytes())?;
+        // The greeting was exchanged in plain text before upgrading to TLS
+        if !c.tls() {
+            // 64 bits
+            c.stream().write_all(&NBD_INIT_MAGIC.to_be_bytes())?;
 
-        // 16 bits
-        let handshake_flags = NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES;
+            // 64 bits
+            c.stream().write_all(&NBD_OPTS_MAGIC.to_be_bytes())?;
 
-        c.stream().write_u16::<BigEndian>(handshake_flags)?;
-        c.stream().flush()?;
+            // 16 bits
+            let handshake_flags = NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES;
 
-        // Start reading client negotiation
-        // option flags
-        let client_flags = c.stream().read_u32::<BigEndian>()?;
-        println!("Received client flags: {:#02x}", client_flags);
-        if client_flags != NBD_FLAG_C_FIXED_NEWSTYLE
-            && client_flags != (NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES)
-        {
-            eprintln!("Unknown client flags {:#02x}", client_flags);
+            c.stream().write_u16::<BigEndian>(handshake_flags)?;
+            c.stream().flush()?;
+
+            // Start reading client negotiation
+            // option flags
+            let client_flags = c.stream().read_u32::<BigEndian>()?;
+            debug!(flags = client_flags, "Received client flags");
+            if client_flags & !(NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES) != 0 {
+                warn!(
+                    flags = client_flags,
+                    "Unsupported client flags, disconnecting"
+                );
+                return Ok(InteractionResult::Abort);
+            }
+            c.set_fixed_newstyle(client_flags & NBD_FLAG_C_FIXED_NEWSTYLE != 0);
+            c.set_no_zeroes(client_flags & NBD_FLAG_C_NO_ZEROES != 0);
         }
 
-        loop {
-            // Check client magic
+        for _ in 0..MAX_OPTIONS {
+            // Check client magic, past a bad one there is no telling where
+            // the next option starts
             let client_magic = c.stream().read_u64::<BigEndian>()?;
-            println!("Checking opts magic: {:#02x}", client_magic);
             if client_magic != NBD_OPTS_MAGIC {
-                eprintln!(
-                    "Bad magic received {:#02x}, expected {:#02x}",
+                warn!(
+                    "Bad magic received {:#02x}, expected {:#02x}, disconnecting",
                     client_magic, NBD_OPTS_MAGIC
                 );
-                continue;
+                return Ok(InteractionResult::Abort);
             }
 
             // Read option
             let option = c.stream().read_u32::<BigEndian>()?;
-            println!("Checking option {:#02x}", option);
 
             // Read option length
             let option_length = c.stream().read_u32::<BigEndian>()?;
-            println!("Received option length {}", option_length);
+            if option_length > MAX_OPTION_LENGTH {
+                warn!(
+                    option,
+                    length = option_length,
+                    "Option too long, disconnecting"
+                );
+                return Ok(InteractionResult::Abort);
+            }
 
             let mut option_data = vec![0; option_length as usize];
             c.read_exact(&mut option_data)?;
-            println!("Read option data {:?}", option_data);
-            // TODO: Remove later
-            let option: NbdOpt = unsafe { transmute(option) };
+            let option = match NbdOpt::try_from(option) {
+                // Clients without fixed newstyle only know the options of
+                // the original newstyle, all we can do is hang up
+                Ok(option @ (NbdOpt::ExportName | NbdOpt::List | NbdOpt::Abort)) => option,
+                _ if !c.fixed_newstyle() => {
+                    warn!(
+                        option,
+                        "Unsupported option without fixed newstyle, disconnecting"
+                    );
+                    return Ok(InteractionResult::Abort);
+                }
+                Ok(option) => option,
+                Err(unknown) => {
+                    debug!(
+                        option = unknown,
+                        length = option_length,
+                        "Received unknown option"
+                    );
+                    protocol::option_reply(
+                        c,
+                        unknown,
+                        NbdReply::NbdRepErrUnsup,
+                        protocol::EMPTY_REPLY,
+                    )?;
+                    continue;
+                }
+            };
+            debug!(?option, length = option_length, "Received option");
+
+            if self.is_shutting_down() && option != NbdOpt::Abort {
+                protocol::handshake_reply(
+                    c,
+                    option,
+                    NbdReply::NbdRepErrShutdown,
+                    protocol::EMPTY_REPLY,
+                )?;
+                continue;
+            }
+
+            if self.options.tls_required
+                && !c.tls()
+                && !matches!(option, NbdOpt::StartTls | NbdOpt::Abort)
+            {
+                if option == NbdOpt::ExportName {
+                    // EXPORT_NAME can't be refused, all we can do is hang up
+                    warn!("Client selected an export without TLS");
+                    return Ok(InteractionResult::Abort);
+                }
+
+                protocol::handshake_reply(
+                    c,
+                    option,
+                    NbdReply::NbdRepErrTlsReqd,
+                    protocol::EMPTY_REPLY,
+                )?;
+                continue;
+            }
+
+            // These options carry no data
+            if !option_data.is_empty()
+                && matches!(
+                    option,
+                    NbdOpt::List
+                        | NbdOpt::StartTls
+                        | NbdOpt::StructuredReply
+                        | NbdOpt::ExtendedHeaders
+                )
+            {
+                protocol::handshake_reply(
+                    c,
+                    option,
+                    NbdReply::NbdRepErrInvalid,
+                    protocol::EMPTY_REPLY,
+                )?;
+                continue;
+            }
 
             match option {
                 NbdOpt::Export => {
@@ -179,195 +829,660 @@ impl Server {
                     )?;
                 }
                 NbdOpt::ExportName => {
-                    println!("Received EXPORT_NAME option");
-                    c.stream().write_u64::<BigEndian>(export.size)?;
+                    let name = String::from_utf8_lossy(&option_data);
+                    let export = match self.find_export(&name) {
+                        Some(export) if allowed(c, &export) => export,
+                        Some(_) => return Ok(InteractionResult::Abort),
+                        None => {
+                            // EXPORT_NAME can't be refused, all we can do is hang up
+                            warn!(export = %name, "Client requested an unknown export");
+                            return Ok(InteractionResult::Abort);
+                        }
+                    };
+
+                    let (export, lease) = match self.admit(export) {
+                        Ok(admitted) => admitted,
+                        Err(refusal) => {
+                            warn!(export = %name, "Refusing client: {}", refusal);
+                            return Ok(InteractionResult::Abort);
+                        }
+                    };
+
+                    Span::current().record("export", export.name.as_str());
+                    c.stream().write_u64::<BigEndian>(export.size())?;
 
                     // TODO use a sane way to initialize the flags
                     let mut flags: u16 = 0;
-                    set_flags(export, &mut flags);
+                    set_flags(&export, &mut flags);
                     c.stream().write_u16::<BigEndian>(flags)?;
+                    if !c.no_zeroes() {
+                        c.stream().write_all(&[0; 124])?;
+                    }
                     c.stream().flush()?;
+
+                    c.set_export(export);
+                    c.set_lease(lease);
+                    return Ok(InteractionResult::Continue);
                 }
                 NbdOpt::List => {
-                    protocol::handle_list(c, &export.name, &export.description)?;
+                    // Exports the client may not use are hidden
+                    let exports: Vec<_> = self
+                        .exports()
+                        .into_iter()
+                        .filter(|e| e.acl.allows(c.peer()))
+                        .collect();
+                    protocol::handle_list(
+                        c,
+                        exports
+                            .iter()
+                            .map(|e| (e.name.as_str(), e.description.as_str())),
+                    )?;
                 }
                 NbdOpt::Abort => {
-                    println!("Aborting");
                     if protocol::handshake_reply(c, option, NbdReply::Ack, protocol::EMPTY_REPLY)
                         .is_err()
                     {
-                        eprintln!("Ignoring abort ACK errors");
+                        debug!("Ignoring abort ACK errors");
                     }
                     return Ok(InteractionResult::Abort);
                 }
                 NbdOpt::StructuredReply => {
+                    // Extended headers already include structured replies
+                    let reply = match c.extended_headers() {
+                        true => NbdReply::NbdRepErrInvalid,
+                        false => NbdReply::Ack,
+                    };
                     c.set_structured_reply(true);
-                    protocol::handshake_reply(c, option, NbdReply::Ack, protocol::EMPTY_REPLY)?;
+                    protocol::handshake_reply(c, option, reply, protocol::EMPTY_REPLY)?;
                 }
-                opt @ NbdOpt::Info => {
-                    println!("Received info");
-                    handle_export_info(c, opt, export, &option_data)?;
-                }
-                opt @ NbdOpt::Go => {
-                    println!("Received go");
-                    handle_export_info(c, opt, export, &option_data)?;
-                    return Ok(InteractionResult::Continue);
+                NbdOpt::ExtendedHeaders => {
+                    c.set_extended_headers(true);
+                    c.set_structured_reply(true);
+                    protocol::handshake_reply(c, option, NbdReply::Ack, protocol::EMPTY_REPLY)?;
                 }
-                NbdOpt::ListMetaContext => {
-                    protocol::handshake_reply(
-                        c,
-                        option,
-                        NbdReply::NbdRepErrUnsup,
-                        protocol::EMPTY_REPLY,
-                    )?;
+                opt @ (NbdOpt::Info | NbdOpt::Go) => {
+                    let (name, requests) = match protocol::parse_info(&option_data) {
+                        Some(parsed) => parsed,
+                        None => {
+                            protocol::handshake_reply(
+                                c,
+                                opt,
+                                NbdReply::NbdRepErrInvalid,
+                                protocol::EMPTY_REPLY,
+                            )?;
+                            continue;
+                        }
+                    };
+
+                    let export = match self.find_export(&name) {
+                        Some(export) if allowed(c, &export) => export,
+                        Some(_) => {
+                            protocol::handshake_reply(
+                                c,
+                                opt,
+                                NbdReply::NbdRepErrPolicy,
+                                protocol::EMPTY_REPLY,
+                            )?;
+                            continue;
+                        }
+                        None => {
+                            debug!(export = %name, "Client requested an unknown export");
+                            protocol::handshake_reply(
+                                c,
+                                opt,
+                                NbdReply::NbdRepErrUnknown,
+                                protocol::EMPTY_REPLY,
+                            )?;
+                            continue;
+                        }
+                    };
+
+                    // INFO gets the flags GO would, but takes no place on the
+                    // export
+                    let admitted = match opt {
+                        NbdOpt::Go => self.admit(export).map(|(e, lease)| (e, Some(lease))),
+                        _ => self.peek(export).map(|e| (e, None)),
+                    };
+                    let (export, lease) = match admitted {
+                        Ok(admitted) => admitted,
+                        Err(refusal) => {
+                            warn!(export = %name, "Refusing client: {}", refusal);
+                            protocol::handshake_reply(
+                                c,
+                                opt,
+                                NbdReply::NbdRepErrPolicy,
+                                refusal.to_string().as_bytes(),
+                            )?;
+                            continue;
+                        }
+                    };
+
+                    handle_export_info(c, opt, &export, &requests)?;
+                    if opt == NbdOpt::Go {
+                        Span::current().record("export", export.name.as_str());
+                        c.set_block_size_constraints(
+                            requests.contains(&(NbdInfoOpt::BlockSize as u16)),
+                        );
+                        c.set_export(export);
+                        if let Some(lease) = lease {
+                            c.set_lease(lease);
+                        }
+                        return Ok(InteractionResult::Continue);
+                    }
                 }
-                NbdOpt::SetMetaContext => {
-                    protocol::handshake_reply(
-                        c,
-                        option,
-                        NbdReply::NbdRepErrUnsup,
-                        protocol::EMPTY_REPLY,
-                    )?;
+                opt @ (NbdOpt::ListMetaContext | NbdOpt::SetMetaContext) => {
+                    self.handle_meta_context(c, opt, &option_data)?;
                 }
                 NbdOpt::StartTls => {
-                    protocol::handshake_reply(
-                        c,
-                        option,
-                        NbdReply::NbdRepErrUnsup,
-                        protocol::EMPTY_REPLY,
-                    )?;
+                    let reply = if self.options.tls.is_none() {
+                        NbdReply::NbdRepErrUnsup
+                    } else if c.tls() {
+                        NbdReply::NbdRepErrInvalid
+                    } else {
+                        NbdReply::Ack
+                    };
+
+                    protocol::handshake_reply(c, option, reply, protocol::EMPTY_REPLY)?;
+                    if reply == NbdReply::Ack {
+                        return Ok(InteractionResult::StartTls);
+                    }
                 }
             }
         }
+
+        warn!(
+            limit = MAX_OPTIONS,
+            "Client sent too many options, disconnecting"
+        );
+        Ok(InteractionResult::Abort)
     }
 
-    fn transmission<T: Read + Write>(
+    /// Sends the single export of an oldstyle listener, there is no
+    /// negotiation and nothing can be refused but by hanging up.
+    fn oldstyle_handshake<T: Read + Write>(
         &self,
         c: &mut Client<T>,
-        export: &Export,
+        name: &str,
     ) -> Result<InteractionResult> {
-        println!("Opening export file {}", export.path);
-        let mut opts = OpenOptions::new();
-        opts.read(true);
-        if !export.read_only {
-            opts.write(true);
+        if self.options.tls_required {
+            warn!("Oldstyle clients can't use TLS, disconnecting");
+            return Ok(InteractionResult::Abort);
         }
 
-        let file = &opts.open(&export.path)?;
+        let export = match self.find_export(name) {
+            Some(export) if allowed(c, &export) => export,
+            Some(_) => return Ok(InteractionResult::Abort),
+            None => {
+                warn!(export = %name, "Oldstyle export doesn't exist");
+                return Ok(InteractionResult::Abort);
+            }
+        };
 
-        let mut request_buf: [u8; NBD_REQUEST_SIZE as usize] = [0; NBD_REQUEST_SIZE as usize];
-        loop {
-            let read = c.stream().read(&mut request_buf)?;
-            println!("Read {} bytes", read);
+        let (export, lease) = match self.admit(export) {
+            Ok(admitted) => admitted,
+            Err(refusal) => {
+                warn!(export = %name, "Refusing client: {}", refusal);
+                return Ok(InteractionResult::Abort);
+            }
+        };
 
-            if (read as u32) < NBD_REQUEST_SIZE {
-                eprintln!("Invalid request size");
+        Span::current().record("export", export.name.as_str());
 
-                // TODO check if client is still there
-                c.write_u32::<BigEndian>(145)?;
-            }
+        let mut flags: u16 = 0;
+        set_flags(&export, &mut flags);
+        debug!(export = %export.name, flags, "Sending oldstyle greeting");
 
-            let request: protocol::Request = bincode::decode_from_slice(
-                &request_buf,
-                bincode::config::standard()
-                    .with_big_endian()
-                    .with_fixed_int_encoding(),
-            )?
-            .0;
+        c.stream().write_all(&NBD_INIT_MAGIC.to_be_bytes())?;
+        c.stream().write_all(&NBD_CLISERV_MAGIC.to_be_bytes())?;
+        c.stream().write_u64::<BigEndian>(export.size())?;
+        // The handshake flags of newstyle are the upper half, and unused
+        c.stream().write_u32::<BigEndian>(flags as u32)?;
+        c.stream().write_all(&[0; 124])?;
+        c.stream().flush()?;
+
+        c.set_export(export);
+        c.set_lease(lease);
+        Ok(InteractionResult::Continue)
+    }
 
-            println!("Checking opts magic: {:?}", request.magic);
-            if request.magic != NBD_REQUEST_MAGIC {
-                eprintln!(
-                    "Bad magic received {:#02x}, expected {:#02x}",
-                    request.magic, NBD_REQUEST_MAGIC
+    /// Answers `NBD_OPT_LIST_META_CONTEXT` and `NBD_OPT_SET_META_CONTEXT`,
+    /// the latter also selects the contexts for the export.
+    fn handle_meta_context<T: Read + Write>(
+        &self,
+        c: &mut Client<T>,
+        opt: NbdOpt,
+        data: &[u8],
+    ) -> Result<()> {
+        // Block status replies are structured
+        let parsed = match c.structured_reply() {
+            true => protocol::parse_meta_context(data),
+            false => None,
+        };
+        let (name, queries) = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                return protocol::handshake_reply(
+                    c,
+                    opt,
+                    NbdReply::NbdRepErrInvalid,
+                    protocol::EMPTY_REPLY,
                 );
+            }
+        };
 
-                continue;
+        let export = match self.find_export(&name) {
+            Some(export) if allowed(c, &export) => export,
+            Some(_) => {
+                return protocol::handshake_reply(
+                    c,
+                    opt,
+                    NbdReply::NbdRepErrPolicy,
+                    protocol::EMPTY_REPLY,
+                );
             }
+            None => {
+                return protocol::handshake_reply(
+                    c,
+                    opt,
+                    NbdReply::NbdRepErrUnknown,
+                    protocol::EMPTY_REPLY,
+                );
+            }
+        };
 
-            let cmd: NbdCmd = unsafe { transmute(request.command_type) };
-            match cmd {
-                NbdCmd::Read => {
-                    println!(
-                        "Received read request, len {}, offset {}",
-                        request.len, request.offset
-                    );
-                    protocol::do_read(c, &request, file)?;
-                }
-                NbdCmd::Write => {
-                    println!(
-                        "Received write request, len {}, offset {}",
-                        request.len, request.offset
-                    );
+        let list = opt == NbdOpt::ListMetaContext;
+        let selected: Vec<(u32, MetaContext)> = MetaContext::available(&export)
+            .into_iter()
+            .enumerate()
+            .map(|(i, context)| (i as u32 + 1, context))
+            .filter(|(_, context)| {
+                // Listing without queries lists everything
+                (list && queries.is_empty()) || queries.iter().any(|q| context.matches(q, list))
+            })
+            .collect();
+
+        for (id, context) in &selected {
+            let id = if list { 0 } else { *id };
+            protocol::meta_context_reply(c, opt, id, &context.name())?;
+        }
+        if !list {
+            debug!(export = %export.name, contexts = selected.len(), "Selected metadata contexts");
+            c.set_meta_contexts(export.name.clone(), selected);
+        }
+
+        protocol::handshake_reply(c, opt, NbdReply::Ack, protocol::EMPTY_REPLY)
+    }
+
+    fn transmission<T: Read + Write>(
+        &self,
+        c: &mut Client<T>,
+        conn: &Connection,
+    ) -> Result<InteractionResult> {
+        let export = match c.export() {
+            Some(export) => Arc::clone(export),
+            None => bail!("Transmission started without an export"),
+        };
+
+        conn.set_export(&export.name);
+        let metrics = self.metrics.export(&export.name);
+        let _active = metrics.connection_opened();
+        let throttle = Throttle::new(&export.connection_limits);
+        // Replaces the handshake deadline
+        conn.set_deadline(None)?;
+        conn.set_read_timeout(self.options.idle_timeout)?;
+        conn.set_write_timeout(self.options.send_timeout)?;
+
+        let result = self.process_requests(c, conn, &export, &metrics, throttle.as_ref());
+
+        // Whatever happened to the client, make sure its writes hit the disk
+        if !export.read_only {
+            export.backend.flush()?;
+        }
+
+        result
+    }
 
-                    protocol::do_write(c, request.handle, request.offset, request.len, file)?;
+    fn process_requests<T: Read + Write>(
+        &self,
+        c: &mut Client<T>,
+        conn: &Connection,
+        export: &Export,
+        metrics: &ExportMetrics,
+        throttle: Option<&Throttle>,
+    ) -> Result<InteractionResult> {
+        let (size, magic) = match c.extended_headers() {
+            true => (NBD_EXTENDED_REQUEST_SIZE, NBD_EXTENDED_REQUEST_MAGIC),
+            false => (NBD_REQUEST_SIZE, NBD_REQUEST_MAGIC),
+        };
+        let mut request_buf = vec![0; size as usize];
+        loop {
+            if let Err(e) = c.stream().read_exact(&mut request_buf) {
+                if e.kind() == io::ErrorKind::UnexpectedEof {
+                    info!("Client closed the connection");
+                    return Ok(InteractionResult::Abort);
                 }
-                NbdCmd::Disc => {
-                    println!("Disconnect requested");
-                    c.stream().flush()?;
+                if is_timeout(&e) {
+                    info!(
+                        timeout = ?self.options.idle_timeout,
+                        "Client was idle for too long, disconnecting"
+                    );
                     return Ok(InteractionResult::Abort);
                 }
-                NbdCmd::Flush => {
-                    println!("Received flush");
-                    c.stream().flush()?;
+
+                return Err(e.into());
+            }
+
+            let request = protocol::Request::decode(&request_buf, c.extended_headers())?;
+
+            // Past a bad magic the stream is out of sync, there is no telling
+            // where the next request starts
+            if request.magic != magic {
+                bail!(
+                    "Bad magic received {:#02x}, expected {:#02x}, disconnecting",
+                    request.magic,
+                    magic
+                );
+            }
+
+            let cmd = match NbdCmd::try_from(request.command_type) {
+                Ok(cmd) => cmd,
+                Err(unknown) => {
+                    // Only writes carry a payload, so assume an unknown command
+                    // has none. If it did, the next request's magic would be
+                    // wrong and the client dropped.
+                    warn!(command = unknown, "Unknown command");
+                    protocol::error_reply(c, &request, NBD_EINVAL)?;
+                    metrics.record_error(NBD_EINVAL);
+                    conn.stats().record_error();
+                    continue;
+                }
+            };
+            let _in_flight = conn.begin_request();
+            let span = debug_span!(
+                "request",
+                handle = request.handle,
+                command = ?cmd,
+                offset = request.offset,
+                length = request.len
+            );
+            let _enter = span.enter();
+            let start = Instant::now();
+
+            if self.is_shutting_down() && !matches!(cmd, NbdCmd::Disc) {
+                if let NbdCmd::Write = cmd {
+                    // Keep the stream in sync by discarding the payload
+                    io::copy(&mut Read::take(c.stream(), request.len), &mut io::sink())?;
                 }
-                NbdCmd::Trim => {
-                    println!("trim!");
+
+                debug!("Failing request, shutting down");
+                protocol::error_reply(c, &request, NBD_ESHUTDOWN)?;
+                metrics.record_error(NBD_ESHUTDOWN);
+                conn.stats().record_error();
+                continue;
+            }
+
+            if cmd == NbdCmd::Disc {
+                info!("Disconnect requested");
+                c.stream().flush()?;
+                return Ok(InteractionResult::Abort);
+            }
+
+            // Only requests that will run are charged to the throttles, a
+            // bogus length must not put a shared bucket in debt
+            if let Some(error) = check(c, export, &request, cmd)? {
+                debug!(error, "Invalid request");
+                protocol::error_reply(c, &request, error)?;
+                metrics.record_error(error);
+                conn.stats().record_error();
+                continue;
+            }
+
+            let bytes = match cmd {
+                NbdCmd::Read | NbdCmd::Write => request.len,
+                _ => 0,
+            };
+            let throttled = qos::wait(
+                export.throttle.as_deref().into_iter().chain(throttle),
+                bytes,
+            );
+            if !throttled.is_zero() {
+                debug!(delay_us = throttled.as_micros() as u64, "Request throttled");
+                metrics.record_throttled(throttled);
+            }
+
+            match execute(c, export, &request, cmd)? {
+                Outcome::Done => {
+                    protocol::done_reply(c, &request)?;
                 }
-                NbdCmd::Cache => {
-                    println!("cache!");
+                Outcome::Data(data) => {
+                    protocol::do_read(c, &request, &data)?;
                 }
-                NbdCmd::WriteZeroes => {
-                    println!("write zeroes!");
+                Outcome::Extents(contexts) => {
+                    protocol::block_status_reply(c, &request, &contexts)?;
                 }
-                NbdCmd::BlockStatus => {
-                    println!("block status!");
+                Outcome::Error(error) => {
+                    debug!(error, "Request failed");
+                    protocol::error_reply(c, &request, error)?;
+                    metrics.record_error(error);
+                    conn.stats().record_error();
+                    continue;
                 }
             }
+
+            let latency = start.elapsed();
+            let (read, written) = match cmd {
+                NbdCmd::Read => (request.len, 0),
+                NbdCmd::Write => (0, request.len),
+                _ => (0, 0),
+            };
+            metrics.record_request(cmd, read, written, latency);
+            conn.stats().record_request(read, written);
+            debug!(latency_us = latency.as_micros() as u64, "Request completed");
         }
     }
 }
 
-fn handle_export_info<T: Read + Write>(
+/// Checks the export's access rules, logging clients that are turned away.
+fn allowed<T: Read + Write>(c: &Client<T>, export: &Export) -> bool {
+    let allowed = export.acl.allows(c.peer());
+    if !allowed {
+        warn!(export = %export.name, peer = %c.peer(), "Access denied");
+    }
+
+    allowed
+}
+
+/// Checks a request against the export before it runs, giving the error to
+/// reply with. The payload of a refused write is skipped.
+fn check<T: Read + Write>(
     c: &mut Client<T>,
-    opt: NbdOpt,
     export: &Export,
-    data: &[u8],
-) -> Result<()> {
-    // Read number of requests
-    let requests = u16::from_be_bytes(data[0..2].try_into().unwrap());
-    println!("Receiving {} request(s)", requests);
+    request: &protocol::Request,
+    cmd: NbdCmd,
+) -> Result<Option<u32>> {
+    let len = request.len;
+    let in_bounds = export.contains(request.offset, len);
+
+    let error = if c.block_size_constraints() && !fits_block_size(export, cmd, request.offset, len)
+    {
+        debug!(block_size = ?export.block_size, "Request breaks the block size constraints");
+        Some(NBD_EINVAL)
+    } else {
+        match cmd {
+            // 64-bit lengths aren't a licence to allocate that much
+            NbdCmd::Read if !in_bounds || len > MAX_BLOCK_SIZE => Some(NBD_EINVAL),
+            NbdCmd::Write if len > MAX_BLOCK_SIZE => Some(NBD_EINVAL),
+            NbdCmd::Resize if !export.can_resize => Some(NBD_EINVAL),
+            NbdCmd::Write | NbdCmd::Trim | NbdCmd::WriteZeroes | NbdCmd::Resize
+                if export.read_only =>
+            {
+                Some(NBD_EPERM)
+            }
+            NbdCmd::Write | NbdCmd::WriteZeroes if !in_bounds => Some(NBD_ENOSPC),
+            NbdCmd::Trim | NbdCmd::Cache if !in_bounds => Some(NBD_EINVAL),
+            NbdCmd::BlockStatus
+                if c.meta_contexts(&export.name).is_empty() || len == 0 || !in_bounds =>
+            {
+                // Only valid once a metadata context was negotiated
+                Some(NBD_EINVAL)
+            }
+            _ => None,
+        }
+    };
+
+    if error.is_some() && cmd == NbdCmd::Write {
+        io::copy(&mut Read::take(c.stream(), len), &mut io::sink())?;
+    }
+
+    Ok(error)
+}
 
-    let mut send_name = false;
-    let mut send_description = false;
+/// Runs a request that passed [`check`] against the export's backend.
+///
+/// Only failing to talk to the client is an error, backend failures are
+/// turned into an errno for the client.
+fn execute<T: Read + Write>(
+    c: &mut Client<T>,
+    export: &Export,
+    request: &protocol::Request,
+    cmd: NbdCmd,
+) -> Result<Outcome> {
+    let len = request.len;
+    let fua = request.flags & NBD_CMD_FLAG_FUA != 0;
 
-    for i in 0..requests {
-        // TODO use proper safe conversion
-        let option = unsafe { transmute(c.stream().read_u16::<BigEndian>()?) };
-        println!("Request {}/{}, option {:?}", i + 1, requests, option);
+    let outcome = match cmd {
+        NbdCmd::Read => {
+            let mut buf = vec![0; len as usize];
+            match export.backend.read_at(&mut buf, request.offset) {
+                Ok(()) => Outcome::Data(buf),
+                Err(e) => io_error(e),
+            }
+        }
+        NbdCmd::Write => {
+            let mut buf = vec![0; len as usize];
+            c.stream().read_exact(&mut buf)?;
 
-        match option {
-            NbdInfoOpt::Export => {
-                println!("Sending export info");
+            // The export may have shrunk since the request was checked
+            let _resizing = export.resizing.read().unwrap();
+            if !export.contains(request.offset, len) {
+                return Ok(Outcome::Error(NBD_ENOSPC));
             }
-            NbdInfoOpt::Name => {
-                println!("export name requested");
-                send_name = true;
+            if let Err(e) = export.mark_dirty(request.offset, len) {
+                return Ok(io_error(e));
+            }
+
+            outcome(export.backend.write_at(&buf, request.offset), export, fua)
+        }
+        NbdCmd::Flush => outcome(export.backend.flush(), export, false),
+        NbdCmd::Trim => {
+            let _resizing = export.resizing.read().unwrap();
+            if !export.contains(request.offset, len) {
+                return Ok(Outcome::Error(NBD_EINVAL));
             }
-            NbdInfoOpt::Description => {
-                println!("export description requested");
-                send_description = true;
+            if let Err(e) = export.mark_dirty(request.offset, len) {
+                return Ok(io_error(e));
             }
-            NbdInfoOpt::BlockSize => {
-                println!("block size requested");
+
+            outcome(export.backend.trim(request.offset, len), export, fua)
+        }
+        NbdCmd::WriteZeroes => {
+            let _resizing = export.resizing.read().unwrap();
+            if !export.contains(request.offset, len) {
+                return Ok(Outcome::Error(NBD_ENOSPC));
             }
-            NbdInfoOpt::Unknown => {
-                panic!("Shouldn't happen");
+            if let Err(e) = export.mark_dirty(request.offset, len) {
+                return Ok(io_error(e));
             }
+
+            let may_trim = request.flags & NBD_CMD_FLAG_NO_HOLE == 0;
+            let result = export.backend.write_zeroes(request.offset, len, may_trim);
+            outcome(result, export, fua)
         }
+        NbdCmd::Cache => outcome(export.backend.cache(request.offset, len), export, false),
+        NbdCmd::BlockStatus => {
+            let contexts = c.meta_contexts(&export.name).to_vec();
+            let mut found = Vec::with_capacity(contexts.len());
+            for (id, context) in contexts {
+                match context.extents(export, request.offset, len) {
+                    Ok(mut extents) => {
+                        if request.flags & NBD_CMD_FLAG_REQ_ONE != 0 {
+                            extents.truncate(1);
+                        }
+                        found.push((id, extents));
+                    }
+                    Err(e) => return Ok(io_error(e)),
+                }
+            }
+
+            Outcome::Extents(found)
+        }
+        NbdCmd::Resize => match export.resize(request.offset) {
+            Ok(()) => Outcome::Done,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Outcome::Error(NBD_EINVAL),
+            Err(e) => io_error(e),
+        },
+        NbdCmd::Disc => Outcome::Done,
+    };
+
+    Ok(outcome)
+}
+
+/// Whether a request respects the export's block sizes: aligned to the
+/// minimum, apart from a partial block at the end of the export, and with
+/// a payload no larger than the maximum.
+fn fits_block_size(export: &Export, cmd: NbdCmd, offset: u64, len: u64) -> bool {
+    let minimum = export.block_size.minimum as u64;
+    let end = offset.saturating_add(len);
+
+    match cmd {
+        NbdCmd::Read | NbdCmd::Write if len > export.block_size.maximum as u64 => false,
+        NbdCmd::Read
+        | NbdCmd::Write
+        | NbdCmd::Trim
+        | NbdCmd::WriteZeroes
+        | NbdCmd::Cache
+        | NbdCmd::BlockStatus => {
+            offset.is_multiple_of(minimum) && (end.is_multiple_of(minimum) || end == export.size())
+        }
+        _ => true,
     }
+}
+
+/// Completes a request, flushing first for `NBD_CMD_FLAG_FUA`.
+fn outcome(result: io::Result<()>, export: &Export, fua: bool) -> Outcome {
+    match result.and_then(|_| if fua { export.backend.flush() } else { Ok(()) }) {
+        Ok(()) => Outcome::Done,
+        Err(e) => io_error(e),
+    }
+}
+
+fn io_error(e: io::Error) -> Outcome {
+    warn!("Backend I/O failed: {}", e);
+
+    Outcome::Error(match e.raw_os_error() {
+        Some(libc::EPERM) | Some(libc::EACCES) | Some(libc::EROFS) => NBD_EPERM,
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) | Some(libc::EFBIG) => NBD_ENOSPC,
+        Some(libc::EINVAL) => NBD_EINVAL,
+        Some(libc::ENOMEM) => NBD_ENOMEM,
+        Some(libc::EOVERFLOW) => NBD_EOVERFLOW,
+        Some(libc::EOPNOTSUPP) => NBD_ENOTSUP,
+        _ => NBD_EIO,
+    })
+}
 
-    if send_name {
+/// Sends the export's details, and whatever else the client asked for.
+fn handle_export_info<T: Read + Write>(
+    c: &mut Client<T>,
+    opt: NbdOpt,
+    export: &Export,
+    requests: &[u16],
+) -> Result<()> {
+    debug!(?requests, "Receiving info requests");
+
+    // NBD_INFO_EXPORT is mandatory, the rest is only sent when requested
+    let requested = |info: NbdInfoOpt| requests.contains(&(info as u16));
+
+    if requested(NbdInfoOpt::Name) {
         protocol::info_reply(
             c,
             opt,
@@ -377,7 +1492,7 @@ fn handle_export_info<T: Read + Write>(
         )?;
     }
 
-    if send_description {
+    if requested(NbdInfoOpt::Description) {
         protocol::info_reply(
             c,
             opt,
@@ -387,41 +1502,38 @@ fn handle_export_info<T: Read + Write>(
         )?;
     }
 
-    let sizes: Vec<u32> = vec![
-        MIN_BLOCK_SIZE as u32,
-        PREFERRED_BLOCK_SIZE as u32,
-        std::cmp::min(export.size, MAX_BLOCK_SIZE) as u32,
-    ];
+    if requested(NbdInfoOpt::BlockSize) {
+        let sizes: Vec<u32> = vec![
+            export.block_size.minimum,
+            export.block_size.preferred,
+            export.block_size.maximum,
+        ];
 
-    println!("Reporting sizes {:?}", sizes);
+        debug!(?sizes, "Reporting block sizes");
 
-    protocol::info_reply(
-        c,
-        opt,
-        NbdInfoOpt::BlockSize,
-        14,
-        &sizes
-            .iter()
-            .flat_map(|x| x.to_be_bytes())
-            .collect::<Vec<u8>>(),
-    )?;
+        protocol::info_reply(
+            c,
+            opt,
+            NbdInfoOpt::BlockSize,
+            14,
+            &sizes
+                .iter()
+                .flat_map(|x| x.to_be_bytes())
+                .collect::<Vec<u8>>(),
+        )?;
+    }
 
     let mut flags: u16 = 0;
     set_flags(export, &mut flags);
 
-    println!(
-        "Sending export '{}' information, flags {}",
-        export.name, flags
-    );
+    debug!(export = %export.name, flags, "Sending export information");
     protocol::info_reply(c, opt, NbdInfoOpt::Export, 12, protocol::EMPTY_REPLY)?;
 
-    c.stream().write_all(&export.size.to_be_bytes())?;
+    c.stream().write_all(&export.size().to_be_bytes())?;
     c.stream().write_all(&flags.to_be_bytes())?;
     c.stream().flush()?;
 
-    protocol::handshake_reply(c, opt, NbdReply::Ack, protocol::EMPTY_REPLY)?;
-
-    Ok(())
+    protocol::handshake_reply(c, opt, NbdReply::Ack, protocol::EMPTY_REPLY)
 }
 
 fn set_flags(export: &Export, flags: &mut u16) {
@@ -430,7 +1542,7 @@ fn set_flags(export: &Export, flags: &mut u16) {
         *flags |= consts::NBD_FLAG_READ_ONLY;
     }
     if export.can_resize {
-        *flags |= consts::NBD_FLAG_SEND_FLUSH;
+        *flags |= consts::NBD_FLAG_SEND_RESIZE;
     }
     if export.fast_zero {
         *flags |= consts::NBD_FLAG_SEND_FAST_ZERO;
@@ -444,10 +1556,287 @@ fn set_flags(export: &Export, flags: &mut u16) {
     if export.flush {
         *flags |= consts::NBD_FLAG_SEND_FLUSH;
     }
+    if export.fua {
+        *flags |= consts::NBD_FLAG_SEND_FUA;
+    }
+    if export.cache {
+        *flags |= consts::NBD_FLAG_SEND_CACHE;
+    }
+    if export.write_zeroes {
+        *flags |= consts::NBD_FLAG_SEND_WRITE_ZEROES;
+    }
     if export.df {
         *flags |= consts::NBD_FLAG_SEND_DF;
     }
-    if export.multiconn {
+    // Without a shared cache and flush, clients of other connections could
+    // miss writes even after flushing
+    if export.multiconn && (export.read_only || export.backend.can_multi_conn()) {
         *flags |= consts::NBD_FLAG_CAN_MULTI_CONN;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::Mutex, thread};
+
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Call {
+        Write(u64, u64),
+        Flush,
+        Trim(u64, u64),
+        WriteZeroes(u64, u64, bool),
+    }
+
+    /// 64KiB of memory recording what was done to it.
+    #[derive(Debug)]
+    struct Recorder {
+        data: Mutex<Vec<u8>>,
+        calls: Mutex<Vec<Call>>,
+    }
+
+    impl Recorder {
+        fn new() -> Arc<Recorder> {
+            Arc::new(Recorder {
+                data: Mutex::new(vec![0xaa; 64 * 1024]),
+                calls: Mutex::default(),
+            })
+        }
+
+        fn calls(&self) -> Vec<Call> {
+            std::mem::take(&mut self.calls.lock().unwrap())
+        }
+
+        fn range(&self, offset: u64, len: u64) -> std::ops::Range<usize> {
+            offset as usize..(offset + len) as usize
+        }
+    }
+
+    impl Backend for Recorder {
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.data.lock().unwrap().len() as u64)
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            let range = self.range(offset, buf.len() as u64);
+            buf.copy_from_slice(&self.data.lock().unwrap()[range]);
+            Ok(())
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+            let range = self.range(offset, buf.len() as u64);
+            self.data.lock().unwrap()[range].copy_from_slice(buf);
+            let call = Call::Write(offset, buf.len() as u64);
+            self.calls.lock().unwrap().push(call);
+            Ok(())
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            self.calls.lock().unwrap().push(Call::Flush);
+            Ok(())
+        }
+
+        fn trim(&self, offset: u64, len: u64) -> io::Result<()> {
+            self.calls.lock().unwrap().push(Call::Trim(offset, len));
+            Ok(())
+        }
+
+        fn write_zeroes(&self, offset: u64, len: u64, may_trim: bool) -> io::Result<()> {
+            self.data.lock().unwrap()[self.range(offset, len)].fill(0);
+            let call = Call::WriteZeroes(offset, len, may_trim);
+            self.calls.lock().unwrap().push(call);
+            Ok(())
+        }
+
+        fn resize(&self, size: u64) -> io::Result<()> {
+            self.data.lock().unwrap().resize(size as usize, 0);
+            Ok(())
+        }
+    }
+
+    fn export(backend: &Arc<Recorder>) -> Export {
+        let backend = Arc::clone(backend) as Arc<dyn Backend>;
+        let mut export = Export::new(String::from("test"), String::new(), backend).unwrap();
+        export.trim = true;
+        export.fua = true;
+        export.write_zeroes = true;
+        export
+    }
+
+    fn request(cmd: u16, flags: u16, offset: u64, len: u64) -> protocol::Request {
+        protocol::Request {
+            magic: NBD_REQUEST_MAGIC,
+            flags,
+            command_type: cmd,
+            handle: 1,
+            offset,
+            len,
+        }
+    }
+
+    /// Checks and runs a request with `payload` as what the client sends
+    /// after it, giving the error and what was left unread.
+    fn run(export: &Export, request: protocol::Request, payload: &[u8]) -> (u32, usize) {
+        let cmd = NbdCmd::try_from(request.command_type).unwrap();
+        let mut c = Client::new(Cursor::new(payload.to_vec()), String::from("test"));
+        let error = match check(&mut c, export, &request, cmd).unwrap() {
+            Some(error) => error,
+            None => match execute(&mut c, export, &request, cmd).unwrap() {
+                Outcome::Error(error) => error,
+                _ => 0,
+            },
+        };
+
+        let unread = payload.len() - c.stream().get_ref().position() as usize;
+        (error, unread)
+    }
+
+    const WRITE: u16 = 1;
+    const TRIM: u16 = 4;
+    const WRITE_ZEROES: u16 = 6;
+
+    #[test]
+    fn test_fua() {
+        let backend = Recorder::new();
+        let export = export(&backend);
+
+        assert_eq!(run(&export, request(WRITE, 0, 512, 4), b"abcd"), (0, 0));
+        assert_eq!(backend.calls(), [Call::Write(512, 4)]);
+
+        // Flushed before the reply, whatever the command
+        let fua = NBD_CMD_FLAG_FUA;
+        assert_eq!(run(&export, request(WRITE, fua, 512, 4), b"abcd"), (0, 0));
+        assert_eq!(backend.calls(), [Call::Write(512, 4), Call::Flush]);
+        run(&export, request(TRIM, fua, 0, 4096), b"");
+        assert_eq!(backend.calls(), [Call::Trim(0, 4096), Call::Flush]);
+        run(&export, request(WRITE_ZEROES, fua, 0, 4096), b"");
+        assert_eq!(
+            backend.calls(),
+            [Call::WriteZeroes(0, 4096, true), Call::Flush]
+        );
+    }
+
+    #[test]
+    fn test_trim_and_write_zeroes() {
+        let backend = Recorder::new();
+        let export = export(&backend);
+
+        run(&export, request(TRIM, 0, 4096, 8192), b"");
+        assert_eq!(backend.calls(), [Call::Trim(4096, 8192)]);
+
+        // NO_HOLE keeps the range allocated
+        run(&export, request(WRITE_ZEROES, 0, 100, 200), b"");
+        run(
+            &export,
+            request(WRITE_ZEROES, NBD_CMD_FLAG_NO_HOLE, 300, 200),
+            b"",
+        );
+        assert_eq!(
+            backend.calls(),
+            [
+                Call::WriteZeroes(100, 200, true),
+                Call::WriteZeroes(300, 200, false)
+            ]
+        );
+        let mut buf = [0xff; 600];
+        backend.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..100], [0xaa; 100]);
+        assert_eq!(buf[100..500], [0; 400]);
+        assert_eq!(buf[500..], [0xaa; 100]);
+
+        // Past the end trimming is invalid and zeroing out of space
+        let size = export.size();
+        assert_eq!(run(&export, request(TRIM, 0, size, 1), b"").0, NBD_EINVAL);
+        let zeroes = request(WRITE_ZEROES, 0, size - 1, 2);
+        assert_eq!(run(&export, zeroes, b"").0, NBD_ENOSPC);
+        assert_eq!(backend.calls(), []);
+    }
+
+    #[test]
+    fn test_fits_block_size() {
+        let backend = Recorder::new();
+        let mut export = export(&backend);
+        export.block_size = BlockSize {
+            minimum: 4096,
+            preferred: 4096,
+            maximum: 64 * 1024,
+        };
+        let fits = |cmd, offset, len| fits_block_size(&export, cmd, offset, len);
+
+        assert!(fits(NbdCmd::Read, 4096, 8192));
+        assert!(!fits(NbdCmd::Read, 512, 4096));
+        assert!(!fits(NbdCmd::Write, 0, 512));
+        assert!(!fits(NbdCmd::Trim, 0, 4097));
+        assert!(!fits(NbdCmd::BlockStatus, 100, 4096));
+        // Only reads and writes carry a payload limited by the maximum
+        assert!(!fits(NbdCmd::Read, 0, 128 * 1024));
+        assert!(fits(NbdCmd::WriteZeroes, 0, 128 * 1024));
+        // Flushes have no range to align
+        assert!(fits(NbdCmd::Flush, 1, 1));
+
+        // A partial block is fine at the end of an export of odd size
+        backend.data.lock().unwrap().truncate(10000);
+        export.size.store(10000, Ordering::SeqCst);
+        assert!(fits(NbdCmd::Read, 8192, 1808));
+        assert!(!fits(NbdCmd::Read, 8192, 1000));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let backend = Recorder::new();
+        let mut export = export(&backend);
+        export.read_only = true;
+
+        // The payload of the refused write is skipped, not taken as requests
+        assert_eq!(
+            run(&export, request(WRITE, 0, 0, 4), b"abcdNEXT"),
+            (NBD_EPERM, 4)
+        );
+        for cmd in [TRIM, WRITE_ZEROES] {
+            assert_eq!(run(&export, request(cmd, 0, 0, 512), b"").0, NBD_EPERM);
+        }
+        assert_eq!(backend.calls(), []);
+    }
+
+    #[test]
+    fn test_shrink_during_write() {
+        let backend = Recorder::new();
+        let mut export = export(&backend);
+        export.can_resize = true;
+        export.can_shrink = true;
+
+        // Checked before the shrink, run after it
+        for (cmd, error) in [
+            (WRITE, NBD_ENOSPC),
+            (TRIM, NBD_EINVAL),
+            (WRITE_ZEROES, NBD_ENOSPC),
+        ] {
+            let write = request(cmd, 0, 48 * 1024, 4);
+            let mut c = Client::new(Cursor::new(b"abcd".to_vec()), String::from("test"));
+            let command = NbdCmd::try_from(cmd).unwrap();
+            assert_eq!(check(&mut c, &export, &write, command).unwrap(), None);
+            export.resize(32 * 1024).unwrap();
+            match execute(&mut c, &export, &write, command).unwrap() {
+                Outcome::Error(e) => assert_eq!(e, error),
+                _ => panic!("Ran past the end of the export"),
+            }
+            export.resize(64 * 1024).unwrap();
+        }
+        assert_eq!(backend.calls(), []);
+
+        // A shrink waits for the requests under way
+        let export = Arc::new(export);
+        let writing = export.resizing.read().unwrap();
+        let shrink = thread::spawn({
+            let export = Arc::clone(&export);
+            move || export.resize(32 * 1024).unwrap()
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(export.size(), 64 * 1024);
+        drop(writing);
+        shrink.join().unwrap();
+        assert_eq!(export.size(), 32 * 1024);
+        assert_eq!(backend.data.lock().unwrap().len(), 32 * 1024);
+    }
+}
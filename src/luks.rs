@@ -0,0 +1,671 @@
+//! Opens LUKS1 and LUKS2 volumes as made by `cryptsetup luksFormat`, in
+//! userspace.
+//!
+//! A keyslot holds the volume key split into anti-forensic stripes and
+//! encrypted with a key derived from the passphrase. Unlocking derives that
+//! key, decrypts and merges the stripes, and checks the result against the
+//! header's digest of the volume key.
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64ct::{Base64, Encoding};
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{digest::Digest as _, Sha256, Sha512};
+use tracing::{debug, info, warn};
+
+use crate::{
+    backend::{Aligned, Backend, Encrypted, Slice},
+    xts::Xts,
+};
+
+const MAGIC: &[u8] = b"LUKS\xba\xbe";
+const SECONDARY_MAGIC: &[u8] = b"SKUL\xba\xbe";
+const SECTOR: usize = 512;
+
+const LUKS1_HEADER_LEN: usize = 592;
+const LUKS1_DIGEST_LEN: usize = 20;
+const LUKS1_KEYSLOTS: usize = 8;
+const LUKS1_KEY_ENABLED: u32 = 0x00ac71f3;
+
+const LUKS2_BINARY_LEN: usize = 4096;
+// Where the second copy of a LUKS2 header may be, by the size of the first
+const LUKS2_SECONDARY_OFFSETS: [u64; 9] = [
+    0x4000, 0x8000, 0x10000, 0x20000, 0x40000, 0x80000, 0x100000, 0x200000, 0x400000,
+];
+// Metadata is at most a few MiB, anything larger is a corrupt header
+const LUKS2_MAX_HEADER_LEN: u64 = 4 * 1024 * 1024;
+// Keyslots are at most 64 byte keys times a few thousand stripes
+const MAX_KEY_MATERIAL: usize = 64 * 1024 * 1024;
+// In KiB, cryptsetup doesn't go beyond 4GiB either
+const MAX_ARGON2_MEMORY: u32 = 4 * 1024 * 1024;
+
+/// Unlocks the LUKS volume in `backend` and gives its decrypted payload.
+pub fn open(backend: Arc<dyn Backend>, passphrase: &[u8]) -> Result<Arc<dyn Backend>> {
+    let header = Header::read(backend.as_ref())?;
+    let key = header.unlock(backend.as_ref(), passphrase)?;
+
+    let size = backend.size()?;
+    if header.payload_offset > size {
+        bail!(
+            "Payload at offset {} is past the end of the file ({} bytes)",
+            header.payload_offset,
+            size
+        );
+    }
+    let sector = header.sector_size as u64;
+    let len = header
+        .payload_len
+        .unwrap_or((size - header.payload_offset) / sector * sector);
+
+    let payload = Slice::new(backend, header.payload_offset, Some(len))?;
+    let encrypted = Encrypted::new(Arc::new(payload), Xts::new(&key)?, header.sector_size)?;
+    Ok(Arc::new(Aligned::new(
+        Arc::new(encrypted),
+        header.sector_size,
+    )?))
+}
+
+/// What's needed from a LUKS1 or LUKS2 header to unlock and serve it.
+#[derive(Debug)]
+struct Header {
+    version: u16,
+    payload_offset: u64,
+    /// Up to the end of the file when not given
+    payload_len: Option<u64>,
+    sector_size: u32,
+    /// Tried in order
+    keyslots: Vec<Keyslot>,
+}
+
+#[derive(Debug)]
+struct Keyslot {
+    name: String,
+    kdf: Kdf,
+    /// Length of the key the KDF derives to decrypt the stripes
+    kdf_len: usize,
+    /// Where the encrypted stripes are
+    offset: u64,
+    stripes: usize,
+    af_hash: Hash,
+    /// Length of the volume key
+    key_len: usize,
+    digest: Digest,
+}
+
+#[derive(Debug)]
+enum Kdf {
+    Pbkdf2 {
+        hash: Hash,
+        iterations: u32,
+        salt: Vec<u8>,
+    },
+    Argon2 {
+        algorithm: Algorithm,
+        time: u32,
+        /// KiB
+        memory: u32,
+        lanes: u32,
+        salt: Vec<u8>,
+    },
+}
+
+/// PBKDF2 of the volume key, to tell whether a keyslot was unlocked.
+#[derive(Debug, Clone)]
+struct Digest {
+    hash: Hash,
+    iterations: u32,
+    salt: Vec<u8>,
+    digest: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Hash {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Hash {
+    fn parse(name: &str) -> Result<Hash> {
+        match name {
+            "sha1" => Ok(Hash::Sha1),
+            "sha256" => Ok(Hash::Sha256),
+            "sha512" => Ok(Hash::Sha512),
+            _ => bail!("Unsupported hash {:?}", name),
+        }
+    }
+
+    fn pbkdf2(self, password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+        match self {
+            Hash::Sha1 => pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, iterations, out),
+            Hash::Sha256 => pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, out),
+            Hash::Sha512 => pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, iterations, out),
+        }
+    }
+
+    /// Hashes each digest sized piece of `buf` with its index in place, the
+    /// last one truncated.
+    fn diffuse(self, buf: &mut [u8]) {
+        match self {
+            Hash::Sha1 => diffuse::<Sha1>(buf),
+            Hash::Sha256 => diffuse::<Sha256>(buf),
+            Hash::Sha512 => diffuse::<Sha512>(buf),
+        }
+    }
+}
+
+fn diffuse<D: sha2::digest::Digest>(buf: &mut [u8]) {
+    for (i, chunk) in buf
+        .chunks_mut(<D as sha2::digest::Digest>::output_size())
+        .enumerate()
+    {
+        let hash = D::new()
+            .chain_update((i as u32).to_be_bytes())
+            .chain_update(&*chunk)
+            .finalize();
+        let len = chunk.len();
+        chunk.copy_from_slice(&hash[..len]);
+    }
+}
+
+impl Kdf {
+    fn derive(&self, passphrase: &[u8], out: &mut [u8]) -> Result<()> {
+        match self {
+            Kdf::Pbkdf2 {
+                hash,
+                iterations,
+                salt,
+            } => hash.pbkdf2(passphrase, salt, *iterations, out),
+            Kdf::Argon2 {
+                algorithm,
+                time,
+                memory,
+                lanes,
+                salt,
+            } => {
+                if *memory > MAX_ARGON2_MEMORY {
+                    bail!(
+                        "Argon2 wants {} KiB of memory, at most {} KiB are allowed",
+                        memory,
+                        MAX_ARGON2_MEMORY
+                    );
+                }
+                let params = Params::new(*memory, *time, *lanes, Some(out.len()))
+                    .map_err(|e| anyhow!("Invalid Argon2 parameters: {}", e))?;
+                Argon2::new(*algorithm, Version::V0x13, params)
+                    .hash_password_into(passphrase, salt, out)
+                    .map_err(|e| anyhow!("Argon2 failed: {}", e))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Digest {
+    fn matches(&self, key: &[u8]) -> bool {
+        let mut digest = vec![0; self.digest.len()];
+        self.hash
+            .pbkdf2(key, &self.salt, self.iterations, &mut digest);
+        digest == self.digest
+    }
+}
+
+impl Header {
+    fn read(backend: &dyn Backend) -> Result<Header> {
+        let mut start = [0; 8];
+        backend
+            .read_at(&mut start, 0)
+            .context("Can't read the LUKS header")?;
+        if &start[..6] != MAGIC {
+            bail!("Not a LUKS volume");
+        }
+
+        match u16::from_be_bytes([start[6], start[7]]) {
+            1 => Header::luks1(backend),
+            2 => Header::luks2(backend),
+            version => bail!("Unsupported LUKS version {}", version),
+        }
+    }
+
+    fn luks1(backend: &dyn Backend) -> Result<Header> {
+        let mut buf = [0; LUKS1_HEADER_LEN];
+        backend.read_at(&mut buf, 0)?;
+
+        let cipher = c_string(&buf[8..40]);
+        let mode = c_string(&buf[40..72]);
+        check_cipher(&format!("{}-{}", cipher, mode))?;
+
+        let hash = Hash::parse(&c_string(&buf[72..104]))?;
+        let payload_offset = u32_at(&buf, 104) as u64 * SECTOR as u64;
+        let key_len = u32_at(&buf, 108) as usize;
+        let digest = Digest {
+            hash,
+            iterations: u32_at(&buf, 164),
+            salt: buf[132..164].to_vec(),
+            digest: buf[112..112 + LUKS1_DIGEST_LEN].to_vec(),
+        };
+
+        let keyslots = (0..LUKS1_KEYSLOTS)
+            .filter_map(|i| {
+                let slot = &buf[208 + i * 48..208 + (i + 1) * 48];
+                if u32_at(slot, 0) != LUKS1_KEY_ENABLED {
+                    return None;
+                }
+
+                Some(Keyslot {
+                    name: i.to_string(),
+                    kdf: Kdf::Pbkdf2 {
+                        hash,
+                        iterations: u32_at(slot, 4),
+                        salt: slot[8..40].to_vec(),
+                    },
+                    kdf_len: key_len,
+                    offset: u32_at(slot, 40) as u64 * SECTOR as u64,
+                    stripes: u32_at(slot, 44) as usize,
+                    af_hash: hash,
+                    key_len,
+                    digest: digest.clone(),
+                })
+            })
+            .collect();
+
+        Ok(Header {
+            version: 1,
+            payload_offset,
+            payload_len: None,
+            sector_size: SECTOR as u32,
+            keyslots,
+        })
+    }
+
+    fn luks2(backend: &dyn Backend) -> Result<Header> {
+        let json = match luks2_metadata(backend, 0, MAGIC) {
+            Ok(json) => json,
+            Err(e) => {
+                // cryptsetup keeps a second copy in case the first is damaged
+                let secondary = LUKS2_SECONDARY_OFFSETS
+                    .iter()
+                    .find_map(|&offset| luks2_metadata(backend, offset, SECONDARY_MAGIC).ok());
+                match secondary {
+                    Some(json) => {
+                        info!("Using the secondary LUKS2 header: {:#}", e);
+                        json
+                    }
+                    None => return Err(e),
+                }
+            }
+        };
+
+        let metadata: Metadata = serde_json::from_slice(&json).context("Invalid LUKS2 metadata")?;
+        metadata.header()
+    }
+
+    /// Finds the keyslot the passphrase opens and gives the volume key, a
+    /// keyslot that can't be tried doesn't keep the others from being.
+    fn unlock(&self, backend: &dyn Backend, passphrase: &[u8]) -> Result<Vec<u8>> {
+        if self.keyslots.is_empty() {
+            bail!("No keyslot is in use");
+        }
+
+        let mut failed = 0;
+        for slot in &self.keyslots {
+            match slot.unlock(backend, passphrase) {
+                Ok(Some(key)) => {
+                    info!("Unlocked LUKS{} keyslot {}", self.version, slot.name);
+                    return Ok(key);
+                }
+                Ok(None) => debug!("Passphrase doesn't open keyslot {}", slot.name),
+                Err(e) => {
+                    warn!("Can't try keyslot {}: {:#}", slot.name, e);
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            bail!(
+                "No keyslot matches the passphrase, {} of them couldn't be tried",
+                failed
+            );
+        }
+        bail!("No keyslot matches the passphrase")
+    }
+}
+
+impl Keyslot {
+    fn unlock(&self, backend: &dyn Backend, passphrase: &[u8]) -> Result<Option<Vec<u8>>> {
+        let len = self
+            .key_len
+            .checked_mul(self.stripes)
+            .filter(|&len| len > 0 && len <= MAX_KEY_MATERIAL)
+            .context("Invalid key size or stripes")?;
+
+        let mut key = vec![0; self.kdf_len];
+        self.kdf.derive(passphrase, &mut key)?;
+        let cipher = Xts::new(&key)?;
+
+        // Stripes are encrypted like a payload of their own, sector by sector
+        let mut material = vec![0; len.div_ceil(SECTOR) * SECTOR];
+        backend
+            .read_at(&mut material, self.offset)
+            .context("Can't read the key material")?;
+        for (i, sector) in material.chunks_exact_mut(SECTOR).enumerate() {
+            cipher.decrypt(sector, i as u64);
+        }
+
+        let key = af_merge(&material[..len], self.key_len, self.af_hash);
+        Ok(self.digest.matches(&key).then_some(key))
+    }
+}
+
+/// Undoes the anti-forensic split, XORing the stripes together with each
+/// partial result diffused.
+fn af_merge(material: &[u8], key_len: usize, hash: Hash) -> Vec<u8> {
+    let mut key = vec![0; key_len];
+    let mut stripes = material.chunks_exact(key_len).peekable();
+    while let Some(stripe) = stripes.next() {
+        for (k, s) in key.iter_mut().zip(stripe) {
+            *k ^= s;
+        }
+        if stripes.peek().is_some() {
+            hash.diffuse(&mut key);
+        }
+    }
+
+    key
+}
+
+/// Only AES-XTS with the sector number as the IV, cryptsetup's default
+/// since 1.6.
+fn check_cipher(cipher: &str) -> Result<()> {
+    match cipher {
+        "aes-xts-plain64" => Ok(()),
+        _ => bail!(
+            "Unsupported cipher {:?}, only aes-xts-plain64 is supported",
+            cipher
+        ),
+    }
+}
+
+/// Reads the JSON metadata of the LUKS2 header at `offset`, after checking
+/// its checksum.
+fn luks2_metadata(backend: &dyn Backend, offset: u64, magic: &[u8]) -> Result<Vec<u8>> {
+    let mut binary = vec![0; LUKS2_BINARY_LEN];
+    backend
+        .read_at(&mut binary, offset)
+        .context("Can't read the LUKS2 header")?;
+    if &binary[..6] != magic || binary[6..8] != [0, 2] {
+        bail!("No LUKS2 header at offset {}", offset);
+    }
+    if u64_at(&binary, 256) != offset {
+        bail!("LUKS2 header at offset {} claims another offset", offset);
+    }
+
+    let len = u64_at(&binary, 8);
+    if len <= LUKS2_BINARY_LEN as u64 || len > LUKS2_MAX_HEADER_LEN {
+        bail!("Invalid LUKS2 header size {}", len);
+    }
+    let mut header = vec![0; len as usize];
+    backend.read_at(&mut header, offset)?;
+
+    let algorithm = c_string(&header[72..104]);
+    if algorithm != "sha256" {
+        bail!("Unsupported LUKS2 checksum {:?}", algorithm);
+    }
+    let checksum = header[448..480].to_vec();
+    header[448..512].fill(0);
+    if Sha256::digest(&header).as_slice() != checksum {
+        bail!("Bad checksum on the LUKS2 header at offset {}", offset);
+    }
+
+    let json = &header[LUKS2_BINARY_LEN..];
+    let end = json.iter().position(|&b| b == 0).unwrap_or(json.len());
+    Ok(json[..end].to_vec())
+}
+
+/// The parts of the LUKS2 JSON metadata we use, numbers that may not fit
+/// in JSON's doubles are strings.
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    keyslots: BTreeMap<String, JsonKeyslot>,
+    segments: BTreeMap<String, JsonSegment>,
+    digests: BTreeMap<String, JsonDigest>,
+    config: JsonConfig,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonKeyslot {
+    #[serde(rename = "type")]
+    kind: String,
+    key_size: usize,
+    area: JsonArea,
+    kdf: JsonKdf,
+    af: JsonAf,
+    /// 0 ignores the keyslot unless it's asked for, 2 tries it first
+    priority: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonArea {
+    #[serde(rename = "type")]
+    kind: String,
+    offset: String,
+    encryption: String,
+    key_size: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum JsonKdf {
+    Pbkdf2 {
+        hash: String,
+        iterations: u32,
+        salt: String,
+    },
+    Argon2i {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+    Argon2id {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonAf {
+    #[serde(rename = "type")]
+    kind: String,
+    stripes: usize,
+    hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonSegment {
+    #[serde(rename = "type")]
+    kind: String,
+    offset: String,
+    size: String,
+    iv_tweak: String,
+    encryption: String,
+    sector_size: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonDigest {
+    #[serde(rename = "type")]
+    kind: String,
+    keyslots: Vec<String>,
+    segments: Vec<String>,
+    hash: String,
+    iterations: u32,
+    salt: String,
+    digest: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonConfig {
+    requirements: Option<JsonRequirements>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRequirements {
+    #[serde(default)]
+    mandatory: Vec<String>,
+}
+
+impl Metadata {
+    fn header(&self) -> Result<Header> {
+        if let Some(requirements) = &self.config.requirements {
+            if !requirements.mandatory.is_empty() {
+                bail!(
+                    "Volume needs {:?}, e.g. it is being reencrypted",
+                    requirements.mandatory
+                );
+            }
+        }
+
+        let (segment_name, segment) = match self.segments.iter().collect::<Vec<_>>()[..] {
+            [segment] => segment,
+            _ => bail!("Only volumes with a single segment are supported"),
+        };
+        if segment.kind != "crypt" {
+            bail!("Unsupported segment type {:?}", segment.kind);
+        }
+        check_cipher(&segment.encryption)?;
+        if number(&segment.iv_tweak)? != 0 {
+            bail!("Segments with an IV tweak are not supported");
+        }
+        if !segment.sector_size.is_power_of_two() || !(512..=4096).contains(&segment.sector_size) {
+            bail!("Invalid sector size {}", segment.sector_size);
+        }
+
+        let mut keyslots = Vec::new();
+        for (name, slot) in &self.keyslots {
+            if slot.kind != "luks2" || slot.priority == Some(0) {
+                continue;
+            }
+
+            let keyslot = self
+                .keyslot(name, slot, segment_name)
+                .with_context(|| format!("Keyslot {}", name))?;
+            keyslots.push((slot.priority.unwrap_or(1), keyslot));
+        }
+        // Higher priorities first, stable so ties stay in order
+        keyslots.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
+
+        Ok(Header {
+            version: 2,
+            payload_offset: number(&segment.offset)?,
+            payload_len: match segment.size.as_str() {
+                "dynamic" => None,
+                size => Some(number(size)?),
+            },
+            sector_size: segment.sector_size,
+            keyslots: keyslots.into_iter().map(|(_, slot)| slot).collect(),
+        })
+    }
+
+    fn keyslot(&self, name: &str, slot: &JsonKeyslot, segment: &str) -> Result<Keyslot> {
+        if slot.area.kind != "raw" {
+            bail!("Unsupported area type {:?}", slot.area.kind);
+        }
+        check_cipher(&slot.area.encryption)?;
+        if slot.af.kind != "luks1" {
+            bail!("Unsupported anti-forensic splitter {:?}", slot.af.kind);
+        }
+
+        let digest = self
+            .digests
+            .values()
+            .find(|d| {
+                d.keyslots.iter().any(|k| k == name) && d.segments.iter().any(|s| s == segment)
+            })
+            .context("No digest for the keyslot")?;
+        if digest.kind != "pbkdf2" {
+            bail!("Unsupported digest type {:?}", digest.kind);
+        }
+
+        let kdf = match &slot.kdf {
+            JsonKdf::Pbkdf2 {
+                hash,
+                iterations,
+                salt,
+            } => Kdf::Pbkdf2 {
+                hash: Hash::parse(hash)?,
+                iterations: *iterations,
+                salt: base64(salt)?,
+            },
+            JsonKdf::Argon2i {
+                time,
+                memory,
+                cpus,
+                salt,
+            } => Kdf::Argon2 {
+                algorithm: Algorithm::Argon2i,
+                time: *time,
+                memory: *memory,
+                lanes: *cpus,
+                salt: base64(salt)?,
+            },
+            JsonKdf::Argon2id {
+                time,
+                memory,
+                cpus,
+                salt,
+            } => Kdf::Argon2 {
+                algorithm: Algorithm::Argon2id,
+                time: *time,
+                memory: *memory,
+                lanes: *cpus,
+                salt: base64(salt)?,
+            },
+        };
+
+        Ok(Keyslot {
+            name: name.to_string(),
+            kdf,
+            kdf_len: slot.area.key_size,
+            offset: number(&slot.area.offset)?,
+            stripes: slot.af.stripes,
+            af_hash: Hash::parse(&slot.af.hash)?,
+            key_len: slot.key_size,
+            digest: Digest {
+                hash: Hash::parse(&digest.hash)?,
+                iterations: digest.iterations,
+                salt: base64(&digest.salt)?,
+                digest: base64(&digest.digest)?,
+            },
+        })
+    }
+}
+
+fn number(s: &str) -> Result<u64> {
+    s.parse()
+        .with_context(|| format!("Invalid number {:?} in LUKS2 metadata", s))
+}
+
+fn base64(s: &str) -> Result<Vec<u8>> {
+    Base64::decode_vec(s).map_err(|e| anyhow!("Invalid base64 in LUKS2 metadata: {}", e))
+}
+
+/// A NUL padded string of a LUKS1 header.
+fn c_string(buf: &[u8]) -> String {
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).into_owned()
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(buf[at..at + 8].try_into().unwrap())
+}
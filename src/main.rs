@@ -1,15 +1,44 @@
-use clap::Parser;
-use nbd::tcp::start_tcp_server;
-use nbd::{self, unix::start_unix_socket_server, Export};
-use std::path::Path;
+use clap::{ArgEnum, Parser, Subcommand};
+use nbd::admin::{self, serve_admin};
+use nbd::config::{self, Config, Listener};
+use nbd::consts::{DEFAULT_ADMIN_SOCKET, DEFAULT_SHUTDOWN_GRACE_SECS};
+use nbd::metrics::serve_metrics;
+use nbd::stdio::{serve_socket_fd, serve_stdio};
+use nbd::systemd::{self, InheritedListener};
+use nbd::tcp::{serve_tcp_listener, start_tcp_server, Keepalive};
+use nbd::unix::serve_unix_listener;
+use nbd::{self, unix::start_unix_socket_server, Export, Server, ServerOptions};
+use serde_json::{json, Value};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::prelude::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+
+const UNIX_SOCKET_PATH: &str = "/tmp/nbd.sock";
 
 #[derive(Parser, Clone)]
-#[clap(version = "0.0.1")]
+#[clap(
+    version = "0.0.1",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// The file we want to export
-    file: String,
+    #[clap(required_unless_present = "config", conflicts_with = "config")]
+    file: Option<String>,
 
     /// The name of the export, empty by default
     #[clap(default_value = "")]
@@ -19,50 +48,472 @@ struct Args {
     #[clap(default_value = "")]
     description: String,
 
+    /// Read listeners, TLS and exports from a TOML file instead of the
+    /// command line
+    #[clap(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
     /// Whether to use a UNIX socket (additionally) along with the TCP socket
     /// by default uses /tmp/nbd.sock, in the future it will be configurable
-    #[clap(long)]
+    #[clap(long, conflicts_with = "config")]
     unix: bool,
+
+    /// Serve on an inherited listening socket (TCP or UNIX) instead of binding
+    /// one, can be repeated. Sockets passed by systemd socket activation
+    /// (LISTEN_FDS) are picked up automatically, and can't be combined with it
+    #[clap(long = "fd", value_name = "FD")]
+    fds: Vec<RawFd>,
+
+    /// Serve a single session over stdin/stdout and exit when it ends,
+    /// e.g. `ssh host nbd --stdio disk.img`
+    #[clap(long, conflicts_with_all = &["unix", "fds", "inetd"])]
+    stdio: bool,
+
+    /// Serve a single session on the connected socket passed as stdin,
+    /// the way inetd starts servers
+    #[clap(long, conflicts_with_all = &["unix", "fds"])]
+    inetd: bool,
+
+    /// Seconds connected clients get to disconnect on shutdown before their
+    /// sockets are closed [default: 10]
+    #[clap(long, value_name = "SECS")]
+    shutdown_grace: Option<u64>,
+
+    /// Seconds clients get to select an export before they are disconnected,
+    /// 0 waits forever [default: 30]
+    #[clap(long, value_name = "SECS")]
+    handshake_timeout: Option<u64>,
+
+    /// Disconnect clients that send no request for this many seconds
+    #[clap(long, value_name = "SECS")]
+    idle_timeout: Option<u64>,
+
+    /// Disconnect clients that leave replies unread for this many seconds,
+    /// 0 waits forever [default: 60]
+    #[clap(long, value_name = "SECS")]
+    send_timeout: Option<u64>,
+
+    /// Enable TCP keepalive, probing connections idle for this many seconds
+    #[clap(long, value_name = "SECS")]
+    keepalive: Option<u32>,
+
+    /// Drop TCP clients that leave sent data unacknowledged for this many
+    /// seconds (TCP_USER_TIMEOUT)
+    #[clap(long, value_name = "SECS")]
+    tcp_user_timeout: Option<u64>,
+
+    /// Log more, can be repeated (-v debug, -vv trace). RUST_LOG takes
+    /// precedence when set
+    #[clap(short, long, parse(from_occurrences), conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Log less, can be repeated (-q warnings, -qq errors, -qqq nothing)
+    #[clap(short, long, parse(from_occurrences))]
+    quiet: u8,
+
+    /// Serve Prometheus metrics over HTTP on this address, e.g. 127.0.0.1:9100
+    #[clap(long, value_name = "ADDR")]
+    metrics: Option<SocketAddr>,
+
+    /// Log output format
+    #[clap(long, arg_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Accept admin commands (see `nbd ctl`) on this UNIX socket
+    #[clap(long, value_name = "PATH")]
+    admin_socket: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Clone)]
+enum Command {
+    /// Manage a running server through its admin socket
+    Ctl(CtlArgs),
+}
+
+#[derive(Parser, Clone)]
+struct CtlArgs {
+    /// The server's admin socket
+    #[clap(long, value_name = "PATH", default_value = DEFAULT_ADMIN_SOCKET)]
+    socket: PathBuf,
+
+    #[clap(subcommand)]
+    command: CtlCommand,
+}
+
+#[derive(Subcommand, Clone)]
+enum CtlCommand {
+    /// List connected clients with their export and counters
+    Connections,
+    /// List the exports offered to new clients
+    Exports,
+    /// Add a file export
+    AddExport {
+        name: String,
+        path: PathBuf,
+        #[clap(long, default_value = "")]
+        description: String,
+        #[clap(long)]
+        read_only: bool,
+    },
+    /// Stop offering an export, connected clients keep using it
+    RemoveExport { name: String },
+    /// Disconnect a client, by the id listed by `connections`
+    Kick { id: u64 },
+    /// Make an export read-only or writable for new clients
+    SetReadOnly {
+        name: String,
+        #[clap(parse(try_from_str))]
+        read_only: bool,
+    },
+    /// Flush an export to disk, or every export
+    Flush { name: Option<String> },
+    /// Change the size of an export, connected clients see it right away
+    Resize { name: String, size: u64 },
+    /// Start tracking writes to an export in a dirty bitmap
+    CreateBitmap { name: String, bitmap: String },
+    /// Forget the writes recorded in a dirty bitmap, e.g. after a backup
+    ClearBitmap { name: String, bitmap: String },
+    /// Reload exports from the config file
+    Reload,
+}
+
+#[derive(ArgEnum, Clone, Copy, PartialEq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Logs go to stderr, stdout is reserved for the protocol in --stdio mode.
+fn init_logging(args: &Args) {
+    let level = match args.verbose as i8 - args.quiet as i8 {
+        i8::MIN..=-3 => LevelFilter::OFF,
+        -2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::default().add_directive(level.into()));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr);
+
+    match args.log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).init(),
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    if !Path::exists(Path::new(&args.file)) {
-        panic!("{} does not exist!", args.file);
+    if let Some(Command::Ctl(ctl)) = args.command {
+        return run_ctl(ctl);
     }
 
-    let export = Arc::new(RwLock::new(Export::init_export(
-        args.file,
-        args.name,
-        args.description,
-    )?));
+    init_logging(&args);
+
+    let (exports, mut options, listeners, configured, metrics, admin_socket) = match &args.config {
+        Some(path) => {
+            let config = Config::load(path)?;
+            (
+                config.exports()?,
+                config.server_options()?,
+                config.listeners(),
+                config.listeners.clone(),
+                config.server.metrics,
+                config.server.admin_socket.clone(),
+            )
+        }
+        None => {
+            // clap makes sure we have a file when there is no config
+            let file = args.file.clone().unwrap_or_default();
+            if !Path::exists(Path::new(&file)) {
+                panic!("{} does not exist!", file);
+            }
+
+            let export = Export::init_export(file, args.name.clone(), args.description.clone())?;
+            let listener = if args.unix {
+                Listener::Unix {
+                    path: PathBuf::from(UNIX_SOCKET_PATH),
+                    oldstyle: None,
+                }
+            } else {
+                Listener::Tcp {
+                    address: format!("0.0.0.0:{}", nbd::consts::NBD_DEFAULT_PORT).parse()?,
+                    oldstyle: None,
+                }
+            };
+
+            let options = ServerOptions {
+                shutdown_grace: Duration::from_secs(DEFAULT_SHUTDOWN_GRACE_SECS),
+                ..ServerOptions::default()
+            };
+            (
+                vec![export],
+                options,
+                vec![listener],
+                Vec::new(),
+                None,
+                None,
+            )
+        }
+    };
+
+    if let Some(secs) = args.shutdown_grace {
+        options.shutdown_grace = Duration::from_secs(secs);
+    }
+    if let Some(secs) = args.handshake_timeout {
+        options.handshake_timeout = (secs > 0).then(|| Duration::from_secs(secs));
+    }
+    if let Some(secs) = args.idle_timeout {
+        options.idle_timeout = Some(Duration::from_secs(secs));
+    }
+    if let Some(secs) = args.send_timeout {
+        options.send_timeout = (secs > 0).then(|| Duration::from_secs(secs));
+    }
+    if let Some(secs) = args.keepalive {
+        options.keepalive = Some(Keepalive::new(secs));
+    }
+    if let Some(secs) = args.tcp_user_timeout {
+        options.user_timeout = Some(Duration::from_secs(secs));
+    }
+    let server = Arc::new(Server::with_options(exports, options));
+
+    if args.stdio {
+        return Ok(serve_stdio(&server)?);
+    }
+
+    if args.inetd {
+        return Ok(serve_socket_fd(&server, io::stdin().as_raw_fd())?);
+    }
 
     let stop_server = Arc::new(AtomicBool::new(false));
     let clone_stop_server = Arc::clone(&stop_server);
-    ctrlc::set_handler(move || {
-        clone_stop_server.store(true, std::sync::atomic::Ordering::SeqCst);
-    })?;
-
-    if args.unix {
-        println!("Listening on UNIX socket {}", "/tmp/nbd.sock");
-        start_unix_socket_server(
-            &export.read().unwrap(),
-            Path::new("/tmp/nbd.sock"),
-            &stop_server,
-        )?;
+    let clone_server = Arc::clone(&server);
+    let config_path = args.config.clone();
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            // SIGHUP reloads the exports, without a config file there is
+            // nothing to reload and it stops the server like it used to
+            if let (SIGHUP, Some(path)) = (signal, &config_path) {
+                reload(&clone_server, path);
+                continue;
+            }
+
+            if let Err(e) = systemd::notify("STOPPING=1") {
+                warn!("Failed to notify service manager: {}", e);
+            }
+            clone_stop_server.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    });
+
+    if let Some(path) = args.admin_socket.clone().or(admin_socket) {
+        let server = Arc::clone(&server);
+        let stop = Arc::clone(&stop_server);
+        let config = args.config.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve_admin(&server, &path, config, &stop) {
+                error!("Admin socket failed: {:#}", e);
+            }
+        });
+    }
+
+    if let Some(address) = args.metrics.or(metrics) {
+        let server = Arc::clone(&server);
+        let stop = Arc::clone(&stop_server);
+        thread::spawn(move || {
+            if let Err(e) = serve_metrics(&server, address, &stop) {
+                error!("Metrics listener failed: {}", e);
+            }
+        });
+    }
+
+    let mut fds = systemd::listen_fds()?;
+    if !fds.is_empty() && !args.fds.is_empty() {
+        // The same socket could be passed both ways and served twice
+        return Err("--fd can't be used together with socket activation (LISTEN_FDS)".into());
+    }
+    fds.extend(args.fds);
+    if !fds.is_empty() {
+        let inherited = fds
+            .into_iter()
+            .map(systemd::listener_from_fd)
+            .collect::<Result<Vec<_>, _>>()?;
+        let listeners = match_inherited(inherited, configured);
+
+        systemd::notify("READY=1")?;
+        serve_inherited(&server, listeners, &stop_server);
 
         return Ok(());
     }
 
-    // Make backends for each export selectable
-    println!("Listening on port {}", nbd::consts::NBD_DEFAULT_PORT);
-    let export = export.read().unwrap();
+    systemd::notify("READY=1")?;
+    serve(&server, listeners, &stop_server);
+
+    Ok(())
+}
+
+/// Runs `nbd ctl`, printing the result as JSON.
+fn run_ctl(args: CtlArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let request = match args.command {
+        CtlCommand::Connections => json!({ "command": "list-connections" }),
+        CtlCommand::Exports => json!({ "command": "list-exports" }),
+        CtlCommand::AddExport {
+            name,
+            path,
+            description,
+            read_only,
+        } => json!({
+            "command": "add-export",
+            "export": {
+                "name": name,
+                "path": path,
+                "description": description,
+                "read_only": read_only,
+            },
+        }),
+        CtlCommand::RemoveExport { name } => json!({ "command": "remove-export", "name": name }),
+        CtlCommand::Kick { id } => json!({ "command": "kick", "id": id }),
+        CtlCommand::SetReadOnly { name, read_only } => {
+            json!({ "command": "set-read-only", "name": name, "read_only": read_only })
+        }
+        CtlCommand::Flush { name } => json!({ "command": "flush", "name": name }),
+        CtlCommand::Resize { name, size } => {
+            json!({ "command": "resize", "name": name, "size": size })
+        }
+        CtlCommand::CreateBitmap { name, bitmap } => {
+            json!({ "command": "create-bitmap", "name": name, "bitmap": bitmap })
+        }
+        CtlCommand::ClearBitmap { name, bitmap } => {
+            json!({ "command": "clear-bitmap", "name": name, "bitmap": bitmap })
+        }
+        CtlCommand::Reload => json!({ "command": "reload" }),
+    };
 
-    start_tcp_server(
-        &export,
-        format!("0.0.0.0:{}", nbd::consts::NBD_DEFAULT_PORT).parse()?,
-        &stop_server,
-    )?;
+    let result = admin::request(&args.socket, &request)?;
+    if result != Value::Null {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    }
 
     Ok(())
 }
+
+/// Reloads the exports on SIGHUP, letting systemd know while we are at it.
+fn reload(server: &Server, path: &Path) {
+    info!("Reloading exports from {}", path.display());
+    if let Err(e) = systemd::notify("RELOADING=1") {
+        warn!("Failed to notify service manager: {}", e);
+    }
+
+    if let Err(e) = config::reload(server, path) {
+        error!("Failed to reload, keeping the current exports: {:#}", e);
+    }
+
+    if let Err(e) = systemd::notify("READY=1") {
+        warn!("Failed to notify service manager: {}", e);
+    }
+}
+
+/// Binds and serves every listener on its own thread until we are stopped.
+fn serve(server: &Arc<Server>, listeners: Vec<Listener>, stop: &Arc<AtomicBool>) {
+    let handles = listeners
+        .into_iter()
+        .map(|listener| {
+            let server = Arc::clone(server);
+            let stop = Arc::clone(stop);
+            thread::spawn(move || {
+                let result = match &listener {
+                    Listener::Tcp { address, oldstyle } => {
+                        info!("Listening on {}", address);
+                        start_tcp_server(&server, *address, oldstyle.as_deref(), &stop)
+                    }
+                    Listener::Unix { path, oldstyle } => {
+                        info!("Listening on UNIX socket {}", path.display());
+                        start_unix_socket_server(&server, path, oldstyle.as_deref(), &stop)
+                    }
+                };
+
+                if let Err(e) = result {
+                    error!("Listener {:?} failed: {}", listener, e);
+                }
+            })
+        })
+        .collect();
+
+    join(handles);
+}
+
+/// Pairs inherited sockets with the configured listeners they stand for,
+/// whose settings they are served with. Configured listeners without a
+/// socket aren't bound, there is no telling whether they were meant to be.
+fn match_inherited(
+    inherited: Vec<InheritedListener>,
+    mut configured: Vec<Listener>,
+) -> Vec<(InheritedListener, Option<String>)> {
+    let listeners = inherited
+        .into_iter()
+        .map(
+            |socket| match configured.iter().position(|l| socket.is(l)) {
+                Some(i) => {
+                    let oldstyle = configured.remove(i).oldstyle().map(str::to_owned);
+                    (socket, oldstyle)
+                }
+                None => (socket, None),
+            },
+        )
+        .collect();
+
+    for listener in configured {
+        warn!(
+            "Ignoring listener {:?}, serving the inherited sockets instead",
+            listener
+        );
+    }
+
+    listeners
+}
+
+/// Serves every inherited listener on its own thread until we are stopped.
+fn serve_inherited(
+    server: &Arc<Server>,
+    listeners: Vec<(InheritedListener, Option<String>)>,
+    stop: &Arc<AtomicBool>,
+) {
+    let handles = listeners
+        .into_iter()
+        .map(|listener| {
+            let server = Arc::clone(server);
+            let stop = Arc::clone(stop);
+            thread::spawn(move || {
+                let (listener, oldstyle) = listener;
+                let result = match listener {
+                    InheritedListener::Tcp(listener) => {
+                        info!("Listening on inherited socket {:?}", listener.local_addr());
+                        serve_tcp_listener(&server, listener, oldstyle.as_deref(), &stop)
+                    }
+                    InheritedListener::Unix(listener) => {
+                        info!("Listening on inherited socket {:?}", listener.local_addr());
+                        serve_unix_listener(&server, listener, oldstyle.as_deref(), &stop)
+                    }
+                };
+
+                if let Err(e) = result {
+                    error!("error: {}", e);
+                }
+            })
+        })
+        .collect();
+
+    join(handles);
+}
+
+fn join(handles: Vec<thread::JoinHandle<()>>) {
+    handles.into_iter().for_each(|h| {
+        if h.join().is_err() {
+            error!("Thread panicked");
+        }
+    });
+}
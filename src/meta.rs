@@ -0,0 +1,48 @@
+use std::{io, sync::Arc};
+
+use crate::{backend::Extent, bitmap::DirtyBitmap, Export};
+
+pub const BASE_ALLOCATION: &str = "base:allocation";
+pub const DIRTY_BITMAP_PREFIX: &str = "qemu:dirty-bitmap:";
+
+/// A metadata context clients can query with `NBD_CMD_BLOCK_STATUS`.
+#[derive(Debug, Clone)]
+pub enum Context {
+    /// Which parts of the export are holes or read as zeroes
+    Allocation,
+    /// Which parts of the export were written since the bitmap was cleared
+    DirtyBitmap(Arc<DirtyBitmap>),
+}
+
+impl Context {
+    /// The contexts `export` offers, the position in the list plus one is
+    /// the id sent to clients.
+    pub fn available(export: &Export) -> Vec<Context> {
+        let mut contexts = vec![Context::Allocation];
+        contexts.extend(export.bitmaps().into_iter().map(Context::DirtyBitmap));
+
+        contexts
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Context::Allocation => BASE_ALLOCATION.to_string(),
+            Context::DirtyBitmap(bitmap) => format!("{}{}", DIRTY_BITMAP_PREFIX, bitmap.name()),
+        }
+    }
+
+    /// Whether a client's query asks for the context. Listing also accepts a
+    /// prefix ending with a colon, e.g. `base:` or `qemu:dirty-bitmap:`.
+    pub fn matches(&self, query: &str, list: bool) -> bool {
+        let name = self.name();
+        query == name || (list && query.ends_with(':') && name.starts_with(query))
+    }
+
+    /// The state of a range of the export in this context.
+    pub fn extents(&self, export: &Export, offset: u64, len: u64) -> io::Result<Vec<Extent>> {
+        match self {
+            Context::Allocation => export.backend().extents(offset, len),
+            Context::DirtyBitmap(bitmap) => Ok(bitmap.extents(offset, len)),
+        }
+    }
+}
@@ -0,0 +1,575 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::sleep,
+    time::Duration,
+};
+
+use anyhow::Result;
+use tracing::{debug, info, warn};
+
+use crate::{connections::Connection, consts::NbdCmd, Server};
+
+const COMMANDS: [(NbdCmd, &str); 9] = [
+    (NbdCmd::Read, "read"),
+    (NbdCmd::Write, "write"),
+    (NbdCmd::Disc, "disc"),
+    (NbdCmd::Flush, "flush"),
+    (NbdCmd::Trim, "trim"),
+    (NbdCmd::Cache, "cache"),
+    (NbdCmd::WriteZeroes, "write_zeroes"),
+    (NbdCmd::BlockStatus, "block_status"),
+    (NbdCmd::Resize, "resize"),
+];
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+// Longest HTTP request we are willing to read
+const MAX_HTTP_REQUEST: usize = 8192;
+
+/// Counters for the whole server, exported in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    exports: Mutex<HashMap<String, Arc<ExportMetrics>>>,
+    handshake_failures: AtomicU64,
+    connections_total: AtomicU64,
+}
+
+impl Metrics {
+    /// Returns the metrics of the export called `name`, creating them on first use.
+    pub fn export(&self, name: &str) -> Arc<ExportMetrics> {
+        let mut exports = self.exports.lock().unwrap();
+        Arc::clone(exports.entry(name.to_owned()).or_default())
+    }
+
+    pub fn connection_accepted(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders every metric, including per-connection ones for `connections`.
+    pub fn render(&self, connections: &[Arc<Connection>]) -> String {
+        let mut out = String::new();
+        let exports: BTreeMap<String, Arc<ExportMetrics>> = self
+            .exports
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, metrics)| (name.clone(), Arc::clone(metrics)))
+            .collect();
+
+        header(
+            &mut out,
+            "nbd_connections_total",
+            "counter",
+            "Connections accepted.",
+        );
+        let _ = writeln!(
+            out,
+            "nbd_connections_total {}",
+            self.connections_total.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "nbd_handshake_failures_total",
+            "counter",
+            "Connections that failed during the handshake.",
+        );
+        let _ = writeln!(
+            out,
+            "nbd_handshake_failures_total {}",
+            self.handshake_failures.load(Ordering::Relaxed)
+        );
+
+        export_metric(
+            &mut out,
+            "nbd_export_active_connections",
+            "gauge",
+            "Connections currently in the transmission phase.",
+            &exports,
+            |m| m.active_connections.load(Ordering::Relaxed),
+        );
+
+        header(
+            &mut out,
+            "nbd_export_requests_total",
+            "counter",
+            "Requests processed, by command.",
+        );
+        for (name, export) in &exports {
+            for (i, (_, command)) in COMMANDS.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "nbd_export_requests_total{{export=\"{}\",command=\"{}\"}} {}",
+                    escape(name),
+                    command,
+                    export.requests[i].load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        export_metric(
+            &mut out,
+            "nbd_export_read_bytes_total",
+            "counter",
+            "Bytes read by clients.",
+            &exports,
+            |m| m.bytes_read.load(Ordering::Relaxed),
+        );
+        export_metric(
+            &mut out,
+            "nbd_export_written_bytes_total",
+            "counter",
+            "Bytes written by clients.",
+            &exports,
+            |m| m.bytes_written.load(Ordering::Relaxed),
+        );
+
+        header(
+            &mut out,
+            "nbd_export_errors_total",
+            "counter",
+            "Requests that failed, by NBD error.",
+        );
+        for (name, export) in &exports {
+            for (errno, count) in export.errors.lock().unwrap().iter() {
+                let _ = writeln!(
+                    out,
+                    "nbd_export_errors_total{{export=\"{}\",error=\"{}\"}} {}",
+                    escape(name),
+                    errno_name(*errno),
+                    count
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "nbd_export_request_duration_seconds",
+            "histogram",
+            "Time spent processing requests.",
+        );
+        for (name, export) in &exports {
+            export.latency.render(&mut out, name);
+        }
+
+        export_metric(
+            &mut out,
+            "nbd_export_throttled_requests_total",
+            "counter",
+            "Requests delayed by I/O limits.",
+            &exports,
+            |m| m.throttled_requests.load(Ordering::Relaxed),
+        );
+        header(
+            &mut out,
+            "nbd_export_throttled_seconds_total",
+            "counter",
+            "Time requests spent delayed by I/O limits.",
+        );
+        for (name, export) in &exports {
+            let _ = writeln!(
+                out,
+                "nbd_export_throttled_seconds_total{{export=\"{}\"}} {}",
+                escape(name),
+                export.throttled_us.load(Ordering::Relaxed) as f64 / 1_000_000.0
+            );
+        }
+
+        connection_metric(
+            &mut out,
+            "nbd_connection_requests_total",
+            "Requests processed by a live connection.",
+            connections,
+            |s| s.requests.load(Ordering::Relaxed),
+        );
+        connection_metric(
+            &mut out,
+            "nbd_connection_read_bytes_total",
+            "Bytes read by a live connection.",
+            connections,
+            |s| s.bytes_read.load(Ordering::Relaxed),
+        );
+        connection_metric(
+            &mut out,
+            "nbd_connection_written_bytes_total",
+            "Bytes written by a live connection.",
+            connections,
+            |s| s.bytes_written.load(Ordering::Relaxed),
+        );
+        connection_metric(
+            &mut out,
+            "nbd_connection_errors_total",
+            "Failed requests of a live connection.",
+            connections,
+            |s| s.errors.load(Ordering::Relaxed),
+        );
+
+        out
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ExportMetrics {
+    active_connections: AtomicU64,
+    requests: [AtomicU64; COMMANDS.len()],
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    errors: Mutex<BTreeMap<u32, u64>>,
+    latency: Histogram,
+    throttled_requests: AtomicU64,
+    throttled_us: AtomicU64,
+}
+
+impl ExportMetrics {
+    /// Counts a connection as active until the returned guard is dropped.
+    pub fn connection_opened(&self) -> ActiveConnection<'_> {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self)
+    }
+
+    pub fn record_request(&self, cmd: NbdCmd, read: u64, written: u64, latency: Duration) {
+        self.requests[cmd as usize].fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(read, Ordering::Relaxed);
+        self.bytes_written.fetch_add(written, Ordering::Relaxed);
+        self.latency.observe(latency);
+    }
+
+    pub fn record_error(&self, errno: u32) {
+        *self.errors.lock().unwrap().entry(errno).or_default() += 1;
+    }
+
+    /// Counts a request held back by I/O limits for `delay`.
+    pub fn record_throttled(&self, delay: Duration) {
+        self.throttled_requests.fetch_add(1, Ordering::Relaxed);
+        self.throttled_us
+            .fetch_add(delay.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct ActiveConnection<'a>(&'a ExportMetrics);
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters of a single connection.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    pub requests: AtomicU64,
+    pub bytes_read: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+impl ConnectionStats {
+    pub fn record_request(&self, read: u64, written: u64) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(read, Ordering::Relaxed);
+        self.bytes_written.fetch_add(written, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *bound {
+                self.buckets[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, export: &str) {
+        let name = "nbd_export_request_duration_seconds";
+        let export = escape(export);
+        let count = self.count.load(Ordering::Relaxed);
+
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{export=\"{}\",le=\"{}\"}} {}",
+                name,
+                export,
+                bound,
+                self.buckets[i].load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{export=\"{}\",le=\"+Inf\"}} {}",
+            name, export, count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{{export=\"{}\"}} {}",
+            name,
+            export,
+            self.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count{{export=\"{}\"}} {}", name, export, count);
+    }
+}
+
+/// Serves `GET /metrics` for `server` until `stop` is set.
+pub fn serve_metrics(server: &Server, address: SocketAddr, stop: &AtomicBool) -> Result<()> {
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    info!("Serving metrics on http://{}/metrics", address);
+
+    for conn in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+
+        match conn {
+            Ok(stream) => {
+                if let Err(e) = handle_http(server, stream) {
+                    debug!("Failed to serve metrics: {}", e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                sleep(Duration::from_millis(100));
+            }
+            Err(e) => warn!("error: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_http(server: &Server, mut stream: TcpStream) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // We only care about the request line, but read the whole header so
+    // the client doesn't see a reset
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_HTTP_REQUEST {
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            server.metrics().render(&server.connections().list()),
+        ),
+        (Some("GET"), _) => ("404 Not Found", String::from("Not found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            String::from("Method not allowed\n"),
+        ),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn export_metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    exports: &BTreeMap<String, Arc<ExportMetrics>>,
+    value: impl Fn(&ExportMetrics) -> u64,
+) {
+    header(out, name, kind, help);
+    for (export, metrics) in exports {
+        let _ = writeln!(
+            out,
+            "{}{{export=\"{}\"}} {}",
+            name,
+            escape(export),
+            value(metrics)
+        );
+    }
+}
+
+fn connection_metric(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    connections: &[Arc<Connection>],
+    value: impl Fn(&ConnectionStats) -> u64,
+) {
+    header(out, name, "counter", help);
+    for conn in connections {
+        let _ = writeln!(
+            out,
+            "{}{{client=\"{}\",export=\"{}\"}} {}",
+            name,
+            escape(conn.addr()),
+            escape(&conn.export().unwrap_or_default()),
+            value(conn.stats())
+        );
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn errno_name(errno: u32) -> String {
+    use crate::consts::*;
+
+    match errno {
+        NBD_EPERM => "EPERM".into(),
+        NBD_EIO => "EIO".into(),
+        NBD_ENOMEM => "ENOMEM".into(),
+        NBD_EINVAL => "EINVAL".into(),
+        NBD_ENOSPC => "ENOSPC".into(),
+        NBD_EOVERFLOW => "EOVERFLOW".into(),
+        NBD_ENOTSUP => "ENOTSUP".into(),
+        NBD_ESHUTDOWN => "ESHUTDOWN".into(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::{NBD_EINVAL, NBD_EIO};
+
+    /// The lines of `out` starting with `prefix`.
+    fn lines<'a>(out: &'a str, prefix: &str) -> Vec<&'a str> {
+        out.lines().filter(|l| l.starts_with(prefix)).collect()
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.connection_accepted();
+        metrics.connection_accepted();
+        metrics.handshake_failed();
+
+        let disk = metrics.export("disk");
+        let active = disk.connection_opened();
+        disk.record_request(NbdCmd::Read, 4096, 0, Duration::from_micros(200));
+        disk.record_request(NbdCmd::Write, 0, 512, Duration::from_millis(2));
+        disk.record_error(NBD_EIO);
+        disk.record_error(NBD_EIO);
+        disk.record_error(0x4242);
+        disk.record_throttled(Duration::from_millis(1500));
+        assert!(Arc::ptr_eq(&disk, &metrics.export("disk")));
+
+        let out = metrics.render(&[]);
+        assert!(out.contains("nbd_connections_total 2\n"));
+        assert!(out.contains("nbd_handshake_failures_total 1\n"));
+        assert!(out.contains("nbd_export_active_connections{export=\"disk\"} 1\n"));
+        assert!(out.contains("nbd_export_requests_total{export=\"disk\",command=\"read\"} 1\n"));
+        assert!(out.contains("nbd_export_requests_total{export=\"disk\",command=\"trim\"} 0\n"));
+        assert!(out.contains("nbd_export_read_bytes_total{export=\"disk\"} 4096\n"));
+        assert!(out.contains("nbd_export_written_bytes_total{export=\"disk\"} 512\n"));
+        assert_eq!(
+            lines(&out, "nbd_export_errors_total{"),
+            [
+                "nbd_export_errors_total{export=\"disk\",error=\"EIO\"} 2",
+                "nbd_export_errors_total{export=\"disk\",error=\"16962\"} 1",
+            ]
+        );
+        assert!(out.contains("nbd_export_throttled_requests_total{export=\"disk\"} 1\n"));
+        assert!(out.contains("nbd_export_throttled_seconds_total{export=\"disk\"} 1.5\n"));
+
+        drop(active);
+        let out = metrics.render(&[]);
+        assert!(out.contains("nbd_export_active_connections{export=\"disk\"} 0\n"));
+
+        // Every metric is described once
+        for name in lines(&out, "# TYPE ") {
+            let name = name.split(' ').nth(2).unwrap();
+            assert_eq!(lines(&out, &format!("# HELP {} ", name)).len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_histogram() {
+        let metrics = Metrics::default();
+        let disk = metrics.export("disk");
+        for latency in [50, 100, 700, 20_000, 10_000_000] {
+            disk.record_request(NbdCmd::Flush, 0, 0, Duration::from_micros(latency));
+        }
+
+        // Buckets are cumulative
+        let out = metrics.render(&[]);
+        let bucket = |le: &str| {
+            let prefix = format!(
+                "nbd_export_request_duration_seconds_bucket{{export=\"disk\",le=\"{}\"}} ",
+                le
+            );
+            lines(&out, &prefix)[0][prefix.len()..]
+                .parse::<u64>()
+                .unwrap()
+        };
+        assert_eq!(bucket("0.0001"), 2);
+        assert_eq!(bucket("0.001"), 3);
+        assert_eq!(bucket("0.05"), 4);
+        assert_eq!(bucket("5"), 4);
+        assert_eq!(bucket("+Inf"), 5);
+        assert!(out.contains("nbd_export_request_duration_seconds_count{export=\"disk\"} 5\n"));
+        assert!(out.contains("nbd_export_request_duration_seconds_sum{export=\"disk\"} 10.02085\n"));
+    }
+
+    #[test]
+    fn test_escape() {
+        let metrics = Metrics::default();
+        metrics
+            .export("a \"quoted\"\\name\n")
+            .record_error(NBD_EINVAL);
+
+        let out = metrics.render(&[]);
+        assert!(out.contains(
+            "nbd_export_errors_total{export=\"a \\\"quoted\\\"\\\\name\\n\",error=\"EINVAL\"} 1\n"
+        ));
+        assert_eq!(out.lines().filter(|l| l.is_empty()).count(), 0);
+    }
+}
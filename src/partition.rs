@@ -0,0 +1,382 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Context, Result};
+
+use crate::backend::Backend;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES: usize = 446;
+const MBR_PROTECTIVE: u8 = 0xee;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+// Extended boot records beyond this are assumed to be a loop in the chain
+const MAX_EBRS: u32 = 128;
+// Entries of a sane GPT fit in 1MiB, the usual table is 16KiB
+const MAX_GPT_ENTRIES_SIZE: u64 = 1024 * 1024;
+
+/// A partition found in a partition table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// Numbered from 1 like the kernel does, logical MBR partitions
+    /// start at 5 and are numbered by their place in the EBR chain
+    pub number: u32,
+    pub offset: u64,
+    pub len: u64,
+    /// The GPT partition name, if it has one
+    pub name: Option<String>,
+}
+
+/// Reads the MBR or GPT partition table at the start of a backend.
+pub fn detect(backend: &dyn Backend) -> Result<Vec<Partition>> {
+    let sector = backend.block_size().map_or(512, |b| b.minimum.max(512)) as u64;
+
+    let mut mbr = [0; 512];
+    backend
+        .read_at(&mut mbr, 0)
+        .context("Failed to read the partition table")?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        bail!("No partition table found");
+    }
+
+    let primary = mbr_entries(&mbr);
+    if primary.iter().any(|e| e.kind == MBR_PROTECTIVE) {
+        // GPT uses 4KiB sectors on disks with 4KiB logical sectors, whatever
+        // a file being exported says
+        for sector in [sector, 4096] {
+            if let Some(partitions) = gpt(backend, sector)? {
+                return Ok(partitions);
+            }
+        }
+        bail!("Protective MBR found but no valid GPT header");
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in primary.iter().enumerate() {
+        if entry.kind == 0 || entry.sectors == 0 {
+            continue;
+        }
+
+        if MBR_EXTENDED.contains(&entry.kind) {
+            partitions.extend(logical(backend, sector, entry.start as u64)?);
+            continue;
+        }
+
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            offset: entry.start as u64 * sector,
+            len: entry.sectors as u64 * sector,
+            name: None,
+        });
+    }
+
+    Ok(partitions)
+}
+
+struct MbrEntry {
+    kind: u8,
+    start: u32,
+    sectors: u32,
+}
+
+fn mbr_entries(sector: &[u8; 512]) -> Vec<MbrEntry> {
+    (0..4)
+        .map(|i| {
+            let entry = &sector[MBR_ENTRIES + i * 16..MBR_ENTRIES + (i + 1) * 16];
+            MbrEntry {
+                kind: entry[4],
+                start: u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+                sectors: u32::from_le_bytes(entry[12..16].try_into().unwrap()),
+            }
+        })
+        .collect()
+}
+
+/// Follows the chain of extended boot records starting at `extended`.
+///
+/// The first entry of each EBR is a logical partition relative to the EBR,
+/// the second points at the next EBR relative to the extended partition.
+/// An EBR without a partition still takes up a number.
+fn logical(backend: &dyn Backend, sector: u64, extended: u64) -> Result<Vec<Partition>> {
+    let mut partitions = Vec::new();
+    let mut visited = HashSet::new();
+    let mut ebr = extended;
+
+    for number in 5.. {
+        if number - 5 >= MAX_EBRS {
+            bail!("Too many extended boot records, the chain may be looping");
+        }
+        if !visited.insert(ebr) {
+            bail!("The extended boot record chain loops at sector {}", ebr);
+        }
+
+        let mut buf = [0; 512];
+        backend
+            .read_at(&mut buf, ebr.saturating_mul(sector))
+            .context("Failed to read an extended boot record")?;
+        if buf[510..512] != MBR_SIGNATURE {
+            bail!("Invalid extended boot record at sector {}", ebr);
+        }
+
+        let entries = mbr_entries(&buf);
+        if entries[0].kind != 0 && entries[0].sectors != 0 {
+            partitions.push(Partition {
+                number,
+                offset: (ebr + entries[0].start as u64) * sector,
+                len: entries[0].sectors as u64 * sector,
+                name: None,
+            });
+        }
+
+        match entries[1].start {
+            0 => break,
+            next => ebr = extended + next as u64,
+        }
+    }
+
+    Ok(partitions)
+}
+
+/// Reads the GPT whose header is in the second sector, `None` when there
+/// is no header for this sector size.
+fn gpt(backend: &dyn Backend, sector: u64) -> Result<Option<Vec<Partition>>> {
+    let mut header = [0; 92];
+    backend
+        .read_at(&mut header, sector)
+        .context("Failed to read the GPT header")?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+
+    let le64 = |b: &[u8]| u64::from_le_bytes(b.try_into().unwrap());
+    let le32 = |b: &[u8]| u32::from_le_bytes(b.try_into().unwrap());
+
+    let entries_lba = le64(&header[72..80]);
+    let count = le32(&header[80..84]) as u64;
+    let entry_size = le32(&header[84..88]) as u64;
+    let len = count
+        .checked_mul(entry_size)
+        .filter(|&len| entry_size >= 128 && len <= MAX_GPT_ENTRIES_SIZE);
+    let len = match len {
+        Some(len) => len,
+        None => bail!("Invalid GPT with {} entries of {} bytes", count, entry_size),
+    };
+
+    let mut entries = vec![0; len as usize];
+    backend
+        .read_at(&mut entries, entries_lba.saturating_mul(sector))
+        .context("Failed to read the GPT entries")?;
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks_exact(entry_size as usize).enumerate() {
+        // Unused entries have a zero type GUID
+        if entry[0..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+
+        let first = le64(&entry[32..40]);
+        let last = le64(&entry[40..48]);
+        if last < first {
+            bail!("GPT entry {} ends before it starts", i + 1);
+        }
+
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+        let name = String::from_utf16_lossy(&name);
+
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            offset: first.saturating_mul(sector),
+            len: (last - first + 1).saturating_mul(sector),
+            name: (!name.is_empty()).then_some(name),
+        });
+    }
+
+    Ok(Some(partitions))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    /// A disk image in memory.
+    #[derive(Debug)]
+    struct Image(Vec<u8>);
+
+    impl Backend for Image {
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.0.len() as u64)
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            let start = offset as usize;
+            match self.0.get(start..start + buf.len()) {
+                Some(data) => {
+                    buf.copy_from_slice(data);
+                    Ok(())
+                }
+                None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            }
+        }
+
+        fn write_at(&self, _buf: &[u8], _offset: u64) -> io::Result<()> {
+            unimplemented!()
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn trim(&self, _offset: u64, _len: u64) -> io::Result<()> {
+            unimplemented!()
+        }
+
+        fn write_zeroes(&self, _offset: u64, _len: u64, _may_trim: bool) -> io::Result<()> {
+            unimplemented!()
+        }
+    }
+
+    /// Writes an MBR style table entry into the sector at `lba`.
+    fn entry(image: &mut [u8], lba: u64, slot: usize, kind: u8, start: u32, sectors: u32) {
+        let at = lba as usize * 512 + MBR_ENTRIES + slot * 16;
+        image[at + 4] = kind;
+        image[at + 8..at + 12].copy_from_slice(&start.to_le_bytes());
+        image[at + 12..at + 16].copy_from_slice(&sectors.to_le_bytes());
+        let signature = lba as usize * 512 + 510;
+        image[signature..signature + 2].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    fn partition(number: u32, start: u64, sectors: u64) -> Partition {
+        Partition {
+            number,
+            offset: start * 512,
+            len: sectors * 512,
+            name: None,
+        }
+    }
+
+    #[test]
+    fn test_mbr() {
+        let mut image = vec![0; 1024 * 512];
+        entry(&mut image, 0, 0, 0x83, 2048, 100);
+        // Empty slots are skipped but keep their numbers
+        entry(&mut image, 0, 2, 0x07, 4096, 200);
+
+        assert_eq!(
+            detect(&Image(image)).unwrap(),
+            [partition(1, 2048, 100), partition(3, 4096, 200)]
+        );
+        assert!(detect(&Image(vec![0; 1024])).is_err());
+    }
+
+    #[test]
+    fn test_extended_chain() {
+        let mut image = vec![0; 8192 * 512];
+        entry(&mut image, 0, 0, 0x83, 64, 64);
+        entry(&mut image, 0, 1, 0x05, 1000, 4000);
+        // Three EBRs, the middle one without a partition
+        entry(&mut image, 1000, 0, 0x83, 8, 100);
+        entry(&mut image, 1000, 1, 0x05, 500, 1000);
+        entry(&mut image, 1500, 1, 0x05, 1500, 1000);
+        entry(&mut image, 2500, 0, 0x83, 8, 300);
+
+        assert_eq!(
+            detect(&Image(image)).unwrap(),
+            [
+                partition(1, 64, 64),
+                partition(5, 1008, 100),
+                partition(7, 2508, 300),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_looping_chains() {
+        // An empty EBR pointing back at itself
+        let mut image = vec![0; 4096 * 512];
+        entry(&mut image, 0, 0, 0x05, 1000, 2000);
+        entry(&mut image, 1000, 1, 0x05, 500, 100);
+        entry(&mut image, 1500, 1, 0x05, 500, 100);
+        assert!(detect(&Image(image)).is_err());
+
+        // Three EBRs with partitions, the last pointing at the second
+        let mut image = vec![0; 4096 * 512];
+        entry(&mut image, 0, 0, 0x0f, 1000, 2000);
+        entry(&mut image, 1000, 0, 0x83, 8, 10);
+        entry(&mut image, 1000, 1, 0x05, 500, 100);
+        entry(&mut image, 1500, 0, 0x83, 8, 10);
+        entry(&mut image, 1500, 1, 0x05, 1, 100);
+        entry(&mut image, 1001, 0, 0x83, 8, 10);
+        entry(&mut image, 1001, 1, 0x05, 500, 100);
+        assert!(detect(&Image(image)).is_err());
+
+        // A chain too long to be real, without ever looping
+        let mut image = vec![0; 4096 * 512];
+        entry(&mut image, 0, 0, 0x05, 1000, 3000);
+        for i in 0..MAX_EBRS + 1 {
+            entry(&mut image, 1000 + i as u64, 1, 0x05, i + 1, 1);
+        }
+        assert!(detect(&Image(image)).is_err());
+    }
+
+    /// A protective MBR and a GPT with `count` entries of 128 bytes at LBA 2.
+    fn gpt_image(count: u32, entries: &[(u64, u64, &str)]) -> Vec<u8> {
+        let mut image = vec![0; 4096 * 512];
+        entry(&mut image, 0, 0, MBR_PROTECTIVE, 1, 4095);
+
+        let header = &mut image[512..512 + 92];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&count.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+
+        for (i, (first, last, name)) in entries.iter().enumerate() {
+            let at = 1024 + i * 128;
+            image[at..at + 16].copy_from_slice(&[0xaf; 16]);
+            image[at + 32..at + 40].copy_from_slice(&first.to_le_bytes());
+            image[at + 40..at + 48].copy_from_slice(&last.to_le_bytes());
+            for (j, c) in name.encode_utf16().enumerate() {
+                image[at + 56 + j * 2..at + 58 + j * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+
+        image
+    }
+
+    #[test]
+    fn test_gpt() {
+        let image = gpt_image(128, &[(34, 1033, "boot"), (0, 0, ""), (2048, 4095, "")]);
+        let mut boot = partition(1, 34, 1000);
+        boot.name = Some(String::from("boot"));
+
+        // The unused second entry is skipped but keeps its number
+        let mut image = image;
+        image[1024 + 128..1024 + 144].fill(0);
+        assert_eq!(
+            detect(&Image(image)).unwrap(),
+            [boot, partition(3, 2048, 2048)]
+        );
+    }
+
+    #[test]
+    fn test_bad_gpts() {
+        // More entries than any sane table
+        assert!(detect(&Image(gpt_image(u32::MAX, &[]))).is_err());
+        // Ends before it starts
+        assert!(detect(&Image(gpt_image(4, &[(100, 10, "")]))).is_err());
+
+        // A protective MBR without a GPT behind it
+        let mut image = gpt_image(4, &[]);
+        image[512..520].fill(0);
+        assert!(detect(&Image(image)).is_err());
+
+        // Huge entry sizes overflow when multiplied
+        let mut image = gpt_image(u32::MAX, &[]);
+        image[512 + 84..512 + 88].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(detect(&Image(image)).is_err());
+    }
+}
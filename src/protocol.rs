@@ -1,17 +1,15 @@
 use anyhow::Result;
 use byteorder::{BigEndian, WriteBytesExt};
-use std::{
-    fs::File,
-    io::{Read, Write},
-    os::unix::prelude::FileExt,
-};
+use std::io::{Read, Write};
 
 use crate::{
+    backend::Extent,
     client::Client,
     consts::{
-        NbdInfoOpt, NbdOpt, NbdReply, NBD_CMD_FLAG_DF, NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_NONE,
-        NBD_REPLY_TYPE_OFFSET_DATA, NBD_REP_MAGIC, NBD_SIMPLE_REPLY_MAGIC,
-        NBD_STRUCTURED_REPLY_MAGIC,
+        NbdInfoOpt, NbdOpt, NbdReply, NBD_CMD_FLAG_DF, NBD_EXTENDED_REPLY_MAGIC,
+        NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_BLOCK_STATUS, NBD_REPLY_TYPE_BLOCK_STATUS_EXT,
+        NBD_REPLY_TYPE_ERROR, NBD_REPLY_TYPE_NONE, NBD_REPLY_TYPE_OFFSET_DATA, NBD_REP_MAGIC,
+        NBD_SIMPLE_REPLY_MAGIC, NBD_STRUCTURED_REPLY_MAGIC,
     },
 };
 
@@ -37,45 +35,171 @@ pub struct StructuredReplyHeader {
     pub length: u32,
 }
 
+// Replaces the structured reply header once extended headers are negotiated
+#[derive(Debug, bincode::Encode, bincode::Decode)]
+#[repr(C)]
+pub struct ExtendedReplyHeader {
+    pub magic: u32,
+    pub flags: u16,
+    pub reply_type: u16,
+    pub handle: u64,
+    pub offset: u64,
+    pub length: u64,
+}
+
 // NBD client request
 // #define NBD_REQUEST_SIZE            (4 + 2 + 2 + 8 + 8 + 4)
 #[derive(Debug, bincode::Encode, bincode::Decode)]
 #[repr(C)]
+struct CompactRequest {
+    magic: u32,
+    flags: u16,
+    command_type: u16,
+    handle: u64,
+    offset: u64,
+    len: u32,
+}
+
+// With extended headers the length is 64 bits
+// #define NBD_EXTENDED_REQUEST_SIZE   (4 + 2 + 2 + 8 + 8 + 8)
+#[derive(Debug, bincode::Encode, bincode::Decode)]
+#[repr(C)]
+struct ExtendedRequest {
+    magic: u32,
+    flags: u16,
+    command_type: u16,
+    handle: u64,
+    offset: u64,
+    len: u64,
+}
+
+/// A request in either header format.
+#[derive(Debug)]
 pub struct Request {
     pub magic: u32,
     pub flags: u16,
     pub command_type: u16,
     pub handle: u64,
     pub offset: u64,
-    pub len: u32,
+    pub len: u64,
+}
+
+impl Request {
+    /// Decodes a request header, an extended one when `extended` is set.
+    pub fn decode(buf: &[u8], extended: bool) -> Result<Request> {
+        let config = bincode::config::standard()
+            .with_big_endian()
+            .with_fixed_int_encoding();
+
+        if extended {
+            let r: ExtendedRequest = bincode::decode_from_slice(buf, config)?.0;
+            return Ok(Request {
+                magic: r.magic,
+                flags: r.flags,
+                command_type: r.command_type,
+                handle: r.handle,
+                offset: r.offset,
+                len: r.len,
+            });
+        }
+
+        let r: CompactRequest = bincode::decode_from_slice(buf, config)?.0;
+        Ok(Request {
+            magic: r.magic,
+            flags: r.flags,
+            command_type: r.command_type,
+            handle: r.handle,
+            offset: r.offset,
+            len: r.len as u64,
+        })
+    }
 }
 
-// TODO: support multiple (and actual) exports
-pub fn handle_list<T: Read + Write>(
+pub fn handle_list<'a, T: Read + Write>(
     c: &mut Client<T>,
-    name: &str,
-    description: &str,
+    exports: impl Iterator<Item = (&'a str, &'a str)>,
 ) -> Result<()> {
-    let reply_header = OptionReply {
-        magic: NBD_REP_MAGIC,
-        option: (NbdOpt::List as u32),
-        reply_type: (NbdReply::Server as u32),
-
-        // Why +4? size of the length field (32)
-        length: (name.len() as u32 + description.len() as u32 + 4),
-    };
+    for (name, description) in exports {
+        let reply_header = OptionReply {
+            magic: NBD_REP_MAGIC,
+            option: (NbdOpt::List as u32),
+            reply_type: (NbdReply::Server as u32),
+
+            // Why +4? size of the length field (32)
+            length: (name.len() as u32 + description.len() as u32 + 4),
+        };
 
-    header_reply(c, reply_header)?;
-    c.stream().write_all(&(name.len() as u32).to_be_bytes())?;
-    c.stream().write_all(name.as_bytes())?;
-    c.stream().write_all(description.as_bytes())?;
-    c.stream().flush()?;
+        header_reply(c, reply_header)?;
+        c.stream().write_all(&(name.len() as u32).to_be_bytes())?;
+        c.stream().write_all(name.as_bytes())?;
+        c.stream().write_all(description.as_bytes())?;
+        c.stream().flush()?;
+    }
 
     handshake_reply(c, NbdOpt::List, NbdReply::Ack, EMPTY_REPLY)?;
 
     Ok(())
 }
 
+/// Extracts the export name and the information types requested from
+/// `NBD_OPT_INFO` and `NBD_OPT_GO` data, which must hold nothing else.
+pub fn parse_info(data: &[u8]) -> Option<(String, Vec<u16>)> {
+    let mut rest = data;
+    let name = take_string(&mut rest)?;
+    let count = u16::from_be_bytes(rest.get(0..2)?.try_into().ok()?) as usize;
+    let requests = rest.get(2..)?;
+    if requests.len() != count * 2 {
+        return None;
+    }
+
+    let requests = requests
+        .chunks_exact(2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .collect();
+
+    Some((name, requests))
+}
+
+/// Extracts the export name and queries from `NBD_OPT_LIST_META_CONTEXT`
+/// and `NBD_OPT_SET_META_CONTEXT` data.
+pub fn parse_meta_context(data: &[u8]) -> Option<(String, Vec<String>)> {
+    let mut rest = data;
+    let name = take_string(&mut rest)?;
+    let count = u32::from_be_bytes(rest.get(0..4)?.try_into().ok()?);
+    rest = &rest[4..];
+
+    let mut queries = Vec::new();
+    for _ in 0..count {
+        queries.push(take_string(&mut rest)?);
+    }
+
+    rest.is_empty().then_some((name, queries))
+}
+
+/// Takes a string prefixed by its 32-bit length off the front of `data`.
+fn take_string(data: &mut &[u8]) -> Option<String> {
+    let len = u32::from_be_bytes(data.get(0..4)?.try_into().ok()?) as usize;
+    let s = data.get(4..4usize.checked_add(len)?)?;
+    let s = String::from_utf8(s.to_vec()).ok()?;
+    *data = &data[4 + len..];
+
+    Some(s)
+}
+
+/// Tells the client about a metadata context, `id` is only meaningful when
+/// setting contexts.
+pub fn meta_context_reply<T: Read + Write>(
+    c: &mut Client<T>,
+    opt: NbdOpt,
+    id: u32,
+    name: &str,
+) -> Result<()> {
+    let mut data = id.to_be_bytes().to_vec();
+    data.extend_from_slice(name.as_bytes());
+
+    handshake_reply(c, opt, NbdReply::MetaContext, &data)
+}
+
 pub fn info_reply<T: Read + Write>(
     c: &mut Client<T>,
     opt: NbdOpt,
@@ -126,9 +250,19 @@ pub fn handshake_reply<T: Read + Write>(
     client_option: NbdOpt,
     reply_type: NbdReply,
     data: &[u8],
+) -> Result<()> {
+    option_reply(c, client_option as u32, reply_type, data)
+}
+
+/// Replies to any option, including ones we don't know.
+pub fn option_reply<T: Read + Write>(
+    c: &mut Client<T>,
+    client_option: u32,
+    reply_type: NbdReply,
+    data: &[u8],
 ) -> Result<()> {
     c.stream().write_u64::<BigEndian>(NBD_REP_MAGIC)?;
-    c.stream().write_u32::<BigEndian>(client_option as u32)?;
+    c.stream().write_u32::<BigEndian>(client_option)?;
     c.stream().write_u32::<BigEndian>(reply_type as u32)?;
     c.stream().write_u32::<BigEndian>(data.len() as u32)?;
     c.stream().write_all(data)?;
@@ -149,108 +283,247 @@ pub fn transmission_simple_reply_header<T: Read + Write>(
     Ok(())
 }
 
-pub fn do_read<T: Read + Write>(c: &mut Client<T>, request: &Request, file: &File) -> Result<()> {
-    if !c.structured_reply() {
-        transmission_simple_reply_header(c, request.handle, 0)?;
-        let mut buf: Vec<u8> = vec![0; request.len as usize];
-        file.read_at(buf.as_mut_slice(), request.offset)?;
-        c.stream().write_all(&buf)?;
+/// Writes the header of a reply chunk, extended when extended headers were
+/// negotiated.
+fn chunk_header<T: Read + Write>(
+    c: &mut Client<T>,
+    request: &Request,
+    flags: u16,
+    reply_type: u16,
+    length: u64,
+) -> Result<()> {
+    let config = bincode::config::standard()
+        .with_big_endian()
+        .with_fixed_int_encoding();
+
+    let header = if c.extended_headers() {
+        bincode::encode_to_vec(
+            ExtendedReplyHeader {
+                magic: NBD_EXTENDED_REPLY_MAGIC,
+                flags,
+                reply_type,
+                handle: request.handle,
+                offset: request.offset,
+                length,
+            },
+            config,
+        )?
     } else {
-        println!("structured reply");
-        structured_reply(c, request, file)?;
+        bincode::encode_to_vec(
+            StructuredReplyHeader {
+                magic: NBD_STRUCTURED_REPLY_MAGIC,
+                flags,
+                reply_type,
+                handle: request.handle,
+                length: length as u32,
+            },
+            config,
+        )?
+    };
+    c.write_all(&header)?;
+
+    Ok(())
+}
+
+/// Completes a request that has nothing to return. Extended headers don't
+/// allow simple replies.
+pub fn done_reply<T: Read + Write>(c: &mut Client<T>, request: &Request) -> Result<()> {
+    if c.extended_headers() {
+        chunk_header(c, request, NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_NONE, 0)?;
+    } else {
+        transmission_simple_reply_header(c, request.handle, 0)?;
     }
 
     c.stream().flush()?;
     Ok(())
 }
 
-pub fn do_write<T: Read + Write>(
+/// Fails a request with `error`, using an error chunk when structured
+/// replies were negotiated.
+pub fn error_reply<T: Read + Write>(
     c: &mut Client<T>,
-    handle: u64,
-    offset: u64,
-    len: u32,
-    file: &File,
+    request: &Request,
+    error: u32,
 ) -> Result<()> {
-    let mut buf: Vec<u8> = vec![0; len as usize];
-    c.stream().read_exact(buf.as_mut_slice())?;
-    file.write_at(&buf, offset)?;
-    transmission_simple_reply_header(c, handle, 0)?;
+    if c.structured_reply() {
+        // error + message length, we don't send a message
+        chunk_header(c, request, NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_ERROR, 6)?;
+        c.stream().write_u32::<BigEndian>(error)?;
+        c.stream().write_u16::<BigEndian>(0)?;
+    } else {
+        transmission_simple_reply_header(c, request.handle, error)?;
+    }
 
     c.stream().flush()?;
+    Ok(())
+}
+
+pub fn do_read<T: Read + Write>(c: &mut Client<T>, request: &Request, data: &[u8]) -> Result<()> {
+    if !c.structured_reply() {
+        transmission_simple_reply_header(c, request.handle, 0)?;
+        c.stream().write_all(data)?;
+    } else {
+        structured_reply(c, request, data)?;
+    }
 
+    c.stream().flush()?;
     Ok(())
 }
 
+/// Sends `data`, read at `request.offset`, as a series of data chunks.
 pub fn structured_reply<T: Read + Write>(
     c: &mut Client<T>,
     request: &Request,
-    data: &File,
+    data: &[u8],
 ) -> Result<()> {
-    let mut start = request.offset;
-    let end = start + request.len as u64;
-    let mut chunk_size = DEFAULT_CHUNK_SIZE;
+    let mut chunk_size = DEFAULT_CHUNK_SIZE as usize;
     if request.flags & NBD_CMD_FLAG_DF != 0 {
-        chunk_size = request.len as u64;
+        chunk_size = std::cmp::max(data.len(), 1);
     }
 
-    while start + chunk_size < end {
-        let mut buf: Vec<u8> = vec![0; (chunk_size + 8) as usize];
-        (&start.to_be_bytes()[..]).read_exact(&mut buf[0..8])?;
-        data.read_exact_at(&mut buf[8..], start)?;
-
-        let header = StructuredReplyHeader {
-            magic: NBD_STRUCTURED_REPLY_MAGIC,
-            flags: 0,
-            reply_type: NBD_REPLY_TYPE_OFFSET_DATA,
-            handle: request.handle,
-            length: (chunk_size + 8) as u32,
-        };
-
-        c.write_all(&bincode::encode_to_vec(
-            &header,
-            bincode::config::standard()
-                .with_big_endian()
-                .with_fixed_int_encoding(),
-        )?)?;
-        c.stream().write_all(&buf)?;
-        start += chunk_size;
+    let mut offset = request.offset;
+    for chunk in data.chunks(chunk_size) {
+        let length = chunk.len() as u64 + 8;
+        chunk_header(c, request, 0, NBD_REPLY_TYPE_OFFSET_DATA, length)?;
+        c.stream().write_all(&offset.to_be_bytes())?;
+        c.stream().write_all(chunk)?;
+        offset += chunk.len() as u64;
     }
 
-    if start < end {
-        let mut buf: Vec<u8> = vec![0; (end - start + 8) as usize];
-        (&start.to_be_bytes()[..]).read_exact(&mut buf[0..8])?;
-        data.read_exact_at(&mut buf[8..], start)?;
-        let header = StructuredReplyHeader {
-            magic: NBD_STRUCTURED_REPLY_MAGIC,
-            flags: 0,
-            reply_type: NBD_REPLY_TYPE_OFFSET_DATA,
-            handle: request.handle,
-            length: (end - start + 8) as u32,
-        };
-        c.write_all(&bincode::encode_to_vec(
-            &header,
-            bincode::config::standard()
-                .with_big_endian()
-                .with_fixed_int_encoding(),
-        )?)?;
-        c.stream().write_all(&buf)?;
-    }
+    chunk_header(c, request, NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_NONE, 0)?;
+    c.flush()?;
 
-    let header = StructuredReplyHeader {
-        magic: NBD_STRUCTURED_REPLY_MAGIC,
-        flags: NBD_REPLY_FLAG_DONE,
-        reply_type: NBD_REPLY_TYPE_NONE,
-        handle: request.handle,
-        length: 0,
-    };
-    c.write_all(&bincode::encode_to_vec(
-        &header,
-        bincode::config::standard()
-            .with_big_endian()
-            .with_fixed_int_encoding(),
-    )?)?;
+    Ok(())
+}
+
+/// Sends the extents found in each selected metadata context, with 64-bit
+/// descriptors when extended headers were negotiated.
+pub fn block_status_reply<T: Read + Write>(
+    c: &mut Client<T>,
+    request: &Request,
+    contexts: &[(u32, Vec<Extent>)],
+) -> Result<()> {
+    for (id, extents) in contexts {
+        if c.extended_headers() {
+            let length = 8 + 16 * extents.len() as u64;
+            chunk_header(c, request, 0, NBD_REPLY_TYPE_BLOCK_STATUS_EXT, length)?;
+            c.stream().write_u32::<BigEndian>(*id)?;
+            c.stream().write_u32::<BigEndian>(extents.len() as u32)?;
+            for extent in extents {
+                c.stream().write_u64::<BigEndian>(extent.len)?;
+                c.stream().write_u64::<BigEndian>(extent.flags as u64)?;
+            }
+        } else {
+            // Compact requests are shorter than 4GiB, and so are the extents
+            let length = 4 + 8 * extents.len() as u64;
+            chunk_header(c, request, 0, NBD_REPLY_TYPE_BLOCK_STATUS, length)?;
+            c.stream().write_u32::<BigEndian>(*id)?;
+            for extent in extents {
+                c.stream().write_u32::<BigEndian>(extent.len as u32)?;
+                c.stream().write_u32::<BigEndian>(extent.flags)?;
+            }
+        }
+    }
 
+    chunk_header(c, request, NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_NONE, 0)?;
     c.flush()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Vec<u8> {
+        [&(s.len() as u32).to_be_bytes(), s.as_bytes()].concat()
+    }
+
+    #[test]
+    fn test_parse_info() {
+        let data = [string("disk"), vec![0, 2, 0, 3, 0, 1]].concat();
+        assert_eq!(parse_info(&data), Some((String::from("disk"), vec![3, 1])));
+        let data = [string(""), vec![0, 0]].concat();
+        assert_eq!(parse_info(&data), Some((String::new(), vec![])));
+
+        // Too few or too many requests for the count
+        assert_eq!(
+            parse_info(&[string("disk"), vec![0, 2, 0, 3]].concat()),
+            None
+        );
+        assert_eq!(parse_info(&[string("disk"), vec![0, 0, 0]].concat()), None);
+        // No count
+        assert_eq!(parse_info(&string("disk")), None);
+        // Names running past the data, or not UTF-8
+        assert_eq!(parse_info(&[0, 0, 0, 5, b'd', 0, 0]), None);
+        assert_eq!(parse_info(&[0xff, 0xff, 0xff, 0xff, 0, 0]), None);
+        assert_eq!(parse_info(&[0, 0, 0, 1, 0xff, 0, 0]), None);
+        assert_eq!(parse_info(&[]), None);
+    }
+
+    #[test]
+    fn test_parse_meta_context() {
+        let data = [
+            string("disk"),
+            2u32.to_be_bytes().to_vec(),
+            string("base:"),
+            string("qemu:dirty-bitmap:backup"),
+        ]
+        .concat();
+        assert_eq!(
+            parse_meta_context(&data),
+            Some((
+                String::from("disk"),
+                vec![
+                    String::from("base:"),
+                    String::from("qemu:dirty-bitmap:backup")
+                ]
+            ))
+        );
+        let data = [string("disk"), 0u32.to_be_bytes().to_vec()].concat();
+        assert_eq!(
+            parse_meta_context(&data),
+            Some((String::from("disk"), vec![]))
+        );
+
+        // Fewer queries than counted, trailing bytes, or no count
+        let data = [string("disk"), 2u32.to_be_bytes().to_vec(), string("base:")].concat();
+        assert_eq!(parse_meta_context(&data), None);
+        let data = [string("disk"), 0u32.to_be_bytes().to_vec(), vec![0]].concat();
+        assert_eq!(parse_meta_context(&data), None);
+        let data = [string("disk"), u32::MAX.to_be_bytes().to_vec()].concat();
+        assert_eq!(parse_meta_context(&data), None);
+        assert_eq!(parse_meta_context(&string("disk")), None);
+    }
+
+    #[test]
+    fn test_decode_request() {
+        let header = [
+            &0x25609513u32.to_be_bytes()[..],
+            &1u16.to_be_bytes(),
+            &3u16.to_be_bytes(),
+            &7u64.to_be_bytes(),
+            &4096u64.to_be_bytes(),
+        ]
+        .concat();
+
+        let compact = [&header[..], &512u32.to_be_bytes()].concat();
+        let request = Request::decode(&compact, false).unwrap();
+        assert_eq!(
+            (request.magic, request.flags, request.command_type),
+            (0x25609513, 1, 3)
+        );
+        assert_eq!(
+            (request.handle, request.offset, request.len),
+            (7, 4096, 512)
+        );
+
+        // 64-bit lengths with extended headers
+        let extended = [&header[..], &(1u64 << 40).to_be_bytes()].concat();
+        let request = Request::decode(&extended, true).unwrap();
+        assert_eq!(request.len, 1 << 40);
+
+        assert!(Request::decode(&compact[..27], false).is_err());
+        assert!(Request::decode(&compact, true).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    sync::Mutex,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+// The most a bucket can owe, in seconds of refill. Requests are checked
+// before they are charged, this only keeps a wait from overflowing.
+const MAX_DEBT_SECS: f64 = 3600.0;
+
+/// I/O limits, rates are per second and bursts default to one second's
+/// worth.
+///
+/// ```toml
+/// iops = 500
+/// bandwidth = 52428800
+/// bandwidth_burst = 104857600
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// Requests per second
+    pub iops: Option<u64>,
+    /// Requests that may be sent at once after being idle
+    pub iops_burst: Option<u64>,
+    /// Bytes read or written per second
+    pub bandwidth: Option<u64>,
+    /// Bytes that may be transferred at once after being idle
+    pub bandwidth_burst: Option<u64>,
+}
+
+impl Limits {
+    pub fn validate(&self) -> Result<()> {
+        for (name, value) in [
+            ("iops", self.iops),
+            ("iops_burst", self.iops_burst),
+            ("bandwidth", self.bandwidth),
+            ("bandwidth_burst", self.bandwidth_burst),
+        ] {
+            if value == Some(0) {
+                bail!("{} must be at least 1", name);
+            }
+        }
+
+        if self.iops.is_none() && self.iops_burst.is_some() {
+            bail!("iops_burst needs iops");
+        }
+        if self.bandwidth.is_none() && self.bandwidth_burst.is_some() {
+            bail!("bandwidth_burst needs bandwidth");
+        }
+
+        Ok(())
+    }
+}
+
+/// A token bucket refilled at `rate` tokens per second, holding at most
+/// `burst`.
+///
+/// Taking more tokens than there are puts the bucket in debt, the caller
+/// waits until it is paid off. That way requests larger than the burst
+/// still go through, just slowly, and concurrent callers queue up in the
+/// order they took their tokens.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: Option<u64>) -> Self {
+        let burst = burst.unwrap_or(rate) as f64;
+
+        TokenBucket {
+            rate: rate as f64,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Takes `amount` tokens, returns how long to wait before using them.
+    fn take(&self, amount: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.burst);
+        *last = now;
+        *tokens = (*tokens - amount as f64).max(-MAX_DEBT_SECS * self.rate);
+
+        if *tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::try_from_secs_f64(-*tokens / self.rate)
+            .unwrap_or(Duration::from_secs_f64(MAX_DEBT_SECS))
+    }
+}
+
+/// Enforces [`Limits`] on whoever shares it.
+#[derive(Debug)]
+pub struct Throttle {
+    iops: Option<TokenBucket>,
+    bandwidth: Option<TokenBucket>,
+}
+
+impl Throttle {
+    /// A throttle for `limits`, `None` when they don't limit anything.
+    pub fn new(limits: &Limits) -> Option<Throttle> {
+        let throttle = Throttle {
+            iops: limits
+                .iops
+                .map(|rate| TokenBucket::new(rate, limits.iops_burst)),
+            bandwidth: limits
+                .bandwidth
+                .map(|rate| TokenBucket::new(rate, limits.bandwidth_burst)),
+        };
+
+        match throttle.iops.is_none() && throttle.bandwidth.is_none() {
+            true => None,
+            false => Some(throttle),
+        }
+    }
+
+    /// Accounts for a request transferring `bytes`, returns how long it has
+    /// to wait.
+    pub fn delay(&self, bytes: u64) -> Duration {
+        let iops = self.iops.as_ref().map_or(Duration::ZERO, |b| b.take(1));
+        let bandwidth = match bytes {
+            0 => Duration::ZERO,
+            _ => self
+                .bandwidth
+                .as_ref()
+                .map_or(Duration::ZERO, |b| b.take(bytes)),
+        };
+
+        iops.max(bandwidth)
+    }
+}
+
+/// Waits for every throttle a request is subject to, returns how long that
+/// took.
+pub fn wait<'a>(throttles: impl IntoIterator<Item = &'a Throttle>, bytes: u64) -> Duration {
+    let delay = throttles
+        .into_iter()
+        .map(|throttle| throttle.delay(bytes))
+        .max()
+        .unwrap_or_default();
+
+    if !delay.is_zero() {
+        sleep(delay);
+    }
+
+    delay
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(d: Duration) -> f64 {
+        d.as_secs_f64()
+    }
+
+    #[test]
+    fn test_burst_then_debt() {
+        let bucket = TokenBucket::new(100, Some(10));
+
+        // The burst is free, then each token costs 10ms
+        assert_eq!(bucket.take(10), Duration::ZERO);
+        let wait = secs(bucket.take(50));
+        assert!((0.45..=0.5).contains(&wait), "{}", wait);
+        let wait = secs(bucket.take(50));
+        assert!((0.95..=1.0).contains(&wait), "{}", wait);
+    }
+
+    #[test]
+    fn test_refill() {
+        let bucket = TokenBucket::new(1000, None);
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        assert!(!bucket.take(20).is_zero());
+
+        sleep(Duration::from_millis(50));
+        // Back in credit, but never past the burst
+        assert_eq!(bucket.take(20), Duration::ZERO);
+        sleep(Duration::from_millis(1100));
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        assert!(!bucket.take(10).is_zero());
+    }
+
+    #[test]
+    fn test_huge_amounts() {
+        let bucket = TokenBucket::new(1, None);
+
+        for amount in [u32::MAX as u64, u64::MAX, u64::MAX] {
+            let wait = bucket.take(amount);
+            assert!(wait <= Duration::from_secs_f64(MAX_DEBT_SECS));
+        }
+        // The debt is capped, the next caller doesn't owe more than that
+        assert!(bucket.take(1) <= Duration::from_secs_f64(MAX_DEBT_SECS));
+    }
+
+    #[test]
+    fn test_throttle() {
+        let limits = Limits {
+            iops: Some(10),
+            bandwidth: Some(1000),
+            ..Limits::default()
+        };
+        let throttle = Throttle::new(&limits).unwrap();
+
+        assert_eq!(throttle.delay(1000), Duration::ZERO);
+        // Bandwidth dominates, iops doesn't bite yet
+        let wait = secs(throttle.delay(500));
+        assert!((0.45..=0.5).contains(&wait), "{}", wait);
+        assert!(Throttle::new(&Limits::default()).is_none());
+    }
+}
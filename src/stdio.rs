@@ -0,0 +1,95 @@
+use crate::{acl::Peer, client::Client, connections::Closer, systemd, tcp, Server};
+use anyhow::{anyhow, bail, Result};
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    os::unix::{
+        net::UnixStream,
+        prelude::{FromRawFd, RawFd},
+    },
+};
+
+/// stdin and stdout joined into a single stream, for running behind
+/// `ssh host nbd --stdio disk.img` or similar.
+///
+/// Anything else writing to stdout corrupts the protocol, diagnostics must
+/// go to stderr.
+#[derive(Debug)]
+pub struct Stdio {
+    stdin: io::Stdin,
+    stdout: io::Stdout,
+}
+
+impl Stdio {
+    pub fn new() -> Self {
+        Stdio {
+            stdin: io::stdin(),
+            stdout: io::stdout(),
+        }
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdin.read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdout.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
+
+/// Serves a single session over stdin/stdout and returns once it ends.
+pub fn serve_stdio(server: &Server) -> Result<()> {
+    let mut client = Client::new(Stdio::new(), String::from("stdio"));
+
+    server.handle(&mut client)
+}
+
+/// Serves a single session on an already connected socket, the way inetd
+/// hands connections over on fd 0.
+pub fn serve_socket_fd(server: &Server, fd: RawFd) -> Result<()> {
+    if systemd::getsockopt_int(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+        bail!("fd {} is not a stream socket", fd);
+    }
+
+    match systemd::getsockopt_int(fd, libc::SO_DOMAIN)? {
+        libc::AF_INET | libc::AF_INET6 => {
+            let stream = unsafe { TcpStream::from_raw_fd(fd) };
+            let addr = stream.peer_addr()?;
+            tcp::configure_stream(&stream, server.options())?;
+            let closer = Closer::Tcp(stream.try_clone()?);
+            let mut client = Client::new(stream, addr.to_string());
+            client.set_closer(closer);
+            client.set_peer(Peer::from_ip(addr.ip()));
+            server.handle(&mut client)
+        }
+        libc::AF_UNIX => {
+            let stream = unsafe { UnixStream::from_raw_fd(fd) };
+            let peer = Peer::from_unix(&stream)?;
+            let closer = Closer::Unix(stream.try_clone()?);
+            let mut client = Client::new(stream, format!("unix-sock-{}", fd));
+            client.set_closer(closer);
+            client.set_peer(peer);
+            server.handle(&mut client)
+        }
+        domain => Err(anyhow!(
+            "fd {} has unsupported address family {}",
+            fd,
+            domain
+        )),
+    }
+}
@@ -0,0 +1,243 @@
+use anyhow::{anyhow, bail, Context, Result};
+
+use std::{
+    env, io,
+    mem::{self, MaybeUninit},
+    net::TcpListener,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            net::{SocketAddr, UnixDatagram, UnixListener},
+            prelude::{FromRawFd, RawFd},
+        },
+    },
+};
+
+use crate::config::Listener;
+
+// https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket handed to us by our parent, either through systemd
+/// socket activation or explicitly with `--fd`.
+#[derive(Debug)]
+pub enum InheritedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl InheritedListener {
+    /// Whether this is the socket `listener` would bind, so its settings
+    /// apply. A TCP listener on any address matches one on any IPv4 or IPv6
+    /// address, as systemd binds `[::]` for a bare port.
+    pub fn is(&self, listener: &Listener) -> bool {
+        match (self, listener) {
+            (InheritedListener::Tcp(socket), Listener::Tcp { address, .. }) => {
+                match socket.local_addr() {
+                    Ok(local) => {
+                        local.port() == address.port()
+                            && (local.ip() == address.ip()
+                                || local.ip().is_unspecified() && address.ip().is_unspecified())
+                    }
+                    Err(_) => false,
+                }
+            }
+            (InheritedListener::Unix(socket), Listener::Unix { path, .. }) => socket
+                .local_addr()
+                .is_ok_and(|local| local.as_pathname() == Some(path.as_path())),
+            _ => false,
+        }
+    }
+}
+
+/// Returns the file descriptors passed through `LISTEN_FDS`/`LISTEN_PID`.
+///
+/// The variables are removed from the environment so that child processes
+/// don't try to use the descriptors as well.
+pub fn listen_fds() -> Result<Vec<RawFd>> {
+    let pid = match env::var("LISTEN_PID") {
+        Ok(pid) => pid,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let fds = env::var("LISTEN_FDS").unwrap_or_default();
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    parse_listen_fds(&pid, &fds, std::process::id())
+}
+
+/// The descriptors `LISTEN_PID` and `LISTEN_FDS` pass to the process `own`.
+fn parse_listen_fds(pid: &str, fds: &str, own: u32) -> Result<Vec<RawFd>> {
+    let pid: u32 = pid
+        .parse()
+        .with_context(|| format!("Invalid LISTEN_PID '{}'", pid))?;
+    if pid != own {
+        // The descriptors were meant for another process
+        return Ok(Vec::new());
+    }
+
+    let count = fds
+        .parse::<RawFd>()
+        .ok()
+        .filter(|count| (0..=RawFd::MAX - SD_LISTEN_FDS_START).contains(count))
+        .with_context(|| format!("Invalid LISTEN_FDS '{}'", fds))?;
+
+    Ok((SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect())
+}
+
+/// Takes ownership of `fd`, which must be a listening stream socket.
+pub fn listener_from_fd(fd: RawFd) -> Result<InheritedListener> {
+    if getsockopt_int(fd, libc::SO_ACCEPTCONN)
+        .with_context(|| format!("fd {} is not a socket", fd))?
+        == 0
+    {
+        bail!("fd {} is not a listening socket", fd);
+    }
+
+    if getsockopt_int(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+        bail!("fd {} is not a stream socket", fd);
+    }
+
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    match getsockopt_int(fd, libc::SO_DOMAIN)? {
+        libc::AF_INET | libc::AF_INET6 => Ok(InheritedListener::Tcp(unsafe {
+            TcpListener::from_raw_fd(fd)
+        })),
+        libc::AF_UNIX => Ok(InheritedListener::Unix(unsafe {
+            UnixListener::from_raw_fd(fd)
+        })),
+        domain => Err(anyhow!(
+            "fd {} has unsupported address family {}",
+            fd,
+            domain
+        )),
+    }
+}
+
+/// Sends `state` (e.g. `READY=1`) to the service manager.
+///
+/// Does nothing when we were not started with `NOTIFY_SOCKET`.
+pub fn notify(state: &str) -> Result<()> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .send_to_addr(state.as_bytes(), &addr)
+        .with_context(|| format!("Failed to notify {}", path))?;
+
+    Ok(())
+}
+
+pub(crate) fn getsockopt_int(fd: RawFd, option: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value = MaybeUninit::<libc::c_int>::uninit();
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            value.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { value.assume_init() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert_eq!(parse_listen_fds("42", "2", 42).unwrap(), [3, 4]);
+        assert_eq!(
+            parse_listen_fds("42", "0", 42).unwrap(),
+            Vec::<RawFd>::new()
+        );
+
+        // Meant for whoever started us, or one of its other children
+        assert_eq!(
+            parse_listen_fds("41", "2", 42).unwrap(),
+            Vec::<RawFd>::new()
+        );
+        assert_eq!(
+            parse_listen_fds("41", "nonsense", 42).unwrap(),
+            Vec::<RawFd>::new()
+        );
+
+        for fds in ["", "-1", "two", "2147483647"] {
+            assert!(parse_listen_fds("42", fds, 42).is_err(), "{:?}", fds);
+        }
+        assert!(parse_listen_fds("", "2", 42).is_err());
+        assert!(parse_listen_fds("-42", "2", 42).is_err());
+    }
+
+    #[test]
+    fn test_listener_from_fd() {
+        use std::os::unix::prelude::IntoRawFd;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        match listener_from_fd(listener.into_raw_fd()).unwrap() {
+            InheritedListener::Tcp(listener) => assert_eq!(listener.local_addr().unwrap(), addr),
+            other => panic!("Expected a TCP listener, got {:?}", other),
+        }
+
+        // A connected socket isn't listening
+        let (a, _b) = std::os::unix::net::UnixStream::pair().unwrap();
+        let fd = a.into_raw_fd();
+        assert!(listener_from_fd(fd).is_err());
+        unsafe { libc::close(fd) };
+    }
+    #[test]
+    fn test_matching_listeners() {
+        let tcp = |address: &str| Listener::Tcp {
+            address: address.parse().unwrap(),
+            oldstyle: None,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let inherited = InheritedListener::Tcp(listener);
+        assert!(inherited.is(&tcp(&format!("127.0.0.1:{}", port))));
+        assert!(!inherited.is(&tcp(&format!("127.0.0.2:{}", port))));
+        assert!(!inherited.is(&tcp(&format!("0.0.0.0:{}", port))));
+        assert!(!inherited.is(&tcp("127.0.0.1:1")));
+
+        // systemd binds [::] for ListenStream=<port>
+        let listener = TcpListener::bind("[::]:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let inherited = InheritedListener::Tcp(listener);
+        assert!(inherited.is(&tcp(&format!("0.0.0.0:{}", port))));
+        assert!(inherited.is(&tcp(&format!("[::]:{}", port))));
+
+        let path = std::env::temp_dir().join(format!("nbd-systemd-{}.sock", std::process::id()));
+        let inherited = InheritedListener::Unix(UnixListener::bind(&path).unwrap());
+        let unix = |path: &std::path::Path| Listener::Unix {
+            path: path.to_owned(),
+            oldstyle: None,
+        };
+        assert!(inherited.is(&unix(&path)));
+        assert!(!inherited.is(&unix(std::path::Path::new("/run/other.sock"))));
+        assert!(!inherited.is(&tcp(&format!("0.0.0.0:{}", port))));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
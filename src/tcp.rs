@@ -1,33 +1,156 @@
 use std::{
     io,
-    net::{SocketAddr, TcpListener},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::unix::prelude::AsRawFd,
     sync::{self, atomic::AtomicBool, Arc},
     thread::{self, sleep, JoinHandle},
     time::Duration,
 };
 
-use crate::{client::Client, Export, Server};
+use crate::{
+    acl::Peer,
+    client::Client,
+    connections::Closer,
+    consts::{DEFAULT_KEEPALIVE_COUNT, DEFAULT_KEEPALIVE_INTERVAL_SECS},
+    Server, ServerOptions,
+};
 use anyhow::Result;
+use serde::Deserialize;
+use tracing::{error, info, warn};
 
-pub fn start_tcp_server(export: &Export, address: SocketAddr, stop: &AtomicBool) -> Result<()> {
-    let server: Arc<Server> = Arc::new(Server::new(export.clone()));
-    let mut handles: Vec<JoinHandle<()>> = Vec::new();
+/// TCP keepalive probing, so dead peers are noticed on idle connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Keepalive {
+    /// Seconds a connection is idle before the first probe
+    pub idle: u32,
+    /// Seconds between probes
+    #[serde(default = "default_keepalive_interval")]
+    pub interval: u32,
+    /// Unanswered probes before the connection is dropped
+    #[serde(default = "default_keepalive_count")]
+    pub count: u32,
+}
+
+impl Keepalive {
+    pub fn new(idle: u32) -> Self {
+        Keepalive {
+            idle,
+            interval: DEFAULT_KEEPALIVE_INTERVAL_SECS,
+            count: DEFAULT_KEEPALIVE_COUNT,
+        }
+    }
+}
+
+fn default_keepalive_interval() -> u32 {
+    DEFAULT_KEEPALIVE_INTERVAL_SECS
+}
+
+fn default_keepalive_count() -> u32 {
+    DEFAULT_KEEPALIVE_COUNT
+}
+
+/// Applies the keepalive and `TCP_USER_TIMEOUT` settings to an accepted
+/// stream.
+pub fn configure_stream(stream: &TcpStream, options: &ServerOptions) -> io::Result<()> {
+    let fd = stream.as_raw_fd();
+
+    if let Some(keepalive) = options.keepalive {
+        setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, keepalive.idle)?;
+        setsockopt_int(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_KEEPINTVL,
+            keepalive.interval,
+        )?;
+        setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, keepalive.count)?;
+    }
+
+    // How long sent data may go unacknowledged before the kernel gives up
+    // on the peer, keepalive alone doesn't cover a peer that vanished
+    // while we were writing to it
+    if let Some(timeout) = options.user_timeout {
+        let millis = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+        setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT, millis)?;
+    }
+
+    Ok(())
+}
+
+fn setsockopt_int(
+    fd: libc::c_int,
+    level: libc::c_int,
+    option: libc::c_int,
+    value: u32,
+) -> io::Result<()> {
+    let value = libc::c_int::try_from(value).unwrap_or(libc::c_int::MAX);
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+pub fn start_tcp_server(
+    server: &Arc<Server>,
+    address: SocketAddr,
+    oldstyle: Option<&str>,
+    stop: &AtomicBool,
+) -> Result<()> {
     let listener = TcpListener::bind(address)?;
+    serve_tcp_listener(server, listener, oldstyle, stop)
+}
+
+/// Serves clients on an already bound listener, e.g. one inherited from systemd.
+///
+/// With `oldstyle` clients get that export without negotiating.
+pub fn serve_tcp_listener(
+    server: &Arc<Server>,
+    listener: TcpListener,
+    oldstyle: Option<&str>,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let mut handles: Vec<JoinHandle<()>> = Vec::new();
     listener.set_nonblocking(true)?;
 
     for conn in listener.incoming() {
         if stop.load(sync::atomic::Ordering::SeqCst) {
-            println!("Received stop signal, exiting");
+            info!("Received stop signal, exiting");
             break;
         }
 
         match conn {
             Ok(stream) => {
-                let client_addr = stream.peer_addr().unwrap().to_string();
-                let mut client = Client::new(stream, client_addr);
-                let clone = Arc::clone(&server);
+                let peer_addr = match stream.peer_addr() {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        warn!("Dropping client without a peer address: {}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = configure_stream(&stream, server.options()) {
+                    warn!(client = %peer_addr, "Failed to set socket options: {}", e);
+                }
+                let closer = Closer::Tcp(stream.try_clone()?);
+                let mut client = Client::new(stream, peer_addr.to_string());
+                client.set_closer(closer);
+                client.set_peer(Peer::from_ip(peer_addr.ip()));
+                client.set_oldstyle(oldstyle.map(str::to_owned));
+                let clone = Arc::clone(server);
                 let join_handle = thread::spawn(move || {
-                    clone.handle(&mut client).unwrap();
+                    if let Err(e) = clone.handle(&mut client) {
+                        error!("Error handling client: {}", e);
+                    }
                 });
 
                 handles.push(join_handle);
@@ -39,15 +162,20 @@ pub fn start_tcp_server(export: &Export, address: SocketAddr, stop: &AtomicBool)
                         continue;
                     }
                     _ => {
-                        eprintln!("error: {}", e);
+                        error!("error: {}", e);
                     }
                 }
-                eprintln!("error: {}", e)
+                error!("error: {}", e)
             }
         }
     }
 
-    handles.into_iter().for_each(|h| h.join().unwrap());
+    server.shutdown();
+    handles.into_iter().for_each(|h| {
+        if h.join().is_err() {
+            error!("Thread panicked");
+        }
+    });
 
     Ok(())
 }
@@ -0,0 +1,49 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+
+/// Builds the TLS configuration used for `NBD_OPT_STARTTLS`.
+///
+/// When `ca` is given clients must present a certificate signed by it.
+pub fn server_config(certificate: &Path, key: &Path, ca: Option<&Path>) -> Result<ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let certs = CertificateDer::pem_file_iter(certificate)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {}", certificate.display()))?;
+    if certs.is_empty() {
+        bail!("No certificate found in {}", certificate.display());
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Failed to read private key from {}", key.display()))?;
+
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca)
+                .with_context(|| format!("Failed to read CA certificates from {}", ca.display()))?
+            {
+                roots.add(cert?)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .context("Failed to set up client certificate verification")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    builder
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or private key")
+}
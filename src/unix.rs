@@ -1,5 +1,6 @@
-use crate::{client::Client, Export, Server};
+use crate::{acl::Peer, client::Client, connections::Closer, Server};
 use anyhow::Result;
+use tracing::{debug, error, info, warn};
 
 use std::{
     io,
@@ -10,26 +11,59 @@ use std::{
     time::Duration,
 };
 
-pub fn start_unix_socket_server(export: &Export, path: &Path, stop: &AtomicBool) -> Result<()> {
-    let server: Arc<Server> = Arc::new(Server::new(export.clone()));
-    let mut handles: Vec<JoinHandle<()>> = Vec::new();
+pub fn start_unix_socket_server(
+    server: &Arc<Server>,
+    path: &Path,
+    oldstyle: Option<&str>,
+    stop: &AtomicBool,
+) -> Result<()> {
     let listener = UnixListener::bind(path)?;
+    serve_unix_listener(server, listener, oldstyle, stop)?;
+
+    // Maybe this can be done automatically somehow?
+    debug!("Cleaning up UNIX socket: {}", path.to_str().unwrap());
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Serves clients on an already bound listener, e.g. one inherited from systemd.
+///
+/// The socket file is left in place, it belongs to whoever bound it. With
+/// `oldstyle` clients get that export without negotiating.
+pub fn serve_unix_listener(
+    server: &Arc<Server>,
+    listener: UnixListener,
+    oldstyle: Option<&str>,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let mut handles: Vec<JoinHandle<()>> = Vec::new();
     listener.set_nonblocking(true)?;
 
     for conn in listener.incoming() {
         if stop.load(sync::atomic::Ordering::SeqCst) {
-            println!("Received stop signal, exiting");
+            info!("Received stop signal, exiting");
             break;
         }
 
         match conn {
             Ok(stream) => {
                 let fd = &stream.as_raw_fd();
+                let closer = Closer::Unix(stream.try_clone()?);
+                let peer = match Peer::from_unix(&stream) {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        warn!("Dropping client without peer credentials: {}", e);
+                        continue;
+                    }
+                };
                 let mut client = Client::new(stream, format!("unix-sock-{}", fd));
-                let clone = Arc::clone(&server);
+                client.set_closer(closer);
+                client.set_peer(peer);
+                client.set_oldstyle(oldstyle.map(str::to_owned));
+                let clone = Arc::clone(server);
                 let h = thread::spawn(move || {
                     if let Err(e) = clone.handle(&mut client) {
-                        eprintln!("Error handling client: {}", e);
+                        error!("Error handling client: {}", e);
                     }
                 });
                 handles.push(h);
@@ -41,21 +75,20 @@ pub fn start_unix_socket_server(export: &Export, path: &Path, stop: &AtomicBool)
                         continue;
                     }
                     _ => {
-                        eprintln!("error: {}", e);
+                        error!("error: {}", e);
                     }
                 }
-                eprintln!("error: {}", e)
+                error!("error: {}", e)
             }
         }
     }
+
+    server.shutdown();
     handles.into_iter().for_each(|h| {
         if h.join().is_err() {
-            println!("Thread panicked");
+            error!("Thread panicked");
         }
     });
 
-    // Maybe this can be done automatically somehow?
-    println!("Cleaning up UNIX socket: {}", path.to_str().unwrap());
-    std::fs::remove_file(path)?;
     Ok(())
 }
@@ -0,0 +1,136 @@
+use std::fmt;
+
+use aes::{
+    cipher::{consts::U16, generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128, Aes256,
+};
+use anyhow::{bail, Result};
+
+const BLOCK: usize = 16;
+
+enum Keys {
+    Aes128 {
+        data: Box<Aes128>,
+        tweak: Box<Aes128>,
+    },
+    Aes256 {
+        data: Box<Aes256>,
+        tweak: Box<Aes256>,
+    },
+}
+
+/// AES in XTS mode, as used for disk encryption: each sector is encrypted
+/// on its own, with its number as the tweak.
+pub struct Xts {
+    keys: Keys,
+}
+
+impl fmt::Debug for Xts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never the keys
+        let bits = match self.keys {
+            Keys::Aes128 { .. } => 128,
+            Keys::Aes256 { .. } => 256,
+        };
+        write!(f, "Xts(AES-{})", bits)
+    }
+}
+
+impl Xts {
+    /// Takes a 32 byte key for AES-128-XTS or a 64 byte one for
+    /// AES-256-XTS, the second half keys the tweak.
+    pub fn new(key: &[u8]) -> Result<Xts> {
+        if key.len() != 32 && key.len() != 64 {
+            bail!(
+                "XTS keys are 32 or 64 bytes long, for AES-128 or AES-256, not {}",
+                key.len()
+            );
+        }
+        let (data, tweak) = key.split_at(key.len() / 2);
+        if data == tweak {
+            bail!("The two halves of an XTS key must differ");
+        }
+
+        let keys = match key.len() {
+            32 => Keys::Aes128 {
+                data: Box::new(Aes128::new(GenericArray::from_slice(data))),
+                tweak: Box::new(Aes128::new(GenericArray::from_slice(tweak))),
+            },
+            64 => Keys::Aes256 {
+                data: Box::new(Aes256::new(GenericArray::from_slice(data))),
+                tweak: Box::new(Aes256::new(GenericArray::from_slice(tweak))),
+            },
+            _ => unreachable!(),
+        };
+
+        Ok(Xts { keys })
+    }
+
+    /// Encrypts one sector in place, its length must be a multiple of 16.
+    pub fn encrypt(&self, sector: &mut [u8], number: u64) {
+        match &self.keys {
+            Keys::Aes128 { data, tweak } => {
+                xts(sector, number, tweak.as_ref(), |b| data.encrypt_block(b))
+            }
+            Keys::Aes256 { data, tweak } => {
+                xts(sector, number, tweak.as_ref(), |b| data.encrypt_block(b))
+            }
+        }
+    }
+
+    /// Decrypts one sector in place.
+    pub fn decrypt(&self, sector: &mut [u8], number: u64) {
+        match &self.keys {
+            Keys::Aes128 { data, tweak } => {
+                xts(sector, number, tweak.as_ref(), |b| data.decrypt_block(b))
+            }
+            Keys::Aes256 { data, tweak } => {
+                xts(sector, number, tweak.as_ref(), |b| data.decrypt_block(b))
+            }
+        }
+    }
+}
+
+type Block = GenericArray<u8, U16>;
+
+fn xts<C: BlockEncrypt<BlockSize = U16>>(
+    sector: &mut [u8],
+    number: u64,
+    tweak_key: &C,
+    mut crypt: impl FnMut(&mut Block),
+) {
+    assert!(sector.len().is_multiple_of(BLOCK), "Partial XTS block");
+
+    // The sector number as a little endian 128-bit value, like dm-crypt's
+    // plain64
+    let mut tweak = Block::default();
+    tweak[..8].copy_from_slice(&number.to_le_bytes());
+    tweak_key.encrypt_block(&mut tweak);
+
+    for chunk in sector.chunks_exact_mut(BLOCK) {
+        let block = Block::from_mut_slice(chunk);
+        xor(block, &tweak);
+        crypt(block);
+        xor(block, &tweak);
+        double(&mut tweak);
+    }
+}
+
+fn xor(block: &mut Block, tweak: &Block) {
+    for (b, t) in block.iter_mut().zip(tweak) {
+        *b ^= t;
+    }
+}
+
+/// Multiplies the tweak by x in GF(2^128), moving on to the next block.
+fn double(tweak: &mut Block) {
+    let mut carry = 0;
+    for byte in tweak.iter_mut() {
+        let next = *byte >> 7;
+        *byte = (*byte << 1) | carry;
+        carry = next;
+    }
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
@@ -0,0 +1,1003 @@
+//! Protocol conformance tests, driving `Server::handle` in-process over an
+//! in-memory stream. Magic numbers and codes are spelled out from the
+//! protocol document rather than taken from the crate, so a wrong constant
+//! fails here too.
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, Read, Write},
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use common::TempDir;
+use nbd::{client::Client, config::Config, Server};
+
+mod common;
+
+const INIT_MAGIC: &[u8] = b"NBDMAGIC";
+const OPTS_MAGIC: &[u8] = b"IHAVEOPT";
+const CLISERV_MAGIC: u64 = 0x00420281861253;
+const REP_MAGIC: u64 = 0x3e889045565a9;
+const REQUEST_MAGIC: u32 = 0x25609513;
+const EXTENDED_REQUEST_MAGIC: u32 = 0x21e41c71;
+const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+const STRUCTURED_REPLY_MAGIC: u32 = 0x668e33ef;
+const EXTENDED_REPLY_MAGIC: u32 = 0x6e8a278c;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_STARTTLS: u32 = 5;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+const OPT_STRUCTURED_REPLY: u32 = 8;
+const OPT_LIST_META_CONTEXT: u32 = 9;
+const OPT_SET_META_CONTEXT: u32 = 10;
+const OPT_EXTENDED_HEADERS: u32 = 11;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_META_CONTEXT: u32 = 4;
+const REP_ERR_UNSUP: u32 = 1 << 31 | 1;
+const REP_ERR_POLICY: u32 = 1 << 31 | 2;
+const REP_ERR_INVALID: u32 = 1 << 31 | 3;
+const REP_ERR_UNKNOWN: u32 = 1 << 31 | 6;
+
+const INFO_EXPORT: u16 = 0;
+const INFO_NAME: u16 = 1;
+const INFO_DESCRIPTION: u16 = 2;
+const INFO_BLOCK_SIZE: u16 = 3;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+const CMD_CACHE: u16 = 5;
+const CMD_WRITE_ZEROES: u16 = 6;
+const CMD_BLOCK_STATUS: u16 = 7;
+const CMD_RESIZE: u16 = 8;
+
+const CMD_FLAG_DF: u16 = 1 << 2;
+
+const REPLY_FLAG_DONE: u16 = 1;
+const REPLY_TYPE_NONE: u16 = 0;
+const REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+const REPLY_TYPE_BLOCK_STATUS_EXT: u16 = 6;
+const REPLY_TYPE_ERROR: u16 = 1 << 15 | 1;
+
+const EPERM: u32 = 1;
+const EINVAL: u32 = 22;
+const ENOSPC: u32 = 28;
+
+// HAS_FLAGS, SEND_DF and CAN_MULTI_CONN, what a file export gets by default
+const DEFAULT_FLAGS: u16 = 0x0181;
+const DISK_SIZE: u64 = 64 * 1024;
+
+/// One direction of a duplex stream, reads block until there is data or
+/// the writer is gone.
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<(VecDeque<u8>, bool)>,
+    ready: Condvar,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.ready.notify_all();
+    }
+}
+
+struct Duplex {
+    rx: Arc<Pipe>,
+    tx: Arc<Pipe>,
+}
+
+fn duplex() -> (Duplex, Duplex) {
+    let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+    (
+        Duplex {
+            rx: Arc::clone(&a),
+            tx: Arc::clone(&b),
+        },
+        Duplex { rx: b, tx: a },
+    )
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.rx.state.lock().unwrap();
+        while state.0.is_empty() && !state.1 {
+            state = self.rx.ready.wait(state).unwrap();
+        }
+
+        let n = buf.len().min(state.0.len());
+        for (b, byte) in buf.iter_mut().zip(state.0.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx.state.lock().unwrap().0.extend(buf);
+        self.tx.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Duplex {
+    fn drop(&mut self) {
+        self.tx.close();
+        self.rx.close();
+    }
+}
+
+/// The client side of a connection to a server running on its own thread.
+struct Conn {
+    stream: Duplex,
+    server: Option<JoinHandle<anyhow::Result<()>>>,
+    shared: Arc<Server>,
+    dir: Arc<TempDir>,
+}
+
+/// Serves the exports of `config`, where `{dir}` is a fresh directory
+/// holding `disk.img`, 64KiB of a known pattern.
+fn serve(config: &str) -> Conn {
+    serve_with(config, |_| {})
+}
+
+fn serve_with(config: &str, setup: impl FnOnce(&mut Client<Duplex>)) -> Conn {
+    let dir = TempDir::new();
+    fs::write(dir.0.join("disk.img"), pattern(0, DISK_SIZE as usize)).unwrap();
+
+    let config = Config::parse(&config.replace("{dir}", dir.0.to_str().unwrap())).unwrap();
+    let server = Server::with_options(config.exports().unwrap(), config.server_options().unwrap());
+
+    connect(Arc::new(server), Arc::new(dir), setup)
+}
+
+fn connect(
+    server: Arc<Server>,
+    dir: Arc<TempDir>,
+    setup: impl FnOnce(&mut Client<Duplex>),
+) -> Conn {
+    let (ours, theirs) = duplex();
+    let mut client = Client::new(theirs, String::from("test"));
+    setup(&mut client);
+    let shared = Arc::clone(&server);
+    let handle = thread::spawn(move || shared.handle(&mut client));
+
+    Conn {
+        stream: ours,
+        server: Some(handle),
+        shared: server,
+        dir,
+    }
+}
+
+const DISK: &str = r#"
+[[exports]]
+name = "disk"
+description = "A disk"
+path = "{dir}/disk.img"
+"#;
+
+fn pattern(offset: u64, len: usize) -> Vec<u8> {
+    (offset..offset + len as u64)
+        .map(|i| (i % 251) as u8)
+        .collect()
+}
+
+fn be(parts: &[&[u8]]) -> Vec<u8> {
+    parts.concat()
+}
+
+impl Conn {
+    /// Connects another client to the same server.
+    fn another(&self) -> Conn {
+        connect(Arc::clone(&self.shared), Arc::clone(&self.dir), |_| {})
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        self.stream.write_all(data).unwrap();
+    }
+
+    fn recv(&mut self, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        self.stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn recv_u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.recv(2).try_into().unwrap())
+    }
+
+    fn recv_u32(&mut self) -> u32 {
+        u32::from_be_bytes(self.recv(4).try_into().unwrap())
+    }
+
+    fn recv_u64(&mut self) -> u64 {
+        u64::from_be_bytes(self.recv(8).try_into().unwrap())
+    }
+
+    /// Checks the greeting and answers it with `flags`.
+    fn newstyle(&mut self, flags: u32) {
+        let greeting = self.recv(18);
+        assert_eq!(greeting, be(&[INIT_MAGIC, OPTS_MAGIC, &[0, 3]]));
+        self.send(&flags.to_be_bytes());
+    }
+
+    fn option(&mut self, option: u32, data: &[u8]) {
+        let len = (data.len() as u32).to_be_bytes();
+        self.send(&be(&[OPTS_MAGIC, &option.to_be_bytes(), &len, data]));
+    }
+
+    /// Reads an option reply, returning its type and data.
+    fn reply(&mut self, option: u32) -> (u32, Vec<u8>) {
+        assert_eq!(self.recv_u64(), REP_MAGIC);
+        assert_eq!(self.recv_u32(), option);
+        let reply_type = self.recv_u32();
+        let len = self.recv_u32() as usize;
+
+        (reply_type, self.recv(len))
+    }
+
+    /// Sends INFO or GO, returning every reply up to the final one.
+    fn info(&mut self, option: u32, name: &str, requests: &[u16]) -> Vec<(u32, Vec<u8>)> {
+        let mut data = be(&[&(name.len() as u32).to_be_bytes(), name.as_bytes()]);
+        data.extend_from_slice(&(requests.len() as u16).to_be_bytes());
+        for request in requests {
+            data.extend_from_slice(&request.to_be_bytes());
+        }
+        self.option(option, &data);
+
+        let mut replies = Vec::new();
+        loop {
+            let reply = self.reply(option);
+            let last = reply.0 != REP_INFO;
+            replies.push(reply);
+            if last {
+                return replies;
+            }
+        }
+    }
+
+    /// Negotiates fixed newstyle and enters transmission with `name`.
+    fn go(&mut self, name: &str) {
+        self.newstyle(3);
+        let replies = self.info(OPT_GO, name, &[]);
+        assert_eq!(replies.last().unwrap().0, REP_ACK);
+    }
+
+    fn structured_reply(&mut self) {
+        self.option(OPT_STRUCTURED_REPLY, &[]);
+        assert_eq!(self.reply(OPT_STRUCTURED_REPLY), (REP_ACK, vec![]));
+    }
+
+    fn request(&mut self, flags: u16, cmd: u16, handle: u64, offset: u64, len: u32) {
+        self.send(&be(&[
+            &REQUEST_MAGIC.to_be_bytes(),
+            &flags.to_be_bytes(),
+            &cmd.to_be_bytes(),
+            &handle.to_be_bytes(),
+            &offset.to_be_bytes(),
+            &len.to_be_bytes(),
+        ]));
+    }
+
+    fn extended_request(&mut self, flags: u16, cmd: u16, handle: u64, offset: u64, len: u64) {
+        self.send(&be(&[
+            &EXTENDED_REQUEST_MAGIC.to_be_bytes(),
+            &flags.to_be_bytes(),
+            &cmd.to_be_bytes(),
+            &handle.to_be_bytes(),
+            &offset.to_be_bytes(),
+            &len.to_be_bytes(),
+        ]));
+    }
+
+    /// Reads a simple reply to `handle`, returning its error.
+    fn simple_reply(&mut self, handle: u64) -> u32 {
+        assert_eq!(self.recv_u32(), SIMPLE_REPLY_MAGIC);
+        let error = self.recv_u32();
+        assert_eq!(self.recv_u64(), handle);
+
+        error
+    }
+
+    /// Reads a structured reply chunk for `handle`, returning its flags, type
+    /// and payload.
+    fn chunk(&mut self, handle: u64) -> (u16, u16, Vec<u8>) {
+        assert_eq!(self.recv_u32(), STRUCTURED_REPLY_MAGIC);
+        let flags = self.recv_u16();
+        let reply_type = self.recv_u16();
+        assert_eq!(self.recv_u64(), handle);
+        let len = self.recv_u32() as usize;
+
+        (flags, reply_type, self.recv(len))
+    }
+
+    /// Reads an extended reply chunk for `handle` at `offset`.
+    fn extended_chunk(&mut self, handle: u64, offset: u64) -> (u16, u16, Vec<u8>) {
+        assert_eq!(self.recv_u32(), EXTENDED_REPLY_MAGIC);
+        let flags = self.recv_u16();
+        let reply_type = self.recv_u16();
+        assert_eq!(self.recv_u64(), handle);
+        assert_eq!(self.recv_u64(), offset);
+        let len = self.recv_u64() as usize;
+
+        (flags, reply_type, self.recv(len))
+    }
+
+    /// Checks the server hung up without failing.
+    fn assert_closed(&mut self) {
+        let mut rest = Vec::new();
+        self.stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"", "Unexpected data before the connection closed");
+        self.server.take().unwrap().join().unwrap().unwrap();
+    }
+
+    /// Checks the server hung up on a misbehaving client.
+    fn assert_dropped(&mut self) {
+        let mut rest = Vec::new();
+        self.stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"", "Unexpected data before the connection closed");
+        assert!(self.server.take().unwrap().join().unwrap().is_err());
+    }
+}
+
+fn error_chunk(error: u32) -> (u16, u16, Vec<u8>) {
+    (
+        REPLY_FLAG_DONE,
+        REPLY_TYPE_ERROR,
+        be(&[&error.to_be_bytes(), &[0, 0]]),
+    )
+}
+
+#[test]
+fn test_greeting_and_unknown_client_flags() {
+    let mut conn = serve(DISK);
+    conn.newstyle(1 << 2);
+    conn.assert_closed();
+}
+
+#[test]
+fn test_export_name_padding() {
+    let mut conn = serve(DISK);
+    conn.newstyle(1);
+    conn.option(OPT_EXPORT_NAME, b"disk");
+    let expected = be(&[
+        &DISK_SIZE.to_be_bytes(),
+        &DEFAULT_FLAGS.to_be_bytes(),
+        &[0; 124],
+    ]);
+    assert_eq!(conn.recv(expected.len()), expected);
+    conn.request(0, CMD_DISC, 1, 0, 0);
+    conn.assert_closed();
+
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+    conn.option(OPT_EXPORT_NAME, b"disk");
+    let expected = be(&[&DISK_SIZE.to_be_bytes(), &DEFAULT_FLAGS.to_be_bytes()]);
+    assert_eq!(conn.recv(expected.len()), expected);
+    conn.request(0, CMD_FLUSH, 7, 0, 0);
+    assert_eq!(conn.simple_reply(7), 0);
+}
+
+#[test]
+fn test_export_name_unknown_export() {
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+    conn.option(OPT_EXPORT_NAME, b"missing");
+    conn.assert_closed();
+}
+
+#[test]
+fn test_list() {
+    let mut conn = serve(&format!(
+        "{}\n[[exports]]\nname = \"other\"\npath = \"{{dir}}/disk.img\"\nread_only = true\n",
+        DISK
+    ));
+    conn.newstyle(3);
+    conn.option(OPT_LIST, &[]);
+    assert_eq!(
+        conn.reply(OPT_LIST),
+        (REP_SERVER, be(&[&4u32.to_be_bytes(), b"disk", b"A disk"]))
+    );
+    assert_eq!(
+        conn.reply(OPT_LIST),
+        (REP_SERVER, be(&[&5u32.to_be_bytes(), b"other"]))
+    );
+    assert_eq!(conn.reply(OPT_LIST), (REP_ACK, vec![]));
+}
+
+#[test]
+fn test_options_without_data() {
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+    for option in [
+        OPT_LIST,
+        OPT_STARTTLS,
+        OPT_STRUCTURED_REPLY,
+        OPT_EXTENDED_HEADERS,
+    ] {
+        conn.option(option, b"x");
+        assert_eq!(conn.reply(option), (REP_ERR_INVALID, vec![]));
+    }
+
+    // Without a TLS configuration there is nothing to upgrade to
+    conn.option(OPT_STARTTLS, &[]);
+    assert_eq!(conn.reply(OPT_STARTTLS), (REP_ERR_UNSUP, vec![]));
+}
+
+#[test]
+fn test_unknown_option() {
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+    conn.option(0x1234, b"whatever");
+    let expected = be(&[
+        &REP_MAGIC.to_be_bytes(),
+        &0x1234u32.to_be_bytes(),
+        &REP_ERR_UNSUP.to_be_bytes(),
+        &0u32.to_be_bytes(),
+    ]);
+    assert_eq!(conn.recv(expected.len()), expected);
+
+    conn.option(OPT_ABORT, &[]);
+    assert_eq!(conn.reply(OPT_ABORT), (REP_ACK, vec![]));
+    conn.assert_closed();
+}
+
+#[test]
+fn test_info_requested_types() {
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+
+    let export = (
+        REP_INFO,
+        be(&[
+            &INFO_EXPORT.to_be_bytes(),
+            &DISK_SIZE.to_be_bytes(),
+            &DEFAULT_FLAGS.to_be_bytes(),
+        ]),
+    );
+    assert_eq!(
+        conn.info(OPT_INFO, "disk", &[]),
+        vec![export.clone(), (REP_ACK, vec![])]
+    );
+
+    let replies = conn.info(
+        OPT_INFO,
+        "disk",
+        &[INFO_NAME, INFO_DESCRIPTION, INFO_BLOCK_SIZE],
+    );
+    assert_eq!(
+        replies,
+        vec![
+            (REP_INFO, be(&[&INFO_NAME.to_be_bytes(), b"disk"])),
+            (REP_INFO, be(&[&INFO_DESCRIPTION.to_be_bytes(), b"A disk"])),
+            (
+                REP_INFO,
+                be(&[
+                    &INFO_BLOCK_SIZE.to_be_bytes(),
+                    &1u32.to_be_bytes(),
+                    &4096u32.to_be_bytes(),
+                    &(32u32 << 20).to_be_bytes(),
+                ])
+            ),
+            export,
+            (REP_ACK, vec![]),
+        ]
+    );
+
+    // INFO leaves the client in the option phase
+    let replies = conn.info(OPT_GO, "disk", &[]);
+    assert_eq!(replies.last().unwrap().0, REP_ACK);
+}
+
+#[test]
+fn test_info_and_go_payloads() {
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+    for option in [OPT_INFO, OPT_GO] {
+        // Truncated name, no request count, a count without the requests
+        // and trailing garbage
+        for data in [
+            &[0, 0, 0, 9, b'd'][..],
+            &be(&[&4u32.to_be_bytes(), b"disk"]),
+            &be(&[&4u32.to_be_bytes(), b"disk", &[0, 1]]),
+            &be(&[&4u32.to_be_bytes(), b"disk", &[0, 0, 0]]),
+        ] {
+            conn.option(option, data);
+            assert_eq!(conn.reply(option), (REP_ERR_INVALID, vec![]));
+        }
+
+        assert_eq!(
+            conn.info(option, "missing", &[]),
+            vec![(REP_ERR_UNKNOWN, vec![])]
+        );
+    }
+}
+
+#[test]
+fn test_simple_replies() {
+    let mut conn = serve(&format!("{}trim = true\nwrite_zeroes = true\n", DISK));
+    conn.go("disk");
+
+    conn.request(0, CMD_READ, 1, 100, 300);
+    assert_eq!(conn.simple_reply(1), 0);
+    assert_eq!(conn.recv(300), pattern(100, 300));
+
+    conn.request(0, CMD_WRITE, 2, 1000, 4);
+    conn.send(b"abcd");
+    assert_eq!(conn.simple_reply(2), 0);
+    conn.request(0, CMD_READ, 3, 998, 8);
+    assert_eq!(conn.simple_reply(3), 0);
+    let mut expected = pattern(998, 8);
+    expected[2..6].copy_from_slice(b"abcd");
+    assert_eq!(conn.recv(8), expected);
+
+    conn.request(0, CMD_WRITE_ZEROES, 4, 4096, 4096);
+    assert_eq!(conn.simple_reply(4), 0);
+    conn.request(0, CMD_READ, 5, 4096, 4096);
+    assert_eq!(conn.simple_reply(5), 0);
+    assert_eq!(conn.recv(4096), vec![0; 4096]);
+
+    for (handle, cmd) in [(6, CMD_TRIM), (7, CMD_CACHE), (8, CMD_FLUSH)] {
+        conn.request(0, cmd, handle, 8192, 4096);
+        assert_eq!(conn.simple_reply(handle), 0);
+    }
+
+    conn.request(0, CMD_DISC, 9, 0, 0);
+    conn.assert_closed();
+}
+
+#[test]
+fn test_simple_reply_errors() {
+    let mut conn = serve(DISK);
+    conn.go("disk");
+
+    conn.request(0, CMD_READ, 1, DISK_SIZE - 1, 2);
+    assert_eq!(conn.simple_reply(1), EINVAL);
+    conn.request(0, CMD_WRITE, 2, DISK_SIZE - 1, 2);
+    conn.send(b"xx");
+    assert_eq!(conn.simple_reply(2), ENOSPC);
+    conn.request(0, CMD_TRIM, 3, DISK_SIZE, 1);
+    assert_eq!(conn.simple_reply(3), EINVAL);
+    // Block status needs a metadata context, resizing `resize = true`
+    conn.request(0, CMD_BLOCK_STATUS, 4, 0, 512);
+    assert_eq!(conn.simple_reply(4), EINVAL);
+    conn.request(0, CMD_RESIZE, 5, 2 * DISK_SIZE, 0);
+    assert_eq!(conn.simple_reply(5), EINVAL);
+    conn.request(0, 0x4242, 6, 0, 0);
+    assert_eq!(conn.simple_reply(6), EINVAL);
+
+    // The connection is still usable
+    conn.request(0, CMD_READ, 7, 0, 16);
+    assert_eq!(conn.simple_reply(7), 0);
+    assert_eq!(conn.recv(16), pattern(0, 16));
+}
+
+#[test]
+fn test_bad_request_magic() {
+    let mut conn = serve(DISK);
+    conn.go("disk");
+    conn.request(0, CMD_READ, 1, 0, 16);
+    assert_eq!(conn.simple_reply(1), 0);
+    assert_eq!(conn.recv(16), pattern(0, 16));
+
+    // Past a bad magic there is no telling where the next request starts,
+    // so no reply may come from guessing
+    conn.send(&be(&[
+        &0x12345678u32.to_be_bytes(),
+        &0u16.to_be_bytes(),
+        &CMD_READ.to_be_bytes(),
+        &2u64.to_be_bytes(),
+        &0u64.to_be_bytes(),
+        &16u32.to_be_bytes(),
+    ]));
+    conn.assert_dropped();
+}
+
+#[test]
+fn test_read_only() {
+    let mut conn = serve(&format!("{}read_only = true\n", DISK));
+    conn.newstyle(3);
+    let replies = conn.info(OPT_GO, "disk", &[]);
+    // READ_ONLY on top of the defaults
+    let flags = DEFAULT_FLAGS | 1 << 1;
+    assert_eq!(
+        replies[0].1,
+        be(&[
+            &INFO_EXPORT.to_be_bytes(),
+            &DISK_SIZE.to_be_bytes(),
+            &flags.to_be_bytes()
+        ])
+    );
+
+    conn.request(0, CMD_WRITE, 1, 0, 4);
+    conn.send(b"nope");
+    assert_eq!(conn.simple_reply(1), EPERM);
+    for (handle, cmd) in [(2, CMD_TRIM), (3, CMD_WRITE_ZEROES)] {
+        conn.request(0, cmd, handle, 0, 4096);
+        assert_eq!(conn.simple_reply(handle), EPERM);
+    }
+}
+
+/// The flags GO or INFO announce for the disk export.
+fn announced(replies: &[(u32, Vec<u8>)]) -> u16 {
+    assert_eq!(replies[0].0, REP_INFO);
+    u16::from_be_bytes(replies[0].1[10..12].try_into().unwrap())
+}
+
+#[test]
+fn test_handshake_deadline() {
+    let mut conn = serve(&format!("[server]\nhandshake_timeout = 1\n{}", DISK));
+    let start = Instant::now();
+    conn.newstyle(3);
+
+    // Each byte comes well within the timeout, the option never does
+    let len = (64 * 1024u32).to_be_bytes();
+    conn.send(&be(&[OPTS_MAGIC, &OPT_GO.to_be_bytes(), &len]));
+    while !conn.server.as_ref().unwrap().is_finished() {
+        assert!(start.elapsed() < Duration::from_secs(5), "Still connected");
+        conn.send(&[0]);
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    conn.assert_closed();
+}
+
+#[test]
+fn test_writers() {
+    let config = format!("{}writers = \"downgrade\"\nmax_connections = 2\n", DISK);
+    let mut writer = serve(&config);
+
+    // INFO takes no place, or the writer couldn't connect after it
+    let mut reader = writer.another();
+    reader.newstyle(3);
+    for _ in 0..3 {
+        assert_eq!(
+            announced(&reader.info(OPT_INFO, "disk", &[])),
+            DEFAULT_FLAGS
+        );
+    }
+    writer.go("disk");
+    writer.request(0, CMD_WRITE, 1, 0, 4);
+    writer.send(b"ours");
+    assert_eq!(writer.simple_reply(1), 0);
+
+    // A later client gets the export read-only
+    let flags = DEFAULT_FLAGS | 1 << 1;
+    assert_eq!(announced(&reader.info(OPT_INFO, "disk", &[])), flags);
+    let replies = reader.info(OPT_GO, "disk", &[]);
+    assert_eq!(announced(&replies), flags);
+    reader.request(0, CMD_WRITE, 1, 0, 4);
+    reader.send(b"mine");
+    assert_eq!(reader.simple_reply(1), EPERM);
+    reader.request(0, CMD_READ, 2, 0, 4);
+    assert_eq!(reader.simple_reply(2), 0);
+    assert_eq!(reader.recv(4), b"ours");
+
+    // Both places are taken
+    let mut third = writer.another();
+    third.newstyle(3);
+    let replies = third.info(OPT_INFO, "disk", &[]);
+    assert_eq!(replies.last().unwrap().0, REP_ERR_POLICY);
+}
+
+#[test]
+fn test_single_writer() {
+    let mut writer = serve(&format!("{}writers = \"single\"\n", DISK));
+    writer.go("disk");
+
+    // Later clients are refused while the writer is connected
+    let mut refused = writer.another();
+    refused.newstyle(3);
+    for option in [OPT_INFO, OPT_GO] {
+        let replies = refused.info(option, "disk", &[]);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].0, REP_ERR_POLICY);
+    }
+    let mut hung_up = writer.another();
+    hung_up.newstyle(3);
+    hung_up.option(OPT_EXPORT_NAME, b"disk");
+    hung_up.assert_closed();
+
+    writer.request(0, CMD_DISC, 1, 0, 0);
+    writer.assert_closed();
+
+    // And may write once it is gone
+    assert_eq!(announced(&refused.info(OPT_GO, "disk", &[])), DEFAULT_FLAGS);
+    refused.request(0, CMD_WRITE, 1, 0, 4);
+    refused.send(b"mine");
+    assert_eq!(refused.simple_reply(1), 0);
+}
+
+#[test]
+fn test_throttled_invalid_requests() {
+    let mut conn = serve(&format!("{}\n[exports.limits]\nbandwidth = 65536\n", DISK));
+    conn.go("disk");
+
+    // Refused before being charged, or the next read would wait for hours
+    conn.request(0, CMD_READ, 1, 0, u32::MAX);
+    assert_eq!(conn.simple_reply(1), EINVAL);
+    conn.request(0, CMD_WRITE, 2, 0, 64 * 1024 * 1024);
+    conn.send(&vec![0; 64 * 1024 * 1024]);
+    assert_eq!(conn.simple_reply(2), EINVAL);
+
+    let start = Instant::now();
+    conn.request(0, CMD_READ, 3, 0, 4096);
+    assert_eq!(conn.simple_reply(3), 0);
+    assert_eq!(conn.recv(4096), pattern(0, 4096));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn test_structured_replies() {
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+    conn.structured_reply();
+    let replies = conn.info(OPT_GO, "disk", &[]);
+    assert_eq!(replies.last().unwrap().0, REP_ACK);
+
+    // Reads are split into 4KiB chunks unless they mustn't be
+    conn.request(0, CMD_READ, 1, 100, 5000);
+    assert_eq!(
+        conn.chunk(1),
+        (
+            0,
+            REPLY_TYPE_OFFSET_DATA,
+            be(&[&100u64.to_be_bytes(), &pattern(100, 4096)])
+        )
+    );
+    assert_eq!(
+        conn.chunk(1),
+        (
+            0,
+            REPLY_TYPE_OFFSET_DATA,
+            be(&[&4196u64.to_be_bytes(), &pattern(4196, 904)])
+        )
+    );
+    assert_eq!(conn.chunk(1), (REPLY_FLAG_DONE, REPLY_TYPE_NONE, vec![]));
+
+    conn.request(CMD_FLAG_DF, CMD_READ, 2, 0, 5000);
+    assert_eq!(
+        conn.chunk(2),
+        (
+            0,
+            REPLY_TYPE_OFFSET_DATA,
+            be(&[&0u64.to_be_bytes(), &pattern(0, 5000)])
+        )
+    );
+    assert_eq!(conn.chunk(2), (REPLY_FLAG_DONE, REPLY_TYPE_NONE, vec![]));
+
+    conn.request(0, CMD_READ, 3, DISK_SIZE, 1);
+    assert_eq!(conn.chunk(3), error_chunk(EINVAL));
+
+    // Without a payload to return a simple reply is still allowed
+    conn.request(0, CMD_WRITE, 4, 0, 2);
+    conn.send(b"ok");
+    assert_eq!(conn.simple_reply(4), 0);
+}
+
+#[test]
+fn test_block_status() {
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+
+    let mut query = be(&[&4u32.to_be_bytes(), b"disk", &1u32.to_be_bytes()]);
+    query.extend_from_slice(&be(&[&15u32.to_be_bytes(), b"base:allocation"]));
+
+    // Metadata contexts need structured replies
+    conn.option(OPT_LIST_META_CONTEXT, &query);
+    assert_eq!(conn.reply(OPT_LIST_META_CONTEXT), (REP_ERR_INVALID, vec![]));
+
+    conn.structured_reply();
+    conn.option(OPT_LIST_META_CONTEXT, &query);
+    assert_eq!(
+        conn.reply(OPT_LIST_META_CONTEXT),
+        (
+            REP_META_CONTEXT,
+            be(&[&0u32.to_be_bytes(), b"base:allocation"])
+        )
+    );
+    assert_eq!(conn.reply(OPT_LIST_META_CONTEXT), (REP_ACK, vec![]));
+
+    conn.option(OPT_SET_META_CONTEXT, &query);
+    assert_eq!(
+        conn.reply(OPT_SET_META_CONTEXT),
+        (
+            REP_META_CONTEXT,
+            be(&[&1u32.to_be_bytes(), b"base:allocation"])
+        )
+    );
+    assert_eq!(conn.reply(OPT_SET_META_CONTEXT), (REP_ACK, vec![]));
+
+    let replies = conn.info(OPT_GO, "disk", &[]);
+    assert_eq!(replies.last().unwrap().0, REP_ACK);
+
+    // The file was written in full, it is all data
+    conn.request(0, CMD_BLOCK_STATUS, 1, 0, DISK_SIZE as u32);
+    assert_eq!(
+        conn.chunk(1),
+        (
+            0,
+            REPLY_TYPE_BLOCK_STATUS,
+            be(&[
+                &1u32.to_be_bytes(),
+                &(DISK_SIZE as u32).to_be_bytes(),
+                &0u32.to_be_bytes()
+            ])
+        )
+    );
+    assert_eq!(conn.chunk(1), (REPLY_FLAG_DONE, REPLY_TYPE_NONE, vec![]));
+
+    conn.request(0, CMD_BLOCK_STATUS, 2, 0, 0);
+    assert_eq!(conn.chunk(2), error_chunk(EINVAL));
+}
+
+#[test]
+fn test_extended_headers() {
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+    conn.option(OPT_EXTENDED_HEADERS, &[]);
+    assert_eq!(conn.reply(OPT_EXTENDED_HEADERS), (REP_ACK, vec![]));
+    // They already include structured replies
+    conn.option(OPT_STRUCTURED_REPLY, &[]);
+    assert_eq!(conn.reply(OPT_STRUCTURED_REPLY), (REP_ERR_INVALID, vec![]));
+    let replies = conn.info(OPT_GO, "disk", &[]);
+    assert_eq!(replies.last().unwrap().0, REP_ACK);
+
+    conn.extended_request(0, CMD_WRITE, 1, 10, 3);
+    conn.send(b"ext");
+    assert_eq!(
+        conn.extended_chunk(1, 10),
+        (REPLY_FLAG_DONE, REPLY_TYPE_NONE, vec![])
+    );
+
+    conn.extended_request(0, CMD_READ, 2, 8, 6);
+    let mut expected = pattern(8, 6);
+    expected[2..5].copy_from_slice(b"ext");
+    assert_eq!(
+        conn.extended_chunk(2, 8),
+        (
+            0,
+            REPLY_TYPE_OFFSET_DATA,
+            be(&[&8u64.to_be_bytes(), &expected])
+        )
+    );
+    assert_eq!(
+        conn.extended_chunk(2, 8),
+        (REPLY_FLAG_DONE, REPLY_TYPE_NONE, vec![])
+    );
+
+    // 64-bit lengths are still limited
+    conn.extended_request(0, CMD_READ, 3, 0, 1 << 32);
+    assert_eq!(conn.extended_chunk(3, 0), error_chunk(EINVAL));
+}
+
+#[test]
+fn test_extended_block_status() {
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+    conn.option(OPT_EXTENDED_HEADERS, &[]);
+    assert_eq!(conn.reply(OPT_EXTENDED_HEADERS), (REP_ACK, vec![]));
+
+    let mut query = be(&[&4u32.to_be_bytes(), b"disk", &1u32.to_be_bytes()]);
+    query.extend_from_slice(&be(&[&15u32.to_be_bytes(), b"base:allocation"]));
+    conn.option(OPT_SET_META_CONTEXT, &query);
+    assert_eq!(conn.reply(OPT_SET_META_CONTEXT).0, REP_META_CONTEXT);
+    assert_eq!(conn.reply(OPT_SET_META_CONTEXT), (REP_ACK, vec![]));
+    let replies = conn.info(OPT_GO, "disk", &[]);
+    assert_eq!(replies.last().unwrap().0, REP_ACK);
+
+    conn.extended_request(0, CMD_BLOCK_STATUS, 1, 0, DISK_SIZE);
+    assert_eq!(
+        conn.extended_chunk(1, 0),
+        (
+            0,
+            REPLY_TYPE_BLOCK_STATUS_EXT,
+            be(&[
+                &1u32.to_be_bytes(),
+                &1u32.to_be_bytes(),
+                &DISK_SIZE.to_be_bytes(),
+                &0u64.to_be_bytes()
+            ])
+        )
+    );
+    assert_eq!(
+        conn.extended_chunk(1, 0),
+        (REPLY_FLAG_DONE, REPLY_TYPE_NONE, vec![])
+    );
+}
+
+#[test]
+fn test_block_size_constraints() {
+    let mut conn = serve(&format!("{}[exports.block_size]\nminimum = 512\n", DISK));
+    conn.newstyle(3);
+    let replies = conn.info(OPT_GO, "disk", &[INFO_BLOCK_SIZE]);
+    assert_eq!(replies.last().unwrap().0, REP_ACK);
+
+    conn.request(0, CMD_READ, 1, 100, 512);
+    assert_eq!(conn.simple_reply(1), EINVAL);
+    conn.request(0, CMD_WRITE, 2, 0, 100);
+    conn.send(&[0; 100]);
+    assert_eq!(conn.simple_reply(2), EINVAL);
+    conn.request(0, CMD_READ, 3, 512, 512);
+    assert_eq!(conn.simple_reply(3), 0);
+    assert_eq!(conn.recv(512), pattern(512, 512));
+}
+
+#[test]
+fn test_non_fixed_newstyle() {
+    let mut conn = serve(DISK);
+    conn.newstyle(0);
+    conn.option(OPT_LIST, &[]);
+    assert_eq!(conn.reply(OPT_LIST).0, REP_SERVER);
+    assert_eq!(conn.reply(OPT_LIST), (REP_ACK, vec![]));
+
+    // There is no way to refuse the options of fixed newstyle
+    conn.option(OPT_GO, &be(&[&4u32.to_be_bytes(), b"disk", &[0, 0]]));
+    conn.assert_closed();
+}
+
+#[test]
+fn test_oldstyle() {
+    let mut conn = serve_with(DISK, |c| c.set_oldstyle(Some(String::from("disk"))));
+    let expected = be(&[
+        INIT_MAGIC,
+        &CLISERV_MAGIC.to_be_bytes(),
+        &DISK_SIZE.to_be_bytes(),
+        &(DEFAULT_FLAGS as u32).to_be_bytes(),
+        &[0; 124],
+    ]);
+    assert_eq!(conn.recv(expected.len()), expected);
+
+    conn.request(0, CMD_READ, 1, 0, 8);
+    assert_eq!(conn.simple_reply(1), 0);
+    assert_eq!(conn.recv(8), pattern(0, 8));
+
+    let mut conn = serve_with(DISK, |c| c.set_oldstyle(Some(String::from("missing"))));
+    conn.assert_closed();
+}
+
+#[test]
+fn test_option_limits() {
+    // The length alone gets the client dropped, before anything is allocated
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+    conn.send(&be(&[
+        OPTS_MAGIC,
+        &OPT_GO.to_be_bytes(),
+        &u32::MAX.to_be_bytes(),
+    ]));
+    conn.assert_closed();
+
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+    for _ in 0..256 {
+        conn.option(OPT_LIST, &[]);
+        assert_eq!(conn.reply(OPT_LIST).0, REP_SERVER);
+        assert_eq!(conn.reply(OPT_LIST), (REP_ACK, vec![]));
+    }
+    conn.option(OPT_LIST, &[]);
+    conn.assert_closed();
+
+    let mut conn = serve(DISK);
+    conn.newstyle(3);
+    conn.send(b"NOTANOPT");
+    conn.assert_closed();
+}
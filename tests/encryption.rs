@@ -0,0 +1,129 @@
+//! AES-XTS against published and independently computed vectors, and
+//! encrypted exports against the file they're stored in.
+use std::{fs, os::unix::fs::PermissionsExt, sync::Arc};
+
+use common::TempDir;
+use nbd::{
+    backend::{Aligned, Backend, Encrypted, FileBackend},
+    config::Config,
+    xts::Xts,
+};
+
+mod common;
+
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_xts_vectors() {
+    // IEEE 1619 vector 2
+    let xts = Xts::new(&[[0x11; 16], [0x22; 16]].concat()).unwrap();
+    let mut data = [0x44; 32];
+    xts.encrypt(&mut data, 0x3333333333);
+    assert_eq!(
+        data.to_vec(),
+        hex("c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0")
+    );
+    xts.decrypt(&mut data, 0x3333333333);
+    assert_eq!(data, [0x44; 32]);
+
+    // A whole AES-256 sector, the tweak carrying over between blocks
+    let key: Vec<u8> = (0..64).collect();
+    let plaintext: Vec<u8> = (0..512).map(|i| i as u8).collect();
+    let xts = Xts::new(&key).unwrap();
+    let mut data = plaintext.clone();
+    xts.encrypt(&mut data, 5);
+    assert_eq!(data[..16], hex("f87ca2f29b117c1b024a6ec8e8c5994e"));
+    assert_eq!(data[496..], hex("e790ce5900c47286caaef5e457fecc4b"));
+    xts.decrypt(&mut data, 5);
+    assert_eq!(data, plaintext);
+}
+
+#[test]
+fn test_xts_keys() {
+    assert!(Xts::new(&[7; 48]).is_err());
+    assert!(Xts::new(&[0; 0]).is_err());
+    // Equal halves make XTS weaker
+    assert!(Xts::new(&[7; 32]).is_err());
+    assert!(Xts::new(&[[1; 32], [2; 32]].concat()).is_ok());
+}
+
+#[test]
+fn test_encrypted_backend() {
+    let dir = TempDir::new();
+    let path = dir.0.join("disk.img");
+    fs::write(&path, vec![0; 16 * 1024]).unwrap();
+
+    let key = [[1; 32], [2; 32]].concat();
+    let file = Arc::new(FileBackend::open(&path, false).unwrap());
+    let encrypted = Encrypted::new(file, Xts::new(&key).unwrap(), 4096).unwrap();
+    let backend = Aligned::new(Arc::new(encrypted), 4096).unwrap();
+    let block_size = backend.block_size().unwrap();
+    assert_eq!(block_size.minimum, 1);
+    assert_eq!(block_size.preferred, 4096);
+
+    // The plaintext file is garbage until written through the backend
+    backend.write_zeroes(0, 16 * 1024, false).unwrap();
+
+    // Unaligned requests are served through whole sectors
+    let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    backend.write_at(&data, 3000).unwrap();
+    backend.write_zeroes(9000, 3000, true).unwrap();
+    backend.flush().unwrap();
+
+    let mut expected = vec![0; 16 * 1024];
+    expected[3000..8000].copy_from_slice(&data);
+    expected[9000..12000].fill(0);
+    let mut buf = vec![0; 16 * 1024];
+    backend.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, expected);
+
+    // Only ciphertext reaches the file, each sector tweaked by its number
+    let stored = fs::read(&path).unwrap();
+    let xts = Xts::new(&key).unwrap();
+    for (i, sector) in expected.chunks_mut(4096).enumerate() {
+        xts.encrypt(sector, i as u64);
+    }
+    assert_eq!(stored, expected);
+
+    // Trimming must not leave sectors that decrypt to garbage
+    backend.trim(0, 16 * 1024).unwrap();
+    backend.read_at(&mut buf[..4096], 4096).unwrap();
+    assert_eq!(buf[..3904], data[1096..]);
+}
+
+#[test]
+fn test_encryption_config() {
+    let dir = TempDir::new();
+    fs::write(dir.0.join("disk.img"), vec![0; 64 * 1024]).unwrap();
+    let keyfile = dir.0.join("disk.key");
+    fs::write(&keyfile, [[3; 32], [4; 32]].concat()).unwrap();
+    fs::set_permissions(&keyfile, fs::Permissions::from_mode(0o600)).unwrap();
+
+    let config = |encryption: &str| {
+        let config = format!(
+            "[[exports]]\nname = \"disk\"\npath = \"{dir}/disk.img\"\noffset = 1000\n\n[exports.encryption]\n{}\n",
+            encryption,
+            dir = dir.0.display()
+        );
+        Config::parse(&config).and_then(|config| config.exports())
+    };
+
+    let keyfile = format!("keyfile = \"{}\"", keyfile.display());
+    let exports = config(&keyfile).unwrap();
+    assert_eq!(exports[0].size(), 64 * 1024 - 1000);
+
+    assert!(config(&format!("{}\nsector_size = 4096", keyfile)).is_ok());
+    assert!(config(&format!("{}\nsector_size = 1000", keyfile)).is_err());
+    assert!(config(&format!("{}\nsector_size = 8192", keyfile)).is_err());
+    assert!(config("keyfile = \"/nonexistent/disk.key\"").is_err());
+    assert!(config(&format!("{}\ncipher = \"aes\"", keyfile)).is_err());
+
+    let short = dir.0.join("short.key");
+    fs::write(&short, [5; 16]).unwrap();
+    assert!(config(&format!("keyfile = \"{}\"", short.display())).is_err());
+}
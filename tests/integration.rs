@@ -1,23 +1,26 @@
 /// The tests rely on qemu-img and nbdinfo to be installed.
 #[cfg(test)]
 mod tests {
-    use nbd::{unix, Export};
+    use nbd::{unix, Export, Server};
     use serde_json::{self, Value};
     use std::{
-        path::Path,
+        path::PathBuf,
         process::Command,
         sync::{atomic::AtomicBool, Arc},
         thread::{self, JoinHandle},
     };
 
-    const TEST_FILE: &str = "/tmp/test.img";
+    // Per process, so parallel runs don't share them
+    fn test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nbd-{}-{}", std::process::id(), name))
+    }
 
     fn create_export_file() -> Result<(), Box<dyn std::error::Error>> {
         Command::new("qemu-img")
             .arg("create")
             .arg("-f")
             .arg("qcow2")
-            .arg(TEST_FILE)
+            .arg(test_path("test.img"))
             .arg("1g")
             .output()?;
 
@@ -29,13 +32,15 @@ mod tests {
     ) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
         create_export_file()?;
         let export = Export::init_export(
-            TEST_FILE.to_string(),
+            test_path("test.img").to_string_lossy().into_owned(),
             String::from("test"),
             String::from("test"),
         )?;
 
+        let server = Arc::new(Server::new(export));
+
         let handle = thread::spawn(move || {
-            unix::start_unix_socket_server(&export, Path::new("/tmp/nbd.sock"), &stop_server)
+            unix::start_unix_socket_server(&server, &test_path("nbd.sock"), None, &stop_server)
                 .unwrap();
         });
         Ok(handle)
@@ -48,7 +53,10 @@ mod tests {
 
         let output = Command::new("qemu-img")
             .arg("info")
-            .arg("nbd+unix://?socket=/tmp/nbd.sock")
+            .arg(format!(
+                "nbd+unix://?socket={}",
+                test_path("nbd.sock").display()
+            ))
             .arg("--output")
             .arg("json")
             .output()
@@ -61,9 +69,9 @@ mod tests {
 
         assert!(output.status.success());
         assert_eq!(v["format"].as_str().unwrap(), "qcow2");
-        assert_eq!(v["virtual-size"].as_u64(), Some(1073741824 as u64));
+        assert_eq!(v["virtual-size"].as_u64(), Some(1073741824_u64));
 
         // Cleanup
-        std::fs::remove_file(TEST_FILE).unwrap();
+        std::fs::remove_file(test_path("test.img")).unwrap();
     }
 }
@@ -0,0 +1,409 @@
+//! LUKS volumes built here the way cryptsetup lays them out, opened through
+//! `luks::open` and export configs.
+use std::{fs, path::Path, sync::Arc};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64ct::{Base64, Encoding};
+use common::TempDir;
+use nbd::{
+    backend::{Backend, FileBackend},
+    config::Config,
+    luks,
+    xts::Xts,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+mod common;
+
+const PASSPHRASE: &[u8] = b"correct horse battery staple";
+const STRIPES: usize = 4000;
+const PAYLOAD_LEN: usize = 64 * 1024;
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn volume_key() -> Vec<u8> {
+    (0..64).map(|i| (i * 7 + 3) as u8).collect()
+}
+
+fn encrypt(key: &[u8], data: &mut [u8], sector: usize) {
+    let xts = Xts::new(key).unwrap();
+    for (i, chunk) in data.chunks_exact_mut(sector).enumerate() {
+        xts.encrypt(chunk, i as u64);
+    }
+}
+
+fn diffuse(buf: &mut [u8]) {
+    for (i, chunk) in buf.chunks_mut(32).enumerate() {
+        let hash = Sha256::new()
+            .chain_update((i as u32).to_be_bytes())
+            .chain_update(&*chunk)
+            .finalize();
+        let len = chunk.len();
+        chunk.copy_from_slice(&hash[..len]);
+    }
+}
+
+/// Splits the key into anti-forensic stripes with SHA-256, encrypted with
+/// `kdf_key` and padded to whole sectors.
+fn key_material(key: &[u8], kdf_key: &[u8]) -> Vec<u8> {
+    let mut material = Vec::new();
+    let mut d = vec![0; key.len()];
+    for i in 0..STRIPES - 1 {
+        let stripe: Vec<u8> = (0..key.len()).map(|j| (i * 31 + j) as u8).collect();
+        for (d, s) in d.iter_mut().zip(&stripe) {
+            *d ^= s;
+        }
+        diffuse(&mut d);
+        material.extend_from_slice(&stripe);
+    }
+    material.extend(d.iter().zip(key).map(|(d, k)| d ^ k));
+
+    material.resize(material.len().div_ceil(512) * 512, 0);
+    encrypt(kdf_key, &mut material, 512);
+    material
+}
+
+fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32, len: usize) -> Vec<u8> {
+    let mut out = vec![0; len];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut out);
+    out
+}
+
+fn pad(s: &[u8], len: usize) -> Vec<u8> {
+    let mut buf = s.to_vec();
+    buf.resize(len, 0);
+    buf
+}
+
+/// A LUKS1 volume with aes-xts-plain64 and SHA-256, keyslot 2 holding the
+/// passphrase, and `payload` encrypted after the key material.
+fn luks1(payload: &[u8]) -> Vec<u8> {
+    let key = volume_key();
+    let salt = [0x5a; 32];
+    let digest_salt = [0xa5; 32];
+    let material_offset = 8;
+    // Room for eight keyslots of 4000 stripes of 64 bytes
+    let payload_offset = 4096;
+
+    let mut header = b"LUKS\xba\xbe\x00\x01".to_vec();
+    header.extend(pad(b"aes", 32));
+    header.extend(pad(b"xts-plain64", 32));
+    header.extend(pad(b"sha256", 32));
+    header.extend((payload_offset as u32).to_be_bytes());
+    header.extend((key.len() as u32).to_be_bytes());
+    header.extend(pbkdf2(&key, &digest_salt, 1000, 20));
+    header.extend(digest_salt);
+    header.extend(1000u32.to_be_bytes());
+    header.extend(pad(b"2ba8ee97-7b62-4ae6-b1cb-3f7d6ec0fc36", 40));
+    for i in 0..8u32 {
+        let active: u32 = if i == 2 { 0x00ac71f3 } else { 0x0000dead };
+        header.extend(active.to_be_bytes());
+        header.extend(1000u32.to_be_bytes());
+        header.extend(salt);
+        header.extend((material_offset + i * 504).to_be_bytes());
+        header.extend((STRIPES as u32).to_be_bytes());
+    }
+
+    let mut image = vec![0; payload_offset * 512];
+    image[..header.len()].copy_from_slice(&header);
+    let material = key_material(&key, &pbkdf2(PASSPHRASE, &salt, 1000, key.len()));
+    let at = (material_offset as usize + 2 * 504) * 512;
+    image[at..at + material.len()].copy_from_slice(&material);
+
+    let mut payload = payload.to_vec();
+    encrypt(&key, &mut payload, 512);
+    image.extend(payload);
+    image
+}
+
+const LUKS2_HEADER_LEN: usize = 16 * 1024;
+const LUKS2_KEYSLOTS_OFFSET: usize = 32 * 1024;
+const LUKS2_PAYLOAD_OFFSET: usize = 1024 * 1024;
+
+/// A LUKS2 volume with 4KiB sectors and two keyslots: an Argon2id one for
+/// `PASSPHRASE` and a PBKDF2 one for "spare".
+fn luks2(payload: &[u8]) -> Vec<u8> {
+    luks2_with(payload, |_| {})
+}
+
+/// Like [`luks2`], with its metadata changed by `edit` before it's written.
+fn luks2_with(payload: &[u8], edit: impl FnOnce(&mut serde_json::Value)) -> Vec<u8> {
+    let key = volume_key();
+    let digest_salt = [0x17; 32];
+    let mut image = vec![0; LUKS2_PAYLOAD_OFFSET];
+
+    let argon2_salt = [0x42; 32];
+    let mut argon2_key = vec![0; key.len()];
+    Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(64, 2, 2, Some(key.len())).unwrap(),
+    )
+    .hash_password_into(PASSPHRASE, &argon2_salt, &mut argon2_key)
+    .unwrap();
+    let pbkdf2_salt = [0x24; 32];
+    let pbkdf2_key = pbkdf2(b"spare", &pbkdf2_salt, 1000, key.len());
+
+    let mut keyslots = serde_json::Map::new();
+    let mut offset = LUKS2_KEYSLOTS_OFFSET;
+    for (name, kdf_key, kdf) in [
+        (
+            "0",
+            &pbkdf2_key,
+            json!({"type": "pbkdf2", "hash": "sha256", "iterations": 1000, "salt": Base64::encode_string(&pbkdf2_salt)}),
+        ),
+        (
+            "1",
+            &argon2_key,
+            json!({"type": "argon2id", "time": 2, "memory": 64, "cpus": 2, "salt": Base64::encode_string(&argon2_salt)}),
+        ),
+    ] {
+        let material = key_material(&key, kdf_key);
+        image[offset..offset + material.len()].copy_from_slice(&material);
+        keyslots.insert(
+            name.to_string(),
+            json!({
+                "type": "luks2",
+                "key_size": key.len(),
+                "af": {"type": "luks1", "stripes": STRIPES, "hash": "sha256"},
+                "area": {
+                    "type": "raw",
+                    "offset": offset.to_string(),
+                    "size": material.len().to_string(),
+                    "encryption": "aes-xts-plain64",
+                    "key_size": key.len(),
+                },
+                "kdf": kdf,
+            }),
+        );
+        offset += material.len();
+    }
+
+    let mut metadata = json!({
+        "keyslots": keyslots,
+        "tokens": {},
+        "segments": {"0": {
+            "type": "crypt",
+            "offset": LUKS2_PAYLOAD_OFFSET.to_string(),
+            "size": "dynamic",
+            "iv_tweak": "0",
+            "encryption": "aes-xts-plain64",
+            "sector_size": 4096,
+        }},
+        "digests": {"0": {
+            "type": "pbkdf2",
+            "keyslots": ["0", "1"],
+            "segments": ["0"],
+            "hash": "sha256",
+            "iterations": 1000,
+            "salt": Base64::encode_string(&digest_salt),
+            "digest": Base64::encode_string(&pbkdf2(&key, &digest_salt, 1000, 32)),
+        }},
+        "config": {
+            "json_size": (LUKS2_HEADER_LEN - 4096).to_string(),
+            "keyslots_size": (LUKS2_PAYLOAD_OFFSET - LUKS2_KEYSLOTS_OFFSET).to_string(),
+        },
+    });
+    edit(&mut metadata);
+
+    // Both copies of the header
+    for (offset, magic) in [(0, b"LUKS\xba\xbe"), (LUKS2_HEADER_LEN, b"SKUL\xba\xbe")] {
+        let header = &mut image[offset..offset + LUKS2_HEADER_LEN];
+        header[..6].copy_from_slice(magic);
+        header[6..8].copy_from_slice(&2u16.to_be_bytes());
+        header[8..16].copy_from_slice(&(LUKS2_HEADER_LEN as u64).to_be_bytes());
+        header[16..24].copy_from_slice(&1u64.to_be_bytes());
+        header[72..78].copy_from_slice(b"sha256");
+        header[256..264].copy_from_slice(&(offset as u64).to_be_bytes());
+        let json = serde_json::to_vec(&metadata).unwrap();
+        header[4096..4096 + json.len()].copy_from_slice(&json);
+        let checksum = Sha256::digest(&*header);
+        header[448..480].copy_from_slice(&checksum);
+    }
+
+    let mut payload = payload.to_vec();
+    encrypt(&key, &mut payload, 4096);
+    image.extend(payload);
+    image
+}
+
+fn open(path: &Path, passphrase: &[u8]) -> anyhow::Result<Arc<dyn Backend>> {
+    let file = Arc::new(FileBackend::open(path, false).unwrap());
+    luks::open(file, passphrase)
+}
+
+fn read_all(backend: &dyn Backend) -> Vec<u8> {
+    let mut buf = vec![0; backend.size().unwrap() as usize];
+    backend.read_at(&mut buf, 0).unwrap();
+    buf
+}
+
+#[test]
+fn test_luks1() {
+    let dir = TempDir::new();
+    let path = dir.0.join("luks1.img");
+    fs::write(&path, luks1(&pattern(PAYLOAD_LEN))).unwrap();
+
+    let volume = open(&path, PASSPHRASE).unwrap();
+    assert_eq!(volume.size().unwrap(), PAYLOAD_LEN as u64);
+    assert_eq!(volume.block_size().unwrap().minimum, 1);
+    assert_eq!(read_all(volume.as_ref()), pattern(PAYLOAD_LEN));
+
+    // Writes go to the payload encrypted, and read back after reopening
+    volume.write_at(b"hello", 1000).unwrap();
+    volume.flush().unwrap();
+    let stored = fs::read(&path).unwrap();
+    assert!(!stored.windows(5).any(|w| w == b"hello"));
+    let mut buf = [0; 5];
+    open(&path, PASSPHRASE)
+        .unwrap()
+        .read_at(&mut buf, 1000)
+        .unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[test]
+fn test_luks2() {
+    let dir = TempDir::new();
+    let path = dir.0.join("luks2.img");
+    fs::write(&path, luks2(&pattern(PAYLOAD_LEN))).unwrap();
+
+    // Either keyslot opens it
+    for passphrase in [PASSPHRASE, b"spare"] {
+        let volume = open(&path, passphrase).unwrap();
+        assert_eq!(volume.size().unwrap(), PAYLOAD_LEN as u64);
+        assert_eq!(volume.block_size().unwrap().minimum, 1);
+        assert_eq!(read_all(volume.as_ref()), pattern(PAYLOAD_LEN));
+    }
+
+    // Partial sectors are read and rewritten whole
+    let volume = open(&path, PASSPHRASE).unwrap();
+    volume.write_at(b"hello", 5000).unwrap();
+    let mut expected = pattern(PAYLOAD_LEN);
+    expected[5000..5005].copy_from_slice(b"hello");
+    assert_eq!(read_all(volume.as_ref()), expected);
+}
+
+#[test]
+fn test_wrong_passphrase() {
+    let dir = TempDir::new();
+    for (name, image) in [
+        ("luks1.img", luks1(&pattern(PAYLOAD_LEN))),
+        ("luks2.img", luks2(&pattern(PAYLOAD_LEN))),
+    ] {
+        let path = dir.0.join(name);
+        fs::write(&path, image).unwrap();
+
+        for passphrase in [&b"wrong"[..], b"", b"correct horse battery stapl"] {
+            let err = open(&path, passphrase).unwrap_err();
+            assert_eq!(format!("{:#}", err), "No keyslot matches the passphrase");
+        }
+    }
+}
+
+#[test]
+fn test_secondary_header() {
+    let dir = TempDir::new();
+    let path = dir.0.join("luks2.img");
+    let image = luks2(&pattern(PAYLOAD_LEN));
+
+    // The metadata or the checksum of the primary header broken
+    for at in [5000, 448] {
+        let mut damaged = image.clone();
+        damaged[at] ^= 1;
+        fs::write(&path, &damaged).unwrap();
+        let volume = open(&path, PASSPHRASE).unwrap();
+        assert_eq!(read_all(volume.as_ref()), pattern(PAYLOAD_LEN));
+
+        // Not when the secondary one is broken as well
+        damaged[LUKS2_HEADER_LEN + at] ^= 1;
+        fs::write(&path, &damaged).unwrap();
+        assert!(open(&path, PASSPHRASE).is_err());
+    }
+}
+
+#[test]
+fn test_priority_zero_keyslot() {
+    let dir = TempDir::new();
+    let path = dir.0.join("luks2.img");
+    let image = luks2_with(&pattern(PAYLOAD_LEN), |metadata| {
+        metadata["keyslots"]["0"]["priority"] = json!(0);
+    });
+    fs::write(&path, image).unwrap();
+
+    // Like cryptsetup, a keyslot with priority 0 is never tried on its own
+    let err = open(&path, b"spare").unwrap_err();
+    assert!(format!("{:#}", err).contains("No keyslot matches"));
+    let volume = open(&path, PASSPHRASE).unwrap();
+    assert_eq!(read_all(volume.as_ref()), pattern(PAYLOAD_LEN));
+
+    // Leaving none to try
+    let image = luks2_with(&pattern(PAYLOAD_LEN), |metadata| {
+        for slot in ["0", "1"] {
+            metadata["keyslots"][slot]["priority"] = json!(0);
+        }
+    });
+    fs::write(&path, image).unwrap();
+    let err = open(&path, PASSPHRASE).unwrap_err();
+    assert!(format!("{:#}", err).contains("No keyslot is in use"));
+}
+
+#[test]
+fn test_unusable_keyslot() {
+    let dir = TempDir::new();
+    let path = dir.0.join("luks2.img");
+    // The Argon2 keyslot comes first and wants 8GiB
+    let image = luks2_with(&pattern(PAYLOAD_LEN), |metadata| {
+        let slot = &mut metadata["keyslots"]["1"];
+        slot["priority"] = json!(2);
+        slot["kdf"]["memory"] = json!(8 * 1024 * 1024);
+    });
+    fs::write(&path, image).unwrap();
+
+    // It's passed over for the next one
+    let volume = open(&path, b"spare").unwrap();
+    assert_eq!(read_all(volume.as_ref()), pattern(PAYLOAD_LEN));
+
+    let err = open(&path, PASSPHRASE).unwrap_err();
+    assert!(format!("{:#}", err).contains("1 of them couldn't be tried"));
+}
+
+#[test]
+fn test_luks_config() {
+    let dir = TempDir::new();
+    fs::write(dir.0.join("disk.img"), luks2(&pattern(PAYLOAD_LEN))).unwrap();
+    fs::write(dir.0.join("plain.img"), pattern(PAYLOAD_LEN)).unwrap();
+    fs::write(dir.0.join("disk.key"), PASSPHRASE).unwrap();
+
+    let config = |file: &str, luks: &str| {
+        let config = format!(
+            "[[exports]]\nname = \"disk\"\npath = \"{dir}/{}\"\nread_only = true\n\n[exports.luks]\n{}\n",
+            file,
+            luks.replace("{dir}", &dir.0.display().to_string()),
+            dir = dir.0.display()
+        );
+        Config::parse(&config).and_then(|config| config.exports())
+    };
+
+    let exports = config("disk.img", "passphrase = \"correct horse battery staple\"").unwrap();
+    assert_eq!(exports[0].size(), PAYLOAD_LEN as u64);
+    assert!(config("disk.img", "keyfile = \"{dir}/disk.key\"").is_ok());
+
+    assert!(config("disk.img", "passphrase = \"wrong\"").is_err());
+    assert!(config("plain.img", "passphrase = \"correct horse battery staple\"").is_err());
+    assert!(config("disk.img", "").is_err());
+    assert!(config(
+        "disk.img",
+        "passphrase = \"x\"\nkeyfile = \"{dir}/disk.key\""
+    )
+    .is_err());
+    assert!(config("disk.img", "keyfile = \"{dir}/missing.key\"").is_err());
+    assert!(config(
+        "disk.img",
+        "keyfile = \"{dir}/disk.key\"\n\n[exports.encryption]\nkeyfile = \"{dir}/disk.key\""
+    )
+    .is_err());
+}